@@ -4,16 +4,22 @@
 extern crate aya_rustc_llvm_proxy;
 
 use std::{
-    env, fs, io,
+    env, fs,
+    io::{self, Read as _, Write as _},
     path::{Component, Path, PathBuf},
     str::FromStr,
 };
 
-use bpf_linker::{Cpu, Linker, LinkerOptions, OptLevel, OutputType};
+use bpf_linker::{
+    format_blocks, format_function_stats, BpfCpuFeatures, CodeModel, Cpu, CpuFeature,
+    LinkMapFormat, Linker, LinkerInput, LinkerOptionsBuilder, MemoryBuiltin, OptLevel, OutputType,
+    RelocMode, MIN_BTF_TYPE_NAME_LEN,
+};
 use clap::{
-    builder::{PathBufValueParser, TypedValueParser as _},
-    Parser,
+    builder::{PathBufValueParser, RangedU64ValueParser, TypedValueParser as _},
+    ArgGroup, CommandFactory as _, FromArgMatches as _, Parser,
 };
+use regex::Regex;
 use thiserror::Error;
 use tracing::{info, Level};
 use tracing_subscriber::{fmt::MakeWriter, prelude::*, EnvFilter};
@@ -25,6 +31,24 @@ enum CliError {
     InvalidOptimization(String),
     #[error("unknown emission type: `{0}` - expected one of: `llvm-bc`, `asm`, `llvm-ir`, `obj`")]
     InvalidOutputType(String),
+    #[error("unknown diagnostics format: `{0}` - expected one of: `human`, `json`")]
+    InvalidDiagnosticsFormat(String),
+    #[error("invalid export symbols file `{0}`: {1}")]
+    InvalidExportSymbols(PathBuf, String),
+    #[error("invalid section rename: `{0}` - expected `old=new`")]
+    InvalidSectionRename(String),
+    #[error("unknown print request: `{0}` - expected one of: `target-cpus`, `target-features`")]
+    InvalidPrintRequest(String),
+    #[error("failed to read response file `{0}`: {1}")]
+    ResponseFile(PathBuf, io::Error),
+    #[error("response file `{0}` includes itself")]
+    RecursiveResponseFile(PathBuf),
+    #[error("only one input can be read from stdin")]
+    MultipleStdinInputs,
+    #[error("only one output type can be written to stdout")]
+    MultipleStdoutOutputs,
+    #[error("cannot name the output after stdin, use --output instead of --output-dir")]
+    StdinOutputName,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -65,6 +89,135 @@ impl FromStr for CliOutputType {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+struct CliSectionRename(String, String);
+
+impl FromStr for CliSectionRename {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+                Ok(CliSectionRename(old.to_owned(), new.to_owned()))
+            }
+            _ => Err(CliError::InvalidSectionRename(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DiagnosticsFormat {
+    /// Log diagnostics through `tracing`.
+    Human,
+    /// Additionally write the diagnostics as JSON.
+    Json,
+}
+
+impl FromStr for DiagnosticsFormat {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use DiagnosticsFormat::*;
+        Ok(match s {
+            "human" => Human,
+            "json" => Json,
+            _ => return Err(CliError::InvalidDiagnosticsFormat(s.to_string())),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PrintRequest {
+    /// The supported values of --cpu.
+    TargetCpus,
+    /// The supported features of --cpu-features.
+    TargetFeatures,
+}
+
+impl FromStr for PrintRequest {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use PrintRequest::*;
+        Ok(match s {
+            "target-cpus" => TargetCpus,
+            "target-features" => TargetFeatures,
+            _ => return Err(CliError::InvalidPrintRequest(s.to_string())),
+        })
+    }
+}
+
+fn print_info(request: PrintRequest, mut writer: impl io::Write) -> io::Result<()> {
+    match request {
+        PrintRequest::TargetCpus => {
+            for cpu in Cpu::ALL {
+                writeln!(writer, "{cpu:<8} {}", cpu.description())?;
+            }
+        }
+        PrintRequest::TargetFeatures => {
+            for feature in CpuFeature::ALL {
+                writeln!(writer, "{feature:<8} {}", feature.description())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug)]
+enum ExportSymbolsFormat {
+    Json,
+    Toml,
+}
+
+// Parses the `export` array of strings of a JSON or TOML export symbols file.
+fn parse_export_symbols(
+    contents: &str,
+    format: ExportSymbolsFormat,
+) -> Result<Vec<String>, String> {
+    let symbols = match format {
+        ExportSymbolsFormat::Json => {
+            let value =
+                serde_json::from_str::<serde_json::Value>(contents).map_err(|e| e.to_string())?;
+            value
+                .get("export")
+                .and_then(serde_json::Value::as_array)
+                .map(|symbols| {
+                    symbols
+                        .iter()
+                        .map(|symbol| symbol.as_str().map(str::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                })
+        }
+        ExportSymbolsFormat::Toml => {
+            let table = contents.parse::<toml::Table>().map_err(|e| e.to_string())?;
+            table
+                .get("export")
+                .and_then(toml::Value::as_array)
+                .map(|symbols| {
+                    symbols
+                        .iter()
+                        .map(|symbol| symbol.as_str().map(str::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                })
+        }
+    };
+    match symbols {
+        Some(Some(symbols)) => Ok(symbols),
+        Some(None) => Err("`export` must only contain strings".to_owned()),
+        None => Err("expected an `export` array of symbols".to_owned()),
+    }
+}
+
+fn read_export_symbols(
+    path: PathBuf,
+    format: ExportSymbolsFormat,
+) -> Result<Vec<String>, CliError> {
+    fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| parse_export_symbols(&contents, format))
+        .map_err(|e| CliError::InvalidExportSymbols(path, e))
+}
+
 fn parent_and_file_name(p: PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
     let mut comps = p.components();
     let file_name = comps
@@ -79,47 +232,186 @@ fn parent_and_file_name(p: PathBuf) -> anyhow::Result<(PathBuf, PathBuf)> {
     Ok((parent.to_path_buf(), Path::new(file_name).to_path_buf()))
 }
 
+/// Splits the contents of a response file into arguments.
+///
+/// The rules match the ones of `buildargv` from GNU libiberty: arguments are
+/// separated by whitespace, single and double quotes group characters
+/// (including whitespace and newlines) into a single argument, and a
+/// backslash escapes the following character, also within quotes.
+fn split_response_file(contents: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut chars = contents.chars();
+    let mut quote = None;
+    while let Some(ch) = chars.next() {
+        match (ch, quote) {
+            ('\\', _) => {
+                let arg = arg.get_or_insert_with(String::new);
+                if let Some(ch) = chars.next() {
+                    arg.push(ch);
+                }
+            }
+            (ch, Some(q)) if ch == q => quote = None,
+            (ch, Some(_)) => arg.get_or_insert_with(String::new).push(ch),
+            ('\'' | '"', None) => {
+                let _: &mut String = arg.get_or_insert_with(String::new);
+                quote = Some(ch);
+            }
+            (ch, None) if ch.is_whitespace() => args.extend(arg.take()),
+            (ch, None) => arg.get_or_insert_with(String::new).push(ch),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Replaces `@path` arguments with the arguments read from the response file
+/// at `path`. Response files can include other response files.
+fn expand_response_files(
+    args: impl IntoIterator<Item = String>,
+    parents: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, CliError> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(path) if !path.is_empty() => {
+                let path = PathBuf::from(path);
+                if parents.contains(&path) {
+                    return Err(CliError::RecursiveResponseFile(path));
+                }
+                let contents = fs::read_to_string(&path)
+                    .map_err(|err| CliError::ResponseFile(path.clone(), err))?;
+                parents.push(path);
+                expanded.extend(expand_response_files(
+                    split_response_file(&contents),
+                    parents,
+                )?);
+                let _: Option<PathBuf> = parents.pop();
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
 #[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("llvm_reports").args(["time_passes", "print_stats"]).multiple(true)))]
 struct CommandLine {
     /// LLVM target triple. When not provided, the target is inferred from the inputs
     #[clap(long)]
     target: Option<String>,
 
-    /// Target BPF processor. Can be one of `generic`, `probe`, `v1`, `v2`, `v3`
+    /// Target BPF processor. Can be one of `generic`, `probe`, `v1`, `v2`, `v3`, `v4`
     #[clap(long, default_value = "generic")]
     cpu: Cpu,
 
+    /// Print the supported values of --cpu and exit. Same as `--print target-cpus`
+    #[clap(long, exclusive = true)]
+    print_supported_cpus: bool,
+
+    /// Print the given information and exit. Can be `target-cpus` or `target-features`
+    #[clap(long, value_name = "info", exclusive = true)]
+    print: Option<PrintRequest>,
+
     /// Enable or disable CPU features. The available features are: alu32, dummy, dwarfris. Use
     /// +feature to enable a feature, or -feature to disable it.  For example
     /// --cpu-features=+alu32,-dwarfris
     #[clap(long, value_name = "features", default_value = "")]
     cpu_features: String,
 
-    /// Write output to <output>
-    #[clap(short, long)]
-    output: PathBuf,
+    /// Pass the CPU features unknown to bpf-linker to LLVM as they are, instead of failing. Useful
+    /// for features added by newer LLVM versions
+    #[clap(long)]
+    allow_unknown_cpu_features: bool,
+
+    /// Write output to <output>. `-` writes a single output to stdout
+    #[clap(short, long, required_unless_present = "output_dir")]
+    output: Option<PathBuf>,
+
+    /// Write the output to the given directory, naming it after the first input and the output
+    /// type
+    #[clap(long, value_name = "dir", conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
 
-    /// Output type. Can be one of `llvm-bc`, `asm`, `llvm-ir`, `obj`
-    #[clap(long, default_value = "obj")]
+    /// Comma separated list of output types. Can be `llvm-bc`, `asm`, `llvm-ir`, `obj`. When
+    /// more than one type is given, each output is written next to <output>, with the extension
+    /// of its type
+    #[clap(long, default_value = "obj", use_value_delimiter = true, action = clap::ArgAction::Append)]
     emit: Vec<CliOutputType>,
 
     /// Emit BTF information
     #[clap(long)]
     btf: bool,
 
+    /// Keep the DWARF debug info of the inputs, instead of stripping it or sanitizing it for BTF.
+    /// Useful for offline analysis with tools like pahole, but the output usually won't load in
+    /// the kernel
+    #[clap(long)]
+    preserve_debug_info: bool,
+
+    /// Downgrade 64-bit enums to 32 bits in BTF. Useful when targeting kernels older than 6.0,
+    /// which don't support BTF_KIND_ENUM64
+    #[clap(long)]
+    downgrade_enum64: bool,
+
+    /// Maximum length of the type names in BTF. Longer names are truncated and suffixed with a
+    /// hash, so the length must be at least 34 [default: 128]
+    #[clap(
+        long,
+        value_name = "len",
+        value_parser = RangedU64ValueParser::<usize>::new().range(MIN_BTF_TYPE_NAME_LEN as u64..)
+    )]
+    btf_name_max_len: Option<usize>,
+
+    /// Keep the variants of data-carrying enums in BTF. By default they are stripped, since most
+    /// kernels reject them
+    #[clap(long)]
+    keep_btf_enums: bool,
+
+    /// Merge the BTF structs and unions which are identical once their names are sanitized
+    #[clap(long)]
+    dedup_btf_types: bool,
+
     /// Add a directory to the library search path
     #[clap(short = 'L', number_of_values = 1)]
     libs: Vec<PathBuf>,
 
+    /// Link the library `name`, found as `libname.a` or `libname.rlib` in the directories given
+    /// with -L
+    #[clap(short = 'l', value_name = "name", number_of_values = 1)]
+    link_libs: Vec<String>,
+
     /// Optimization level. 0-3, s, or z
     #[clap(short = 'O', default_value = "2")]
     optimize: Vec<CliOptLevel>,
 
+    /// Run the given LLVM pass `pipeline` instead of the default one for the optimization level,
+    /// e.g. `default<Os>,bpf-preserve-static-offset`
+    #[clap(long, value_name = "pipeline")]
+    passes: Option<String>,
+
+    /// Relocation model. Can be one of `default`, `static`, `pic`, `dynamic-no-pic`
+    #[clap(long, value_name = "model", default_value = "default")]
+    reloc_mode: RelocMode,
+
+    /// Code model. Can be one of `default`, `tiny`, `small`, `kernel`, `medium`, `large`
+    #[clap(long, value_name = "model", default_value = "default")]
+    code_model: CodeModel,
+
     /// Export the symbols specified in the file `path`. The symbols must be separated by new lines
     #[clap(long, value_name = "path")]
     export_symbols: Option<PathBuf>,
 
-    /// Output logs to the given `path`
+    /// Export the symbols listed in the JSON file `path`, given as `{"export": ["sym1", "sym2"]}`
+    #[clap(long, value_name = "path")]
+    export_symbols_json: Option<PathBuf>,
+
+    /// Export the symbols listed in the TOML file `path`, given as `export = ["sym1", "sym2"]`
+    #[clap(long, value_name = "path")]
+    export_symbols_toml: Option<PathBuf>,
+
+    /// Output logs to the given `path`. The reports of --time-passes and --print-stats still go
+    /// to stderr, use --time-passes-file to write them to a file
     #[clap(
         long,
         value_name = "path",
@@ -136,6 +428,14 @@ struct CommandLine {
     #[clap(long)]
     unroll_loops: bool,
 
+    /// Cost threshold of the loops unrolled by --unroll-loops. Defaults to the maximum
+    #[clap(long, value_name = "N", requires = "unroll_loops")]
+    unroll_threshold: Option<u32>,
+
+    /// Maximum trip count of the loops fully unrolled by --unroll-loops. Defaults to the maximum
+    #[clap(long, value_name = "N", requires = "unroll_loops")]
+    unroll_max_upperbound: Option<u32>,
+
     /// Ignore `noinline`/`#[inline(never)]`. Useful when targeting kernels that don't support function calls
     #[clap(long)]
     ignore_inline_never: bool,
@@ -144,10 +444,67 @@ struct CommandLine {
     #[clap(long, value_name = "path")]
     dump_module: Option<PathBuf>,
 
+    /// Print the block structure of each bitcode input to stderr, without parsing it with LLVM
+    #[clap(long)]
+    dump_bitcode_blocks: bool,
+
+    /// Data layout of the output module, e.g. `e-m:e-p:64:64-i64:64-i128:128-n32:64-S128` for
+    /// little endian BPF. Defaults to the layout of the inputs
+    #[clap(long, value_name = "layout")]
+    data_layout: Option<String>,
+
+    /// Run the LLVM verifier on the optimized module before generating the code
+    #[clap(long)]
+    verify_module: bool,
+
+    /// Keep the module as bitcode and IR in the given `dir` after linking, after optimization
+    /// and before generating the code
+    #[clap(long, value_name = "dir")]
+    save_temps: Option<PathBuf>,
+
+    /// Write a make rule listing the linked inputs to the given `path`, like `clang -MD`. Archives
+    /// are listed themselves, their linked members follow as comments
+    #[clap(long, value_name = "path", visible_alias = "emit-depfile")]
+    emit_deps: Option<PathBuf>,
+
+    /// Write the symbols internalized and the symbols removed during optimization to the given
+    /// `path`
+    #[clap(long, value_name = "path")]
+    report_internalized: Option<PathBuf>,
+
+    /// Write the symbols left after optimization, with their section, linkage and approximate
+    /// size, to the given `path`
+    #[clap(long, value_name = "path")]
+    emit_link_map: Option<PathBuf>,
+
+    /// Format of the link map. Can be one of `text`, `json`
+    #[clap(
+        long,
+        value_name = "format",
+        default_value = "text",
+        requires = "emit_link_map"
+    )]
+    emit_link_map_format: LinkMapFormat,
+
     /// Extra command line arguments to pass to LLVM
     #[clap(long, value_name = "args", use_value_delimiter = true, action = clap::ArgAction::Append)]
     llvm_args: Vec<String>,
 
+    /// Report the time spent in each LLVM pass to stderr
+    #[clap(long)]
+    time_passes: bool,
+
+    /// Write the reports of `--time-passes` and `--print-stats` to the given `path` instead of
+    /// stderr
+    #[clap(long, value_name = "path", requires = "llvm_reports")]
+    time_passes_file: Option<PathBuf>,
+
+    /// Report the statistics collected by the LLVM passes to stderr. Most statistics are only
+    /// available when LLVM is built with assertions. Also prints the number of basic blocks and
+    /// IR instructions of each function after optimization to stderr, which works with any LLVM
+    #[clap(long)]
+    print_stats: bool,
+
     /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
     #[clap(long)]
     disable_expand_memcpy_in_order: bool,
@@ -158,7 +515,19 @@ struct CommandLine {
     #[clap(long)]
     disable_memory_builtins: bool,
 
-    /// Input files. Can be object files or static libraries
+    /// Disable exporting the given memory builtin. Can be one of `memcpy`, `memmove`, `memset`,
+    /// `memcmp`, `bcmp` or `strlen`, which is only exported with `--export-memory-builtin`. Can
+    /// be repeated
+    #[clap(long, value_name = "name", action = clap::ArgAction::Append)]
+    disable_memory_builtin: Vec<MemoryBuiltin>,
+
+    /// Export the given memory builtin, even with `--disable-memory-builtins`. Can be one of
+    /// `memcpy`, `memmove`, `memset`, `memcmp`, `bcmp` or `strlen`, which is never exported by
+    /// default. Can be repeated
+    #[clap(long, value_name = "name", action = clap::ArgAction::Append)]
+    export_memory_builtin: Vec<MemoryBuiltin>,
+
+    /// Input files. Can be object files or static libraries. `-` reads a single input from stdin
     #[clap(required = true)]
     inputs: Vec<PathBuf>,
 
@@ -166,6 +535,90 @@ struct CommandLine {
     #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
     export: Vec<String>,
 
+    /// Fail when a symbol to export is not defined by any input, instead of printing a warning
+    #[clap(long)]
+    error_on_missing_export: bool,
+
+    /// Export every symbol whose name matches the given regular expression, e.g. `^xdp_`. Can be
+    /// repeated. Can't be combined with `--internalize-only`
+    #[clap(long, value_name = "regex", action = clap::ArgAction::Append)]
+    export_pattern: Vec<Regex>,
+
+    /// Comma separated list of symbols to internalize. All the other symbols are exported.
+    /// Can't be combined with `--export` or `--export-symbols`
+    #[clap(long, value_name = "symbols", use_value_delimiter = true, action = clap::ArgAction::Append)]
+    internalize_only: Vec<String>,
+
+    /// Number of threads used to parse and link the members of archives
+    #[clap(long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Name of the section containing the embedded bitcode of object files. Defaults to `.llvmbc`
+    /// for ELF and `__bitcode` for Mach-O files
+    #[clap(long, value_name = "name")]
+    bitcode_section: Option<String>,
+
+    /// Also write the .BTF section of the generated object to the given `path`. Requires --btf
+    #[clap(long, value_name = "path")]
+    emit_btf: Option<PathBuf>,
+
+    /// Store a fingerprint of the inputs and options to `path`, and skip linking when it's
+    /// unchanged and the outputs, including side outputs like `--emit-link-map`, exist
+    #[clap(long, value_name = "path")]
+    fingerprint: Option<PathBuf>,
+
+    /// Delete the functions which can't be reached from the exported symbols, the programs and
+    /// the global variables
+    #[clap(long)]
+    gc_sections: bool,
+
+    /// Move the programs in the section `old`, or in a section starting with `old/`, to `new`,
+    /// keeping the rest of the section name. For example `--section-rename kprobe=kprobe.multi`
+    /// moves `kprobe/do_unlinkat` to `kprobe.multi/do_unlinkat`. Can be repeated
+    #[clap(long, value_name = "old=new", action = clap::ArgAction::Append)]
+    section_rename: Vec<CliSectionRename>,
+
+    /// Stop linking once LLVM reported `N` errors
+    #[clap(long, value_name = "N")]
+    max_errors: Option<usize>,
+
+    /// Treat LLVM warnings as errors
+    #[clap(long)]
+    deny_warnings: bool,
+
+    /// Ignore the LLVM diagnostics containing `message`. Can be repeated
+    #[clap(long, value_name = "message", action = clap::ArgAction::Append)]
+    suppress_diagnostic: Vec<String>,
+
+    /// Log the suppressed LLVM diagnostics
+    #[clap(long)]
+    list_suppressed: bool,
+
+    /// Only link the inputs and report which of them contain bitcode, without generating any
+    /// code or writing the output
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Format of the LLVM diagnostics. Can be one of `human`, `json`. With `json`, all the
+    /// diagnostics are written as a JSON array to stderr or to `--diagnostics-file`. Conflicts
+    /// with `--error-format`
+    #[clap(
+        long,
+        value_name = "format",
+        default_value = "human",
+        conflicts_with = "error_format"
+    )]
+    diagnostics_format: DiagnosticsFormat,
+
+    /// Format of the errors printed to stderr. Can be one of `human`, `json`. With `json`, each
+    /// LLVM diagnostic is also written to stderr as a JSON object on its own line
+    #[clap(long, value_name = "format", default_value = "human")]
+    error_format: DiagnosticsFormat,
+
+    /// Write JSON diagnostics to the given `path` instead of stderr
+    #[clap(long, value_name = "path")]
+    diagnostics_file: Option<PathBuf>,
+
     /// Whether to treat LLVM errors as fatal.
     #[clap(long, action = clap::ArgAction::Set, default_value_t = true)]
     fatal_errors: bool,
@@ -187,7 +640,8 @@ where
         .with_writer(writer)
 }
 fn main() -> anyhow::Result<()> {
-    let args = env::args().map(|arg| {
+    let args = expand_response_files(env::args(), &mut Vec::new())?;
+    let args = args.into_iter().map(|arg| {
         if arg == "-flavor" {
             "--flavor".to_string()
         } else {
@@ -197,26 +651,85 @@ fn main() -> anyhow::Result<()> {
     let CommandLine {
         target,
         cpu,
+        print_supported_cpus,
+        print,
         cpu_features,
+        allow_unknown_cpu_features,
         output,
+        output_dir,
         emit,
         btf,
+        preserve_debug_info,
+        downgrade_enum64,
+        btf_name_max_len,
+        keep_btf_enums,
+        dedup_btf_types,
         libs,
+        link_libs,
         optimize,
+        passes,
+        reloc_mode,
+        code_model,
         export_symbols,
+        export_symbols_json,
+        export_symbols_toml,
         log_file,
         log_level,
         unroll_loops,
+        unroll_threshold,
+        unroll_max_upperbound,
         ignore_inline_never,
         dump_module,
+        dump_bitcode_blocks,
+        data_layout,
+        verify_module,
+        save_temps,
+        emit_deps,
+        report_internalized,
+        emit_link_map,
+        emit_link_map_format,
         llvm_args,
+        time_passes,
+        time_passes_file,
+        print_stats,
         disable_expand_memcpy_in_order,
         disable_memory_builtins,
+        disable_memory_builtin,
+        export_memory_builtin,
         inputs,
         export,
+        error_on_missing_export,
+        export_pattern,
+        internalize_only,
+        jobs,
+        bitcode_section,
+        gc_sections,
+        section_rename,
+        max_errors,
+        deny_warnings,
+        suppress_diagnostic,
+        list_suppressed,
+        dry_run,
+        emit_btf,
+        fingerprint,
+        diagnostics_format,
+        error_format,
+        diagnostics_file,
         fatal_errors,
         _debug,
-    } = Parser::try_parse_from(args)?;
+    } = CommandLine::from_arg_matches(
+        &CommandLine::command()
+            .version(version())
+            .try_get_matches_from(args)
+            // Print --version to stdout and exit successfully, unlike the parsing errors.
+            .map_err(|e| if e.use_stderr() { e } else { e.exit() })?,
+    )?;
+
+    let print = print.or(print_supported_cpus.then_some(PrintRequest::TargetCpus));
+    if let Some(request) = print {
+        print_info(request, io::stdout().lock())?;
+        return Ok(());
+    }
 
     // Configure tracing.
     let _guard = {
@@ -249,49 +762,207 @@ fn main() -> anyhow::Result<()> {
         env::args().collect::<Vec<_>>().join(" ")
     );
 
+    let cpu_features = if allow_unknown_cpu_features {
+        BpfCpuFeatures::parse_unchecked(&cpu_features)?
+    } else {
+        cpu_features.parse()?
+    };
+
     let export_symbols = export_symbols.map(fs::read_to_string).transpose()?;
+    let export_symbols_json = export_symbols_json
+        .map(|path| read_export_symbols(path, ExportSymbolsFormat::Json))
+        .transpose()?;
+    let export_symbols_toml = export_symbols_toml
+        .map(|path| read_export_symbols(path, ExportSymbolsFormat::Toml))
+        .transpose()?;
 
     // TODO: the data is owned by this call frame; we could make this zero-alloc.
+    // The symbols are collected into a set, which drops the duplicates across the sources.
     let export_symbols = export_symbols
         .as_deref()
         .into_iter()
         .flat_map(str::lines)
         .map(str::to_owned)
-        .chain(export)
-        .map(Into::into)
-        .collect();
+        .chain(export_symbols_json.into_iter().flatten())
+        .chain(export_symbols_toml.into_iter().flatten())
+        .chain(export);
 
     let output_type = match *emit.as_slice() {
         [] => unreachable!("emit has a default value"),
         [CliOutputType(output_type), ..] => output_type,
     };
+    let output = match (output, output_dir) {
+        (Some(output), _) => output,
+        (None, Some(output_dir)) => output_in_dir(&output_dir, &inputs, output_type)?,
+        (None, None) => unreachable!("clap requires --output or --output-dir"),
+    };
+    let outputs = output_paths(&output, &emit);
+    let to_stdout = output.as_os_str() == STDIO;
+    if to_stdout && outputs.len() > 1 {
+        return Err(CliError::MultipleStdoutOutputs.into());
+    }
+
+    if inputs
+        .iter()
+        .filter(|input| input.as_os_str() == STDIO)
+        .count()
+        > 1
+    {
+        return Err(CliError::MultipleStdinInputs.into());
+    }
+    let inputs = inputs
+        .into_iter()
+        .map(|input| {
+            if input.as_os_str() == STDIO {
+                let mut data = Vec::new();
+                let _: usize = io::stdin().read_to_end(&mut data)?;
+                Ok(LinkerInput::new_from_bytes("<stdin>", data))
+            } else {
+                Ok(input.into())
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
     let optimize = match *optimize.as_slice() {
-        [] => unreachable!("emit has a default value"),
+        [] => unreachable!("optimize has a default value"),
         [.., CliOptLevel(optimize)] => optimize,
     };
+    let options = LinkerOptionsBuilder::new(output)
+        .cpu(cpu)
+        .cpu_features(cpu_features)
+        .output_type(output_type)
+        .optimize(optimize)
+        .reloc_mode(reloc_mode)
+        .code_model(code_model)
+        .error_on_missing_export(error_on_missing_export)
+        .unroll_loops(unroll_loops)
+        .ignore_inline_never(ignore_inline_never)
+        .dump_bitcode_blocks(dump_bitcode_blocks)
+        .verify_module(verify_module)
+        .link_map_format(emit_link_map_format)
+        .time_passes(time_passes)
+        .print_stats(print_stats)
+        .disable_expand_memcpy_in_order(disable_expand_memcpy_in_order)
+        .disable_memory_builtins(disable_memory_builtins)
+        .btf(btf)
+        .preserve_debug_info(preserve_debug_info)
+        .downgrade_enum64(downgrade_enum64)
+        .keep_variant_enums(keep_btf_enums)
+        .dedup_btf_types(dedup_btf_types)
+        .gc_functions(gc_sections)
+        .deny_warnings(deny_warnings)
+        .list_suppressed(list_suppressed)
+        .dry_run(dry_run)
+        // The options which can be repeated, or left out.
+        .set_each(inputs, LinkerOptionsBuilder::input)
+        .set_each(libs, LinkerOptionsBuilder::lib)
+        .set_each(link_libs, LinkerOptionsBuilder::link_lib)
+        .set_each(export_symbols, LinkerOptionsBuilder::export_symbol)
+        .set_each(export_pattern, LinkerOptionsBuilder::export_pattern)
+        .set_each(internalize_only, LinkerOptionsBuilder::internalize_symbol)
+        .set_each(llvm_args, LinkerOptionsBuilder::llvm_arg)
+        .set_each(
+            disable_memory_builtin,
+            LinkerOptionsBuilder::disable_memory_builtin,
+        )
+        .set_each(
+            export_memory_builtin,
+            LinkerOptionsBuilder::export_memory_builtin,
+        )
+        .set_each(section_rename, |options, CliSectionRename(old, new)| {
+            options.rename_section(old, new)
+        })
+        .set_each(
+            suppress_diagnostic,
+            LinkerOptionsBuilder::suppress_diagnostic,
+        )
+        .set_each(target, LinkerOptionsBuilder::target)
+        .set_each(passes, LinkerOptionsBuilder::passes)
+        .set_each(unroll_threshold, LinkerOptionsBuilder::unroll_threshold)
+        .set_each(
+            unroll_max_upperbound,
+            LinkerOptionsBuilder::unroll_max_upperbound,
+        )
+        .set_each(dump_module, LinkerOptionsBuilder::dump_module)
+        .set_each(data_layout, LinkerOptionsBuilder::data_layout)
+        .set_each(save_temps, LinkerOptionsBuilder::save_temps)
+        .set_each(emit_deps, LinkerOptionsBuilder::emit_deps)
+        .set_each(
+            report_internalized,
+            LinkerOptionsBuilder::report_internalized,
+        )
+        .set_each(emit_link_map, LinkerOptionsBuilder::link_map)
+        .set_each(time_passes_file, LinkerOptionsBuilder::time_passes_file)
+        .set_each(
+            btf_name_max_len,
+            LinkerOptionsBuilder::btf_type_name_max_len,
+        )
+        .set_each(jobs, LinkerOptionsBuilder::jobs)
+        .set_each(bitcode_section, LinkerOptionsBuilder::bitcode_section)
+        .set_each(max_errors, LinkerOptionsBuilder::max_errors)
+        .set_each(emit_btf, LinkerOptionsBuilder::emit_btf)
+        .set_each(fingerprint, LinkerOptionsBuilder::fingerprint);
+    let mut linker = Linker::new(options.build());
 
-    let mut linker = Linker::new(LinkerOptions {
-        target,
-        cpu,
-        cpu_features,
-        inputs,
-        output,
-        output_type,
-        libs,
-        optimize,
-        export_symbols,
-        unroll_loops,
-        ignore_inline_never,
-        dump_module,
-        llvm_args,
-        disable_expand_memcpy_in_order,
-        disable_memory_builtins,
-        btf,
-    });
+    let result = if to_stdout {
+        linker.link_to_buffer().map(Some)
+    } else {
+        linker.link_to_files(&outputs).map(|()| None)
+    };
+
+    // The blocks of the inputs read before a failure help understanding why LLVM rejected them.
+    for (path, blocks) in linker.bitcode_blocks() {
+        match blocks {
+            Ok(blocks) => write!(
+                io::stderr(),
+                "bitcode blocks of {}:\n{}",
+                path.display(),
+                format_blocks(blocks)
+            )?,
+            Err(e) => writeln!(io::stderr(), "bitcode blocks of {}: {e}", path.display())?,
+        }
+    }
 
-    linker.link()?;
+    if error_format == DiagnosticsFormat::Json {
+        let mut stderr = io::stderr().lock();
+        for diagnostic in linker.diagnostics() {
+            serde_json::to_writer(&mut stderr, diagnostic)?;
+            writeln!(stderr)?;
+        }
+    }
+
+    if diagnostics_format == DiagnosticsFormat::Json {
+        let json = serde_json::to_string(linker.diagnostics())?;
+        match diagnostics_file {
+            Some(path) => fs::write(path, json)?,
+            None => writeln!(io::stderr(), "{json}")?,
+        }
+    }
+
+    if let Some(output) = result? {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&output)?;
+        stdout.flush()?;
+    }
 
-    if fatal_errors && linker.has_errors() {
+    if dry_run {
+        let mut stdout = io::stdout().lock();
+        let linked = linker.linked_inputs().count();
+        let skipped = linker.skipped_inputs();
+        writeln!(stdout, "linked {linked} inputs, skipped {}", skipped.len())?;
+        for (path, reason) in skipped {
+            writeln!(stdout, "  skipped {}: {reason}", path.display())?;
+        }
+    }
+
+    let has_errors = linker.has_errors();
+    if print_stats {
+        eprint!("{}", format_function_stats(linker.function_stats()));
+        drop(linker);
+        // SAFETY: LLVM isn't used anymore, shutting it down prints the statistics.
+        unsafe { llvm_sys::core::LLVMShutdown() };
+    }
+
+    if fatal_errors && has_errors {
         return Err(anyhow::anyhow!(
             "LLVM issued diagnostic with error severity"
         ));
@@ -300,6 +971,65 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Sets an option of the builder for each value given on the command line, when the option can be
+// repeated or left out.
+trait SetEach: Sized {
+    fn set_each<T>(
+        self,
+        values: impl IntoIterator<Item = T>,
+        set: impl FnMut(Self, T) -> Self,
+    ) -> Self {
+        values.into_iter().fold(self, set)
+    }
+}
+
+impl SetEach for LinkerOptionsBuilder {}
+
+// Version printed by --version, including the version of LLVM.
+fn version() -> &'static str {
+    let (major, minor, patch) = bpf_linker::llvm_version();
+    let version = format!(
+        "{} (LLVM {major}.{minor}.{patch})",
+        env!("CARGO_PKG_VERSION")
+    );
+    // clap needs a static string, leak it since it's only built once.
+    Box::leak(version.into_boxed_str())
+}
+
+// Path of the input read from stdin, or of the output written to stdout.
+const STDIO: &str = "-";
+
+// Names the output written to `dir` after the first input, like `dir/foo.o` for `foo.bc`.
+fn output_in_dir(
+    dir: &Path,
+    inputs: &[PathBuf],
+    output_type: OutputType,
+) -> Result<PathBuf, CliError> {
+    let stem = match inputs.first() {
+        Some(input) if input.as_os_str() == STDIO => return Err(CliError::StdinOutputName),
+        Some(input) => input.file_stem().unwrap_or(input.as_os_str()),
+        None => unreachable!("inputs are required"),
+    };
+    let mut file_name = stem.to_owned();
+    file_name.push(".");
+    file_name.push(output_type.extension());
+    Ok(dir.join(file_name))
+}
+
+// A single output is written to `output`. Multiple outputs are written to siblings of `output`,
+// with the extension swapped for the one of their type.
+fn output_paths(output: &Path, emit: &[CliOutputType]) -> Vec<(OutputType, PathBuf)> {
+    match emit {
+        [CliOutputType(output_type)] => vec![(*output_type, output.to_owned())],
+        emit => emit
+            .iter()
+            .map(|CliOutputType(output_type)| {
+                (*output_type, output.with_extension(output_type.extension()))
+            })
+            .collect(),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -339,6 +1069,113 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_split_response_file() {
+        let contents = r#"--export foo  -o "/tmp/out dir/bin.o"
+'some input.o' plain\ escaped.o "multi
+line" "quoted \"escape\"" '' last"#;
+        assert_eq!(
+            split_response_file(contents),
+            [
+                "--export",
+                "foo",
+                "-o",
+                "/tmp/out dir/bin.o",
+                "some input.o",
+                "plain escaped.o",
+                "multi\nline",
+                r#"quoted "escape""#,
+                "",
+                "last",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_response_files() {
+        let dir = env::temp_dir().join(format!("bpf-linker-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let outer = dir.join("outer.rsp");
+        let inner = dir.join("inner.rsp");
+        fs::write(&outer, format!("--export foo @{}\nrcgu.o", inner.display())).unwrap();
+        fs::write(&inner, "'symbols with spaces.o'").unwrap();
+
+        let args = [
+            "bpf-linker".to_owned(),
+            format!("@{}", outer.display()),
+            "-o".to_owned(),
+            "bin.o".to_owned(),
+        ];
+        let expanded = expand_response_files(args, &mut Vec::new()).unwrap();
+        assert_eq!(
+            expanded,
+            [
+                "bpf-linker",
+                "--export",
+                "foo",
+                "symbols with spaces.o",
+                "rcgu.o",
+                "-o",
+                "bin.o",
+            ]
+        );
+
+        fs::write(&inner, format!("@{}", outer.display())).unwrap();
+        let args = [format!("@{}", outer.display())];
+        assert!(matches!(
+            expand_response_files(args, &mut Vec::new()),
+            Err(CliError::RecursiveResponseFile(path)) if path == outer
+        ));
+
+        let args = [format!("@{}", dir.join("missing.rsp").display())];
+        assert!(matches!(
+            expand_response_files(args, &mut Vec::new()),
+            Err(CliError::ResponseFile(_, _))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diagnostics_to_json() {
+        use bpf_linker::{Diagnostic, DiagnosticSeverity, SourceLocation};
+
+        let diagnostics = [
+            Diagnostic {
+                severity: DiagnosticSeverity::Error,
+                message: "src/main.rs:1:2: in function foo: \"bar\"\n".to_owned(),
+                location: Some(SourceLocation {
+                    file: "src/main.rs".to_owned(),
+                    line: 1,
+                    column: 2,
+                }),
+            },
+            Diagnostic {
+                severity: DiagnosticSeverity::Warning,
+                message: "baz".to_owned(),
+                location: None,
+            },
+        ];
+        assert_eq!(
+            serde_json::to_string(&diagnostics).unwrap(),
+            r#"[{"severity":"error","message":"src/main.rs:1:2: in function foo: \"bar\"\n","location":{"file":"src/main.rs","line":1,"column":2}},{"severity":"warning","message":"baz","location":null}]"#
+        );
+        assert_eq!(
+            serde_json::to_string(&diagnostics[1]).unwrap(),
+            r#"{"severity":"warning","message":"baz","location":null}"#
+        );
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.s",
+            "--diagnostics-format=json",
+            "--error-format=json",
+            "foo.o",
+        ])
+        .is_err());
+    }
+
     #[test]
     fn test_export_delimiter() {
         let args = [
@@ -369,4 +1206,336 @@ mod test {
             [PathBuf::from("symbols.o"), PathBuf::from("rcgu.o")]
         );
     }
+
+    #[test]
+    fn test_internalize_only() {
+        let args = [
+            "bpf-linker",
+            "--internalize-only",
+            "foo,bar",
+            "--internalize-only=baz",
+            "symbols.o",
+            "-o",
+            "/tmp/bin.s",
+        ];
+        let CommandLine {
+            inputs,
+            export,
+            internalize_only,
+            ..
+        } = Parser::parse_from(args);
+        assert!(export.is_empty());
+        assert_eq!(internalize_only, ["foo", "bar", "baz"]);
+        assert_eq!(inputs, [PathBuf::from("symbols.o")]);
+    }
+
+    #[test]
+    fn test_export_pattern() {
+        let CommandLine { export_pattern, .. } = Parser::parse_from([
+            "bpf-linker",
+            "--export-pattern",
+            "^xdp_",
+            "--export-pattern=^tracepoint/",
+            "symbols.o",
+            "-o",
+            "/tmp/bin.s",
+        ]);
+        assert_eq!(
+            export_pattern.iter().map(Regex::as_str).collect::<Vec<_>>(),
+            ["^xdp_", "^tracepoint/"]
+        );
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "--export-pattern",
+            "xdp_(",
+            "symbols.o",
+            "-o",
+            "/tmp/bin.s",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let CommandLine { output, emit, .. } =
+            Parser::parse_from(["bpf-linker", "-o", "/tmp/bin.o", "--emit=asm", "rcgu.o"]);
+        let paths = output_paths(&output.unwrap(), &emit)
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [PathBuf::from("/tmp/bin.o")]);
+
+        let CommandLine { output, emit, .. } = Parser::parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--emit=obj,llvm-ir",
+            "--emit",
+            "llvm-bc",
+            "rcgu.o",
+        ]);
+        let paths = output_paths(&output.unwrap(), &emit)
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/tmp/bin.o"),
+                PathBuf::from("/tmp/bin.ll"),
+                PathBuf::from("/tmp/bin.bc")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_in_dir() {
+        let CommandLine {
+            output,
+            output_dir,
+            inputs,
+            ..
+        } = Parser::parse_from([
+            "bpf-linker",
+            "--output-dir",
+            "/tmp/out",
+            "foo.rcgu.o",
+            "bar.o",
+        ]);
+        assert_eq!(output, None);
+        assert_eq!(
+            output_in_dir(&output_dir.unwrap(), &inputs, OutputType::LlvmAssembly).unwrap(),
+            PathBuf::from("/tmp/out/foo.rcgu.ll")
+        );
+
+        assert!(matches!(
+            output_in_dir(
+                Path::new("/tmp/out"),
+                &[PathBuf::from("-")],
+                OutputType::Object
+            ),
+            Err(CliError::StdinOutputName)
+        ));
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--output-dir",
+            "/tmp/out",
+            "foo.o",
+        ])
+        .is_err());
+        assert!(CommandLine::try_parse_from(["bpf-linker", "foo.o"]).is_err());
+    }
+
+    #[test]
+    fn test_reloc_mode_code_model() {
+        let CommandLine {
+            reloc_mode,
+            code_model,
+            ..
+        } = Parser::parse_from(["bpf-linker", "-o", "/tmp/bin.o", "rcgu.o"]);
+        assert_eq!(reloc_mode.to_string(), "default");
+        assert_eq!(code_model.to_string(), "default");
+
+        let CommandLine {
+            reloc_mode,
+            code_model,
+            ..
+        } = Parser::parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--reloc-mode=pic",
+            "--code-model",
+            "small",
+            "rcgu.o",
+        ]);
+        assert_eq!(reloc_mode.to_string(), "pic");
+        assert_eq!(code_model.to_string(), "small");
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--code-model=huge",
+            "rcgu.o",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_unroll_limits() {
+        let CommandLine {
+            unroll_threshold,
+            unroll_max_upperbound,
+            ..
+        } = Parser::parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--unroll-loops",
+            "--unroll-threshold=1000",
+            "rcgu.o",
+        ]);
+        assert_eq!(unroll_threshold, Some(1000));
+        assert_eq!(unroll_max_upperbound, None);
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--unroll-max-upperbound=16",
+            "rcgu.o",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_btf_name_max_len() {
+        let CommandLine {
+            btf_name_max_len, ..
+        } = Parser::parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--btf-name-max-len=34",
+            "rcgu.o",
+        ]);
+        assert_eq!(btf_name_max_len, Some(34));
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--btf-name-max-len=33",
+            "rcgu.o",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_time_passes_file() {
+        for report in ["--time-passes", "--print-stats"] {
+            assert!(CommandLine::try_parse_from([
+                "bpf-linker",
+                "-o",
+                "/tmp/bin.o",
+                report,
+                "--time-passes-file=/tmp/report.txt",
+                "rcgu.o",
+            ])
+            .is_ok());
+        }
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "-o",
+            "/tmp/bin.o",
+            "--time-passes-file=/tmp/report.txt",
+            "rcgu.o",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_print_supported_cpus() {
+        let CommandLine {
+            print_supported_cpus,
+            inputs,
+            ..
+        } = Parser::parse_from(["bpf-linker", "--print-supported-cpus"]);
+        assert!(print_supported_cpus);
+        assert!(inputs.is_empty());
+
+        assert!(CommandLine::try_parse_from([
+            "bpf-linker",
+            "--print-supported-cpus",
+            "-o",
+            "/tmp/bin.o",
+            "rcgu.o",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_print() {
+        let CommandLine { print, inputs, .. } =
+            Parser::parse_from(["bpf-linker", "--print", "target-features"]);
+        assert_eq!(print, Some(PrintRequest::TargetFeatures));
+        assert!(inputs.is_empty());
+
+        assert!(CommandLine::try_parse_from(["bpf-linker", "--print", "cpus"]).is_err());
+
+        let mut output = Vec::new();
+        print_info(PrintRequest::TargetCpus, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        for cpu in Cpu::ALL {
+            assert!(
+                output
+                    .lines()
+                    .any(|line| line.starts_with(&format!("{cpu} "))),
+                "{output}"
+            );
+        }
+
+        let mut output = Vec::new();
+        print_info(PrintRequest::TargetFeatures, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        for feature in CpuFeature::ALL {
+            assert!(
+                output
+                    .lines()
+                    .any(|line| line.starts_with(&format!("{feature} "))),
+                "{output}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_section_rename() {
+        let CommandLine { section_rename, .. } = Parser::parse_from([
+            "bpf-linker",
+            "--section-rename",
+            "kprobe=kprobe.multi",
+            "--section-rename=xdp=xdp.frags",
+            "-o",
+            "/tmp/bin.o",
+            "rcgu.o",
+        ]);
+        assert_eq!(
+            section_rename,
+            [
+                CliSectionRename("kprobe".into(), "kprobe.multi".into()),
+                CliSectionRename("xdp".into(), "xdp.frags".into()),
+            ]
+        );
+
+        for rename in ["kprobe", "=kprobe.multi", "kprobe="] {
+            assert!(matches!(
+                rename.parse::<CliSectionRename>(),
+                Err(CliError::InvalidSectionRename(s)) if s == rename
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_export_symbols() {
+        assert_eq!(
+            parse_export_symbols(r#"{"export": ["foo", "bar"]}"#, ExportSymbolsFormat::Json),
+            Ok(vec!["foo".to_owned(), "bar".to_owned()])
+        );
+        assert_eq!(
+            parse_export_symbols(
+                "# programs\nexport = [\n  \"foo\",\n  'bar',\n]\n",
+                ExportSymbolsFormat::Toml
+            ),
+            Ok(vec!["foo".to_owned(), "bar".to_owned()])
+        );
+
+        assert!(parse_export_symbols(r#"{"export": "foo"}"#, ExportSymbolsFormat::Json).is_err());
+        assert!(parse_export_symbols(r#"{"export": [1]}"#, ExportSymbolsFormat::Json).is_err());
+        assert!(parse_export_symbols("symbols = []", ExportSymbolsFormat::Toml).is_err());
+        assert!(parse_export_symbols("export = [", ExportSymbolsFormat::Toml).is_err());
+    }
 }
@@ -1,31 +1,44 @@
 use std::{
     borrow::Cow,
-    collections::HashSet,
-    ffi::{CStr, CString},
-    fs::File,
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{CStr, CString, OsStr},
+    fs::{self, File},
     io,
-    io::{Read, Seek},
+    io::Read,
     os::unix::ffi::OsStrExt as _,
     path::{Path, PathBuf},
     ptr, str,
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
 };
 
 use ar::Archive;
+use flate2::read::ZlibDecoder;
 use llvm_sys::{
-    bit_writer::LLVMWriteBitcodeToFile,
     core::{
-        LLVMContextCreate, LLVMContextDispose, LLVMContextSetDiagnosticHandler, LLVMDisposeModule,
-        LLVMGetTarget,
+        LLVMCloneModule, LLVMContextCreate, LLVMContextDispose, LLVMContextSetDiagnosticHandler,
+        LLVMDisposeModule, LLVMGetTarget, LLVMGetVersion, LLVMSetDataLayout,
     },
     error_handling::{LLVMEnablePrettyStackTrace, LLVMInstallFatalErrorHandler},
     prelude::{LLVMContextRef, LLVMModuleRef},
     target_machine::{LLVMCodeGenFileType, LLVMDisposeTargetMachine, LLVMTargetMachineRef},
 };
+use memmap2::Mmap;
+use regex::Regex;
+use serde::Serialize;
 use thiserror::Error;
 use tracing::{debug, error, info, warn};
 
 use crate::llvm;
+pub use crate::llvm::{
+    bitcode_producer_string, bitcode_target_triple, format_blocks, sanitize_btf_type_name,
+    BitcodeBlock, BitcodeError, BtfSanitizeError, DISanitizer, MIN_BTF_TYPE_NAME_LEN,
+};
 
 /// Linker error
 #[derive(Debug, Error)]
@@ -34,10 +47,34 @@ pub enum LinkerError {
     #[error("invalid CPU {0}")]
     InvalidCpu(String),
 
+    /// Invalid Cpu feature.
+    #[error(
+        "invalid CPU feature `{0}`{}, expected `+feature` or `-feature` where feature is one of: {}",
+        CpuFeature::suggestion(.0).map(|s| format!(" (did you mean `{s}`?)")).unwrap_or_default(),
+        CpuFeature::ALL.map(CpuFeature::to_str).join(", ")
+    )]
+    InvalidCpuFeature(String),
+
     /// Invalid LLVM target.
     #[error("invalid LLVM target {0}")]
     InvalidTarget(String),
 
+    /// Invalid memory builtin.
+    #[error("invalid memory builtin {0}")]
+    InvalidMemoryBuiltin(String),
+
+    /// Invalid relocation model.
+    #[error("invalid relocation model {0}")]
+    InvalidRelocMode(String),
+
+    /// Invalid code model.
+    #[error("invalid code model {0}")]
+    InvalidCodeModel(String),
+
+    /// Invalid link map format.
+    #[error("invalid link map format {0}")]
+    InvalidLinkMapFormat(String),
+
     /// An IO Error occurred while linking a module.
     #[error("`{0}`: {1}")]
     IoError(PathBuf, io::Error),
@@ -46,6 +83,10 @@ pub enum LinkerError {
     #[error("invalid input file `{0}`")]
     InvalidInputType(PathBuf),
 
+    /// Parsing an LLVM IR file failed.
+    #[error("failure parsing LLVM IR {0}: {1}")]
+    ParseIRError(PathBuf, String),
+
     /// Linking a module failed.
     #[error("failure linking module {0}")]
     LinkModuleError(PathBuf),
@@ -54,18 +95,18 @@ pub enum LinkerError {
     #[error("failure linking module {1} from {0}")]
     LinkArchiveModuleError(PathBuf, PathBuf),
 
+    /// A thread linking the members of an archive panicked.
+    #[error("a thread linking the members of {0} panicked")]
+    ArchiveWorkerPanicked(PathBuf),
+
     /// Optimizing the BPF code failed.
     #[error("LLVMRunPasses failed: {0}")]
     OptimizeError(String),
 
     /// Generating the BPF code failed.
-    #[error("LLVMTargetMachineEmitToFile failed: {0}")]
+    #[error("LLVMTargetMachineEmitToMemoryBuffer failed: {0}")]
     EmitCodeError(String),
 
-    /// Writing the bitcode failed.
-    #[error("LLVMWriteBitcodeToFile failed")]
-    WriteBitcodeError,
-
     /// Writing the LLVM IR failed.
     #[error("LLVMPrintModuleToFile failed: {0}")]
     WriteIRError(String),
@@ -75,21 +116,126 @@ pub enum LinkerError {
     EmbeddedBitcodeError(String),
 
     /// The input object file does not have embedded bitcode.
-    #[error("no bitcode section found in {0}")]
-    MissingBitcodeSection(PathBuf),
+    #[error("no bitcode section `{1}` found in {0}")]
+    MissingBitcodeSection(PathBuf, String),
+
+    /// The input is a BPF object file without embedded bitcode, which can't be linked.
+    #[error(
+        "`{0}` is a precompiled BPF object without embedded bitcode, linking such objects isn't \
+         supported: link the bitcode it was built from instead"
+    )]
+    PrecompiledObject(PathBuf),
+
+    /// The new name of a renamed section is invalid.
+    #[error("invalid section name {0:?}")]
+    InvalidSectionName(String),
+
+    /// The output path has no file name the module can be named after.
+    #[error("invalid output path `{0}`")]
+    InvalidOutputPath(PathBuf),
+
+    /// The module name can't be passed to LLVM.
+    #[error("invalid module name {0:?}")]
+    InvalidModuleName(String),
+
+    /// The thin archive could not be parsed.
+    #[error("`{0}`: invalid thin archive: {1}")]
+    InvalidThinArchive(PathBuf, String),
+
+    /// A member of a thin archive could not be found.
+    #[error("thin archive {0} references missing member {1}")]
+    MissingThinArchiveMember(PathBuf, PathBuf),
+
+    /// The bitcode section is compressed with an unsupported algorithm.
+    #[error("`{0}`: unsupported compression type {1} of the bitcode section")]
+    UnsupportedSectionCompression(PathBuf, u32),
+
+    /// The maximum length of the BTF type names is too short to fit their hash.
+    #[error(
+        "invalid BTF type name length {0}, must be at least {}",
+        MIN_BTF_TYPE_NAME_LEN
+    )]
+    InvalidBtfTypeNameMaxLen(usize),
+
+    /// Both symbols to export and symbols to internalize were given.
+    #[error("exporting symbols and internalizing only some symbols are mutually exclusive")]
+    ConflictingExportOptions,
+
+    /// Some of the symbols to export are not defined by any input.
+    #[error("exported symbols not found: {}", .0.join(", "))]
+    MissingExports(Vec<String>),
+
+    /// Sanitizing the debug info for BTF failed.
+    #[error("error sanitizing debug info for BTF: {0}")]
+    Btf(#[from] BtfSanitizeError),
+
+    /// An input was compiled for a BPF target with a different endianness than the output.
+    #[error("input {input} doesn't match the endianness of the target {expected}")]
+    EndiannessMismatch { input: PathBuf, expected: String },
+
+    /// The generated object has no BTF to write to a separate file.
+    #[error("no .BTF section in the generated object, BTF emission requires --btf and debug info")]
+    MissingBtfSection,
+
+    /// The generated object could not be read back.
+    #[error("failed to read the generated object: {0}")]
+    ReadObjectError(String),
+
+    /// LLVM issued a diagnostic with error severity.
+    #[error("LLVM issued diagnostic with error severity")]
+    LLVMError,
+
+    /// A library to link wasn't found in the library search path.
+    #[error("library `{0}` not found in the library search path")]
+    LibraryNotFound(String),
+
+    /// The data layout can't be passed to LLVM.
+    #[error("invalid data layout `{0}`")]
+    InvalidDataLayout(String),
+
+    /// The optimized module is invalid.
+    #[error("module verification failed: {0}")]
+    ModuleVerificationFailed(String),
+
+    /// LLVM issued more error diagnostics than allowed by [`LinkerOptions::max_errors`].
+    #[error("aborting after {0} LLVM errors")]
+    TooManyErrors(usize),
+
+    /// Linking was cancelled through [`LinkerOptions::cancel`].
+    #[error("linking cancelled")]
+    Cancelled,
 }
 
 /// BPF Cpu type
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Cpu {
     Generic,
     Probe,
     V1,
     V2,
     V3,
+    V4,
 }
 
 impl Cpu {
+    /// All the processors, from the oldest to the newest instruction set.
+    pub const ALL: [Cpu; 6] = [Cpu::Generic, Cpu::Probe, Cpu::V1, Cpu::V2, Cpu::V3, Cpu::V4];
+
+    /// Returns a one line description of the processor.
+    pub fn description(self) -> &'static str {
+        use Cpu::*;
+        match self {
+            Generic => "the base instruction set, same as v1",
+            Probe => "the newest instruction set supported by the running kernel",
+            V1 => "the original eBPF instruction set",
+            V2 => "adds the jlt, jle, jslt and jsle jumps",
+            V3 => "adds the 32-bit jumps and ALU32",
+            V4 => {
+                "adds the sign extending loads and moves, bswap, sdiv, smod and 32-bit jump offsets"
+            }
+        }
+    }
+
     fn to_str(self) -> &'static str {
         use Cpu::*;
         match self {
@@ -98,6 +244,7 @@ impl Cpu {
             V1 => "v1",
             V2 => "v2",
             V3 => "v3",
+            V4 => "v4",
         }
     }
 }
@@ -119,13 +266,425 @@ impl FromStr for Cpu {
             "v1" => V1,
             "v2" => V2,
             "v3" => V3,
+            "v4" => V4,
             _ => return Err(LinkerError::InvalidCpu(s.to_string())),
         })
     }
 }
 
-/// Optimization level
+/// BPF Cpu feature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuFeature {
+    Alu32,
+    Dummy,
+    Dwarfris,
+}
+
+impl CpuFeature {
+    /// All the known features.
+    pub const ALL: [CpuFeature; 3] = [CpuFeature::Alu32, CpuFeature::Dummy, CpuFeature::Dwarfris];
+
+    /// Returns a one line description of the feature.
+    pub fn description(self) -> &'static str {
+        use CpuFeature::*;
+        match self {
+            Alu32 => "enable the 32-bit ALU instructions and subregisters",
+            Dummy => "unused feature, kept for compatibility with LLVM",
+            Dwarfris => "disable MCAsmInfo DwarfUsesRelocationsAcrossSections",
+        }
+    }
+
+    fn to_str(self) -> &'static str {
+        use CpuFeature::*;
+        match self {
+            Alu32 => "alu32",
+            Dummy => "dummy",
+            Dwarfris => "dwarfris",
+        }
+    }
+
+    // Suggests the known feature closest to the invalid `feature`, keeping its `+` or `-` prefix.
+    fn suggestion(feature: &str) -> Option<String> {
+        let (prefix, name) = match feature.strip_prefix(['+', '-']) {
+            Some(name) => (&feature[..1], name),
+            None => ("+", feature),
+        };
+        CpuFeature::ALL
+            .into_iter()
+            .map(|known| (edit_distance(name, known.to_str()), known))
+            .filter(|(distance, _)| *distance <= 2)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, known)| format!("{prefix}{known}"))
+    }
+}
+
+// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev + usize::from(ca != *cb);
+            prev = row[j + 1];
+            row[j + 1] = substitution.min(prev + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl std::fmt::Display for CpuFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl FromStr for CpuFeature {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CpuFeature::ALL
+            .into_iter()
+            .find(|feature| feature.to_str() == s)
+            .ok_or_else(|| LinkerError::InvalidCpuFeature(s.to_string()))
+    }
+}
+
+/// Set of BPF Cpu features to enable or disable
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BpfCpuFeatures {
+    features: Vec<(CpuFeature, bool)>,
+    // Features unknown to bpf-linker, passed to LLVM as they are. Only set by
+    // `BpfCpuFeatures::parse_unchecked`.
+    unknown: Vec<String>,
+}
+
+impl BpfCpuFeatures {
+    /// Enable the given feature.
+    pub fn enable(&mut self, feature: CpuFeature) {
+        self.set(feature, true)
+    }
+
+    /// Disable the given feature.
+    pub fn disable(&mut self, feature: CpuFeature) {
+        self.set(feature, false)
+    }
+
+    /// Like parsing with [`FromStr`], but keeps the features unknown to bpf-linker instead of
+    /// failing, so that features supported by newer LLVM versions can still be used.
+    pub fn parse_unchecked(s: &str) -> Result<Self, LinkerError> {
+        Self::parse(s, true)
+    }
+
+    fn parse(s: &str, allow_unknown: bool) -> Result<Self, LinkerError> {
+        let mut features = BpfCpuFeatures::default();
+        for feature in s.split(',').filter(|feature| !feature.is_empty()) {
+            let invalid = || LinkerError::InvalidCpuFeature(feature.to_string());
+            let (name, enabled) = if let Some(name) = feature.strip_prefix('+') {
+                (name, true)
+            } else if let Some(name) = feature.strip_prefix('-') {
+                (name, false)
+            } else {
+                return Err(invalid());
+            };
+            match name.parse() {
+                Ok(name) => features.set(name, enabled),
+                Err(_) if allow_unknown && !name.is_empty() => {
+                    features.unknown.retain(|f| f[1..] != *name);
+                    features.unknown.push(feature.to_string());
+                }
+                Err(_) => return Err(invalid()),
+            }
+        }
+        Ok(features)
+    }
+
+    fn set(&mut self, feature: CpuFeature, enabled: bool) {
+        self.features.retain(|(f, _)| *f != feature);
+        self.features.push((feature, enabled));
+    }
+
+    fn get(&self, feature: CpuFeature) -> Option<bool> {
+        self.features
+            .iter()
+            .find_map(|(f, enabled)| (*f == feature).then_some(*enabled))
+    }
+
+    // Whether both sets enable and disable the same features, regardless of their order.
+    fn same_features(&self, other: &BpfCpuFeatures) -> bool {
+        CpuFeature::ALL
+            .into_iter()
+            .all(|feature| self.get(feature) == other.get(feature))
+    }
+}
+
+impl std::fmt::Display for BpfCpuFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (feature, enabled)) in self.features.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}{feature}", if *enabled { '+' } else { '-' })?;
+        }
+        for (i, feature) in self.unknown.iter().enumerate() {
+            if i > 0 || !self.features.is_empty() {
+                f.write_str(",")?;
+            }
+            f.write_str(feature)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BpfCpuFeatures {
+    type Err = LinkerError;
+
+    /// Parses a comma separated list of features, each prefixed with `+` to enable it or `-` to
+    /// disable it. For example `+alu32,-dwarfris`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s, false)
+    }
+}
+
+/// Memory builtin which can be exported from the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryBuiltin {
+    Memcpy,
+    Memmove,
+    Memset,
+    Memcmp,
+    Bcmp,
+    /// Not exported by default, only when explicitly enabled.
+    Strlen,
+}
+
+impl MemoryBuiltin {
+    const ALL: [MemoryBuiltin; 6] = [
+        MemoryBuiltin::Memcpy,
+        MemoryBuiltin::Memmove,
+        MemoryBuiltin::Memset,
+        MemoryBuiltin::Memcmp,
+        MemoryBuiltin::Bcmp,
+        MemoryBuiltin::Strlen,
+    ];
+
+    // The builtins exported by default.
+    const DEFAULT: [MemoryBuiltin; 5] = [
+        MemoryBuiltin::Memcpy,
+        MemoryBuiltin::Memmove,
+        MemoryBuiltin::Memset,
+        MemoryBuiltin::Memcmp,
+        MemoryBuiltin::Bcmp,
+    ];
+
+    fn to_str(self) -> &'static str {
+        use MemoryBuiltin::*;
+        match self {
+            Memcpy => "memcpy",
+            Memmove => "memmove",
+            Memset => "memset",
+            Memcmp => "memcmp",
+            Bcmp => "bcmp",
+            Strlen => "strlen",
+        }
+    }
+}
+
+impl std::fmt::Display for MemoryBuiltin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl FromStr for MemoryBuiltin {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MemoryBuiltin::ALL
+            .into_iter()
+            .find(|builtin| builtin.to_str() == s)
+            .ok_or_else(|| LinkerError::InvalidMemoryBuiltin(s.to_string()))
+    }
+}
+
+/// Set of memory builtins to export. All of them except `strlen` are exported by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryBuiltinSet {
+    builtins: Vec<MemoryBuiltin>,
+}
+
+impl MemoryBuiltinSet {
+    /// A set without any builtin.
+    pub fn none() -> Self {
+        MemoryBuiltinSet {
+            builtins: Vec::new(),
+        }
+    }
+
+    /// Enable the given builtin.
+    pub fn enable(&mut self, builtin: MemoryBuiltin) {
+        if !self.contains(builtin) {
+            self.builtins.push(builtin);
+        }
+    }
+
+    /// Disable the given builtin.
+    pub fn disable(&mut self, builtin: MemoryBuiltin) {
+        self.builtins.retain(|b| *b != builtin);
+    }
+
+    /// Whether the given builtin is enabled.
+    pub fn contains(&self, builtin: MemoryBuiltin) -> bool {
+        self.builtins.contains(&builtin)
+    }
+
+    /// Iterate over the enabled builtins.
+    pub fn iter(&self) -> impl Iterator<Item = MemoryBuiltin> + '_ {
+        self.builtins.iter().copied()
+    }
+}
+
+impl Default for MemoryBuiltinSet {
+    fn default() -> Self {
+        MemoryBuiltinSet {
+            builtins: MemoryBuiltin::DEFAULT.to_vec(),
+        }
+    }
+}
+
+/// Relocation model
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RelocMode {
+    /// The default of the target, static for BPF.
+    Default,
+    Static,
+    Pic,
+    DynamicNoPic,
+}
+
+impl RelocMode {
+    fn to_str(self) -> &'static str {
+        use RelocMode::*;
+        match self {
+            Default => "default",
+            Static => "static",
+            Pic => "pic",
+            DynamicNoPic => "dynamic-no-pic",
+        }
+    }
+}
+
+impl std::fmt::Display for RelocMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl FromStr for RelocMode {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use RelocMode::*;
+        Ok(match s {
+            "default" => Default,
+            "static" => Static,
+            "pic" => Pic,
+            "dynamic-no-pic" => DynamicNoPic,
+            _ => return Err(LinkerError::InvalidRelocMode(s.to_string())),
+        })
+    }
+}
+
+/// Code model
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CodeModel {
+    /// The default of the target, small for BPF.
+    Default,
+    Tiny,
+    Small,
+    Kernel,
+    Medium,
+    Large,
+}
+
+impl CodeModel {
+    fn to_str(self) -> &'static str {
+        use CodeModel::*;
+        match self {
+            Default => "default",
+            Tiny => "tiny",
+            Small => "small",
+            Kernel => "kernel",
+            Medium => "medium",
+            Large => "large",
+        }
+    }
+}
+
+impl std::fmt::Display for CodeModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl FromStr for CodeModel {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use CodeModel::*;
+        Ok(match s {
+            "default" => Default,
+            "tiny" => Tiny,
+            "small" => Small,
+            "kernel" => Kernel,
+            "medium" => Medium,
+            "large" => Large,
+            _ => return Err(LinkerError::InvalidCodeModel(s.to_string())),
+        })
+    }
+}
+
+/// Link map format
 #[derive(Clone, Copy, Debug)]
+pub enum LinkMapFormat {
+    /// One line per symbol.
+    Text,
+    /// An array of objects, one per symbol.
+    Json,
+}
+
+impl LinkMapFormat {
+    fn to_str(self) -> &'static str {
+        use LinkMapFormat::*;
+        match self {
+            Text => "text",
+            Json => "json",
+        }
+    }
+}
+
+impl std::fmt::Display for LinkMapFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl FromStr for LinkMapFormat {
+    type Err = LinkerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use LinkMapFormat::*;
+        Ok(match s {
+            "text" => Text,
+            "json" => Json,
+            _ => return Err(LinkerError::InvalidLinkMapFormat(s.to_string())),
+        })
+    }
+}
+
+/// Optimization level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum OptLevel {
     /// No optimizations. Equivalent to -O0.
     No,
@@ -152,6 +711,10 @@ enum InputType {
     MachO,
     /// Archive file. (.a)
     Archive,
+    /// GNU thin archive file, which only references its members. (.a)
+    ThinArchive,
+    /// Textual LLVM IR. (.ll)
+    LlvmIr,
 }
 
 impl std::fmt::Display for InputType {
@@ -165,6 +728,8 @@ impl std::fmt::Display for InputType {
                 Elf => "elf",
                 MachO => "Mach-O",
                 Archive => "archive",
+                ThinArchive => "thin archive",
+                LlvmIr => "LLVM IR",
             }
         )
     }
@@ -183,6 +748,68 @@ pub enum OutputType {
     Object,
 }
 
+impl OutputType {
+    /// The conventional file extension of the output type.
+    pub fn extension(self) -> &'static str {
+        use OutputType::*;
+        match self {
+            Bitcode => "bc",
+            Assembly => "s",
+            LlvmAssembly => "ll",
+            Object => "o",
+        }
+    }
+
+    // Whether generating the output runs the codegen pipeline, which modifies the module.
+    fn is_machine_code(self) -> bool {
+        matches!(self, OutputType::Assembly | OutputType::Object)
+    }
+}
+
+/// Linker input
+#[derive(Clone)]
+pub enum LinkerInput {
+    /// A file on disk. Can be bitcode, an object file with embedded bitcode or an archive file.
+    File { path: PathBuf },
+    /// An in-memory buffer with the same contents as a file input. The name is used in logs and
+    /// errors.
+    Buffer { name: String, bytes: Vec<u8> },
+}
+
+impl LinkerInput {
+    /// Create an input that reads the file at `path`.
+    pub fn new_from_file(path: impl Into<PathBuf>) -> Self {
+        LinkerInput::File { path: path.into() }
+    }
+
+    /// Create an input from an in-memory buffer.
+    pub fn new_from_bytes(name: &str, data: Vec<u8>) -> Self {
+        LinkerInput::Buffer {
+            name: name.to_owned(),
+            bytes: data,
+        }
+    }
+}
+
+impl From<PathBuf> for LinkerInput {
+    fn from(path: PathBuf) -> Self {
+        Self::new_from_file(path)
+    }
+}
+
+impl std::fmt::Debug for LinkerInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkerInput::File { path } => f.debug_struct("File").field("path", path).finish(),
+            LinkerInput::Buffer { name, bytes } => f
+                .debug_struct("Buffer")
+                .field("name", name)
+                .field("len", &bytes.len())
+                .finish(),
+        }
+    }
+}
+
 /// Options to configure the linker
 #[derive(Debug)]
 pub struct LinkerOptions {
@@ -192,428 +819,3698 @@ pub struct LinkerOptions {
     /// Cpu type.
     pub cpu: Cpu,
     /// Cpu features.
-    pub cpu_features: String,
-    /// Input files. Can be bitcode, object files with embedded bitcode or archive files.
-    pub inputs: Vec<PathBuf>,
-    /// Where to save the output.
+    pub cpu_features: BpfCpuFeatures,
+    /// Inputs. Can be bitcode, object files with embedded bitcode or archive files.
+    pub inputs: Vec<LinkerInput>,
+    /// Where to save the output. Not used by [`Linker::link_to_buffer`], except for naming the
+    /// linked module.
     pub output: PathBuf,
     /// The format to output.
     pub output_type: OutputType,
+    /// Library search path, see [`LinkerOptions::link_libs`].
     pub libs: Vec<PathBuf>,
+    /// Libraries to link, like `foo` or `static=foo`. They are looked up as `libfoo.a` and
+    /// `libfoo.rlib` in the directories of [`LinkerOptions::libs`], and linked after the inputs.
+    pub link_libs: Vec<String>,
     /// Optimization level.
     pub optimize: OptLevel,
+    /// Pass pipeline to run instead of the default one for the optimization level, in the syntax
+    /// of `opt -passes`, e.g. `default<Os>,bpf-preserve-static-offset`.
+    pub passes: Option<String>,
+    /// Relocation model of the generated code.
+    pub reloc_mode: RelocMode,
+    /// Code model of the generated code.
+    pub code_model: CodeModel,
     /// Set of symbol names to export.
     pub export_symbols: HashSet<Cow<'static, str>>,
+    /// Fail when some of the symbols to export are not defined, instead of warning about them.
+    pub error_on_missing_export: bool,
+    /// Patterns of symbol names to export, in addition to `export_symbols`. Every defined symbol
+    /// matching any of them is kept external.
+    pub export_patterns: Vec<Regex>,
+    /// Set of symbol names to internalize. When not empty, all the other symbols are exported.
+    /// Mutually exclusive with `export_symbols`.
+    pub internalize_only: HashSet<Cow<'static, str>>,
     /// Whether to aggressively unroll loops. Useful for older kernels that don't support loops.
     pub unroll_loops: bool,
+    /// Cost threshold of the loops to unroll when [`LinkerOptions::unroll_loops`] is set.
+    /// Defaults to `u32::MAX`.
+    pub unroll_threshold: Option<u32>,
+    /// Maximum trip count of the loops to fully unroll when [`LinkerOptions::unroll_loops`] is
+    /// set. Defaults to `u32::MAX`.
+    pub unroll_max_upperbound: Option<u32>,
     /// Remove `noinline` attributes from functions. Useful for kernels before 5.8 that don't
     /// support function calls.
     pub ignore_inline_never: bool,
     /// Write the linked module IR before and after optimization.
     pub dump_module: Option<PathBuf>,
+    /// Read the block structure of each bitcode input before linking it, see
+    /// [`Linker::bitcode_blocks`].
+    pub dump_bitcode_blocks: bool,
+    /// Data layout of the output module, e.g. `e-m:e-p:64:64-i64:64-i128:128-n32:64-S128`, the
+    /// layout of little endian BPF. When not set, the layout of the inputs is used and inputs
+    /// with different layouts are warned about. LLVM aborts on malformed layouts.
+    pub data_layout: Option<String>,
+    /// Run the LLVM verifier on the module after optimization, before generating the code.
+    pub verify_module: bool,
+    /// Write the module as bitcode and IR to the given directory after linking
+    /// (`linked.bc`/`linked.ll`), after optimization (`optimized.bc`/`optimized.ll`) and before
+    /// generating the code (`codegen.bc`/`codegen.ll`).
+    pub save_temps: Option<PathBuf>,
+    /// Write a make rule listing the linked input files to the given path. Archives are listed
+    /// themselves in the rule, and their linked members follow as `# archive(member)` comments.
+    /// The members of thin archives are listed as the files they are.
+    pub emit_deps: Option<PathBuf>,
+    /// Write the symbols which were internalized and the symbols which were removed during
+    /// optimization to the given path.
+    pub report_internalized: Option<PathBuf>,
+    /// Write the symbols left after optimization, with their section, linkage and approximate
+    /// size, to the given path.
+    pub link_map: Option<PathBuf>,
+    /// Format of the link map written to `link_map`.
+    pub link_map_format: LinkMapFormat,
     /// Extra command line args to pass to LLVM.
+    ///
+    /// LLVM's command line is global to the process and only parsed by the first linker which
+    /// links. It includes these arguments as well as the ones derived from `unroll_loops`,
+    /// `disable_expand_memcpy_in_order`, `time_passes` and `print_stats`, so later linkers can't
+    /// change any of them.
     pub llvm_args: Vec<String>,
+    /// Report the time spent in each LLVM pass. The report is printed to stderr, unless
+    /// `time_passes_file` is set.
+    pub time_passes: bool,
+    /// Write the pass timing and the statistics reports to the given path instead of stderr.
+    pub time_passes_file: Option<PathBuf>,
+    /// Collect the statistics of the LLVM passes. LLVM only prints them when it's shut down with
+    /// `LLVMShutdown`, to stderr unless `time_passes_file` is set. Most statistics are only
+    /// available when LLVM is built with assertions. The number of basic blocks and IR
+    /// instructions of each function is also counted after optimizing, whichever LLVM is used,
+    /// see [`Linker::function_stats`].
+    pub print_stats: bool,
     /// Disable passing --bpf-expand-memcpy-in-order to LLVM.
     pub disable_expand_memcpy_in_order: bool,
-    /// Disable exporting memcpy, memmove, memset, memcmp and bcmp. Exporting
-    /// those is commonly needed when LLVM does not manage to expand memory
-    /// intrinsics to a sequence of loads and stores.
-    pub disable_memory_builtins: bool,
+    /// Memory builtins to export, out of memcpy, memmove, memset, memcmp, bcmp and strlen. All
+    /// of them but strlen are exported by default. Exporting those is commonly needed when LLVM
+    /// does not manage to expand memory intrinsics to a sequence of loads and stores.
+    pub memory_builtins: MemoryBuiltinSet,
     /// Emit BTF information
     pub btf: bool,
+    /// Keep the debug info of the inputs as it is, instead of stripping it or sanitizing it for
+    /// BTF. The output keeps its DWARF for offline analysis, but the BTF generated from the
+    /// unsanitized debug info is usually rejected by the kernel.
+    pub preserve_debug_info: bool,
+    /// Downgrade 64-bit enums to 32 bits in BTF, for kernels without BTF_KIND_ENUM64 support.
+    pub downgrade_enum64: bool,
+    /// Maximum length of the type names in BTF. Longer names are truncated and suffixed with a
+    /// hash. Defaults to 128, the lowest `KSYM_NAME_LEN` across kernel versions, and can't be less
+    /// than [`MIN_BTF_TYPE_NAME_LEN`].
+    pub btf_type_name_max_len: Option<usize>,
+    /// Keep the variants of data-carrying enums in BTF instead of stripping them. Only useful for
+    /// kernels which accept such types.
+    pub keep_variant_enums: bool,
+    /// Merge the structs and unions which have the same name after sanitization and the same
+    /// members into a single BTF type.
+    pub dedup_btf_types: bool,
+    /// Number of threads used to parse and link the members of archives. Defaults to one.
+    pub jobs: Option<usize>,
+    /// Name of the section containing the embedded bitcode of object files. Defaults to `.llvmbc`
+    /// for ELF and `__bitcode` for Mach-O files.
+    pub bitcode_section: Option<String>,
+    /// Delete the functions which can't be reached from the exported symbols, the programs and the
+    /// global variables after optimizing.
+    pub gc_functions: bool,
+    /// Sections to rename, as `(old, new)` pairs. The functions in the section `old`, or in a
+    /// section starting with `old/`, are moved to `new`, keeping the rest of the section name.
+    pub section_renames: Vec<(String, String)>,
+    /// Abort linking at the end of the current phase once LLVM reported this many errors. Zero
+    /// aborts on the first error, like one.
+    pub max_errors: Option<usize>,
+    /// Treat the warnings reported by LLVM as errors.
+    pub deny_warnings: bool,
+    /// Ignore the diagnostics reported by LLVM which contain any of these strings, in addition to
+    /// the errors about unsupported calls to memory builtins.
+    pub suppress_diagnostics: Vec<String>,
+    /// Log the diagnostics which are suppressed.
+    pub list_suppressed: bool,
+    /// Only link the inputs to validate them, without generating any code or writing any output.
+    pub dry_run: bool,
+    /// Also write the `.BTF` section of the generated object to the given path. Not used by
+    /// [`Linker::link_to_buffer`].
+    pub emit_btf: Option<PathBuf>,
+    /// Store a fingerprint of the inputs and of the options affecting the output to the given
+    /// path. When the fingerprint is unchanged and the outputs, including side outputs such as
+    /// `link_map` or `save_temps`, exist, linking is skipped. Not used by
+    /// [`Linker::link_to_buffer`].
+    pub fingerprint: Option<PathBuf>,
+    /// Cancel linking when set to `true`, e.g. by another thread enforcing a timeout. It's checked
+    /// between the linking, optimization and code generation phases, a running LLVM pass can't be
+    /// interrupted.
+    pub cancel: Option<Arc<AtomicBool>>,
 }
 
-/// BPF Linker
-pub struct Linker {
+/// Builder for [`LinkerOptions`]
+///
+/// Options which are not set keep the defaults of the command line linker: a `generic` CPU, `-O2`,
+/// object output and memory builtins exported.
+#[derive(Debug)]
+pub struct LinkerOptionsBuilder {
     options: LinkerOptions,
-    context: LLVMContextRef,
-    module: LLVMModuleRef,
-    target_machine: LLVMTargetMachineRef,
-    has_errors: bool,
 }
 
-impl Linker {
-    /// Create a new linker instance with the given options.
-    pub fn new(options: LinkerOptions) -> Self {
-        Linker {
-            options,
-            context: ptr::null_mut(),
-            module: ptr::null_mut(),
-            target_machine: ptr::null_mut(),
-            has_errors: false,
+impl LinkerOptionsBuilder {
+    /// Create a builder for options writing the output to `output`.
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        LinkerOptionsBuilder {
+            options: LinkerOptions {
+                target: None,
+                cpu: Cpu::Generic,
+                cpu_features: BpfCpuFeatures::default(),
+                inputs: Vec::new(),
+                output: output.into(),
+                output_type: OutputType::Object,
+                libs: Vec::new(),
+                link_libs: Vec::new(),
+                optimize: OptLevel::Default,
+                passes: None,
+                reloc_mode: RelocMode::Default,
+                code_model: CodeModel::Default,
+                export_symbols: HashSet::new(),
+                error_on_missing_export: false,
+                export_patterns: Vec::new(),
+                internalize_only: HashSet::new(),
+                unroll_loops: false,
+                unroll_threshold: None,
+                unroll_max_upperbound: None,
+                ignore_inline_never: false,
+                dump_module: None,
+                dump_bitcode_blocks: false,
+                data_layout: None,
+                verify_module: false,
+                save_temps: None,
+                emit_deps: None,
+                report_internalized: None,
+                link_map: None,
+                link_map_format: LinkMapFormat::Text,
+                llvm_args: Vec::new(),
+                time_passes: false,
+                time_passes_file: None,
+                print_stats: false,
+                disable_expand_memcpy_in_order: false,
+                memory_builtins: MemoryBuiltinSet::default(),
+                btf: false,
+                preserve_debug_info: false,
+                downgrade_enum64: false,
+                btf_type_name_max_len: None,
+                keep_variant_enums: false,
+                dedup_btf_types: false,
+                jobs: None,
+                bitcode_section: None,
+                gc_functions: false,
+                section_renames: Vec::new(),
+                max_errors: None,
+                deny_warnings: false,
+                suppress_diagnostics: Vec::new(),
+                list_suppressed: false,
+                dry_run: false,
+                emit_btf: None,
+                fingerprint: None,
+                cancel: None,
+            },
         }
     }
 
-    /// Link and generate the output code.
-    pub fn link(&mut self) -> Result<(), LinkerError> {
-        self.llvm_init();
-        self.link_modules()?;
-        self.create_target_machine()?;
-        if let Some(path) = &self.options.dump_module {
-            std::fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.clone(), err))?;
+    /// Set the LLVM target triple. See [`LinkerOptions::target`].
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.options.target = Some(target.into());
+        self
+    }
+
+    pub fn cpu(mut self, cpu: Cpu) -> Self {
+        self.options.cpu = cpu;
+        self
+    }
+
+    pub fn cpu_features(mut self, cpu_features: BpfCpuFeatures) -> Self {
+        self.options.cpu_features = cpu_features;
+        self
+    }
+
+    /// Add an input.
+    pub fn input(mut self, input: impl Into<LinkerInput>) -> Self {
+        self.options.inputs.push(input.into());
+        self
+    }
+
+    pub fn output_type(mut self, output_type: OutputType) -> Self {
+        self.options.output_type = output_type;
+        self
+    }
+
+    /// Add a directory to the library search path.
+    pub fn lib(mut self, lib: impl Into<PathBuf>) -> Self {
+        self.options.libs.push(lib.into());
+        self
+    }
+
+    /// Add a library to link. See [`LinkerOptions::link_libs`].
+    pub fn link_lib(mut self, name: impl Into<String>) -> Self {
+        self.options.link_libs.push(name.into());
+        self
+    }
+
+    pub fn optimize(mut self, optimize: OptLevel) -> Self {
+        self.options.optimize = optimize;
+        self
+    }
+
+    pub fn reloc_mode(mut self, reloc_mode: RelocMode) -> Self {
+        self.options.reloc_mode = reloc_mode;
+        self
+    }
+
+    pub fn code_model(mut self, code_model: CodeModel) -> Self {
+        self.options.code_model = code_model;
+        self
+    }
+
+    /// Add a symbol to export.
+    pub fn export_symbol(mut self, symbol: impl Into<Cow<'static, str>>) -> Self {
+        let _: bool = self.options.export_symbols.insert(symbol.into());
+        self
+    }
+
+    /// Export every defined symbol whose name matches `pattern`.
+    pub fn export_pattern(mut self, pattern: Regex) -> Self {
+        self.options.export_patterns.push(pattern);
+        self
+    }
+
+    /// Move the functions in the section `old`, or in a section starting with `old/`, to `new`.
+    /// See [`LinkerOptions::section_renames`].
+    pub fn rename_section(mut self, old: impl Into<String>, new: impl Into<String>) -> Self {
+        self.options.section_renames.push((old.into(), new.into()));
+        self
+    }
+
+    /// Add a symbol to internalize. See [`LinkerOptions::internalize_only`].
+    pub fn internalize_symbol(mut self, symbol: impl Into<Cow<'static, str>>) -> Self {
+        let _: bool = self.options.internalize_only.insert(symbol.into());
+        self
+    }
+
+    pub fn unroll_loops(mut self, unroll_loops: bool) -> Self {
+        self.options.unroll_loops = unroll_loops;
+        self
+    }
+
+    pub fn unroll_threshold(mut self, unroll_threshold: u32) -> Self {
+        self.options.unroll_threshold = Some(unroll_threshold);
+        self
+    }
+
+    pub fn unroll_max_upperbound(mut self, unroll_max_upperbound: u32) -> Self {
+        self.options.unroll_max_upperbound = Some(unroll_max_upperbound);
+        self
+    }
+
+    pub fn ignore_inline_never(mut self, ignore_inline_never: bool) -> Self {
+        self.options.ignore_inline_never = ignore_inline_never;
+        self
+    }
+
+    /// Add an extra command line argument to pass to LLVM.
+    pub fn llvm_arg(mut self, arg: impl Into<String>) -> Self {
+        self.options.llvm_args.push(arg.into());
+        self
+    }
+
+    pub fn disable_memory_builtins(mut self, disable_memory_builtins: bool) -> Self {
+        self.options.memory_builtins = if disable_memory_builtins {
+            MemoryBuiltinSet::none()
+        } else {
+            MemoryBuiltinSet::default()
+        };
+        self
+    }
+
+    pub fn disable_memory_builtin(mut self, builtin: MemoryBuiltin) -> Self {
+        self.options.memory_builtins.disable(builtin);
+        self
+    }
+
+    /// Export the given memory builtin, even when the memory builtins were disabled.
+    pub fn export_memory_builtin(mut self, builtin: MemoryBuiltin) -> Self {
+        self.options.memory_builtins.enable(builtin);
+        self
+    }
+
+    pub fn btf(mut self, btf: bool) -> Self {
+        self.options.btf = btf;
+        self
+    }
+
+    pub fn preserve_debug_info(mut self, preserve_debug_info: bool) -> Self {
+        self.options.preserve_debug_info = preserve_debug_info;
+        self
+    }
+
+    pub fn downgrade_enum64(mut self, downgrade_enum64: bool) -> Self {
+        self.options.downgrade_enum64 = downgrade_enum64;
+        self
+    }
+
+    /// Cancel linking once `cancel` is set. See [`LinkerOptions::cancel`].
+    pub fn cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.options.cancel = Some(cancel);
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.options.jobs = Some(jobs);
+        self
+    }
+
+    /// Run the given pass pipeline instead of the default one. See [`LinkerOptions::passes`].
+    pub fn passes(mut self, passes: impl Into<String>) -> Self {
+        self.options.passes = Some(passes.into());
+        self
+    }
+
+    pub fn error_on_missing_export(mut self, error_on_missing_export: bool) -> Self {
+        self.options.error_on_missing_export = error_on_missing_export;
+        self
+    }
+
+    /// Write the linked module IR before and after optimization to `path`.
+    pub fn dump_module(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.dump_module = Some(path.into());
+        self
+    }
+
+    pub fn dump_bitcode_blocks(mut self, dump_bitcode_blocks: bool) -> Self {
+        self.options.dump_bitcode_blocks = dump_bitcode_blocks;
+        self
+    }
+
+    /// Set the data layout of the output module. See [`LinkerOptions::data_layout`].
+    pub fn data_layout(mut self, data_layout: impl Into<String>) -> Self {
+        self.options.data_layout = Some(data_layout.into());
+        self
+    }
+
+    pub fn verify_module(mut self, verify_module: bool) -> Self {
+        self.options.verify_module = verify_module;
+        self
+    }
+
+    /// Write the intermediate modules to `dir`. See [`LinkerOptions::save_temps`].
+    pub fn save_temps(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.save_temps = Some(dir.into());
+        self
+    }
+
+    /// Write a make rule listing the input files to `path`.
+    pub fn emit_deps(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.emit_deps = Some(path.into());
+        self
+    }
+
+    /// Write the internalized and removed symbols to `path`.
+    pub fn report_internalized(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.report_internalized = Some(path.into());
+        self
+    }
+
+    /// Write the link map to `path`, see [`LinkerOptions::link_map`].
+    pub fn link_map(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.link_map = Some(path.into());
+        self
+    }
+
+    pub fn link_map_format(mut self, link_map_format: LinkMapFormat) -> Self {
+        self.options.link_map_format = link_map_format;
+        self
+    }
+
+    pub fn time_passes(mut self, time_passes: bool) -> Self {
+        self.options.time_passes = time_passes;
+        self
+    }
+
+    /// Write the LLVM reports to `path` instead of stderr.
+    pub fn time_passes_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.time_passes_file = Some(path.into());
+        self
+    }
+
+    pub fn print_stats(mut self, print_stats: bool) -> Self {
+        self.options.print_stats = print_stats;
+        self
+    }
+
+    pub fn disable_expand_memcpy_in_order(mut self, disable_expand_memcpy_in_order: bool) -> Self {
+        self.options.disable_expand_memcpy_in_order = disable_expand_memcpy_in_order;
+        self
+    }
+
+    /// Set the maximum length of the type names in BTF, see
+    /// [`LinkerOptions::btf_type_name_max_len`].
+    pub fn btf_type_name_max_len(mut self, max_len: usize) -> Self {
+        self.options.btf_type_name_max_len = Some(max_len);
+        self
+    }
+
+    pub fn keep_variant_enums(mut self, keep_variant_enums: bool) -> Self {
+        self.options.keep_variant_enums = keep_variant_enums;
+        self
+    }
+
+    pub fn dedup_btf_types(mut self, dedup_btf_types: bool) -> Self {
+        self.options.dedup_btf_types = dedup_btf_types;
+        self
+    }
+
+    /// Read the embedded bitcode of object files from `section`.
+    pub fn bitcode_section(mut self, section: impl Into<String>) -> Self {
+        self.options.bitcode_section = Some(section.into());
+        self
+    }
+
+    pub fn gc_functions(mut self, gc_functions: bool) -> Self {
+        self.options.gc_functions = gc_functions;
+        self
+    }
+
+    /// Abort linking once LLVM reported `max_errors` errors. See [`LinkerOptions::max_errors`].
+    pub fn max_errors(mut self, max_errors: usize) -> Self {
+        self.options.max_errors = Some(max_errors);
+        self
+    }
+
+    pub fn deny_warnings(mut self, deny_warnings: bool) -> Self {
+        self.options.deny_warnings = deny_warnings;
+        self
+    }
+
+    /// Ignore the diagnostics reported by LLVM which contain `pattern`.
+    pub fn suppress_diagnostic(mut self, pattern: impl Into<String>) -> Self {
+        self.options.suppress_diagnostics.push(pattern.into());
+        self
+    }
+
+    pub fn list_suppressed(mut self, list_suppressed: bool) -> Self {
+        self.options.list_suppressed = list_suppressed;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Also write the `.BTF` section of the generated object to `path`.
+    pub fn emit_btf(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.emit_btf = Some(path.into());
+        self
+    }
+
+    /// Skip linking when the fingerprint stored in `path` is unchanged. See
+    /// [`LinkerOptions::fingerprint`].
+    pub fn fingerprint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.fingerprint = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> LinkerOptions {
+        self.options
+    }
+}
+
+/// Severity of a diagnostic reported by LLVM
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+    Remark,
+    Note,
+}
+
+impl DiagnosticSeverity {
+    fn to_str(self) -> &'static str {
+        use DiagnosticSeverity::*;
+        match self {
+            Error => "error",
+            Warning => "warning",
+            Remark => "remark",
+            Note => "note",
+        }
+    }
+}
+
+impl std::fmt::Display for DiagnosticSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.to_str())
+    }
+}
+
+impl From<llvm_sys::LLVMDiagnosticSeverity> for DiagnosticSeverity {
+    fn from(severity: llvm_sys::LLVMDiagnosticSeverity) -> Self {
+        use llvm_sys::LLVMDiagnosticSeverity::*;
+        match severity {
+            LLVMDSError => Self::Error,
+            LLVMDSWarning => Self::Warning,
+            LLVMDSRemark => Self::Remark,
+            LLVMDSNote => Self::Note,
+        }
+    }
+}
+
+/// Source location of a diagnostic
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl SourceLocation {
+    /// Parses the first `file:line:column` location found in a diagnostic message.
+    ///
+    /// LLVM doesn't expose the location of a diagnostic through the C API, but most diagnostics
+    /// reported by the BPF backend are formatted as `file:line:column: in function ...: message`.
+    fn parse(message: &str) -> Option<Self> {
+        message.split(": ").find_map(|segment| {
+            let mut parts = segment.rsplitn(3, ':');
+            let column = parts.next()?.parse().ok()?;
+            let line = parts.next()?.parse().ok()?;
+            let file = parts.next().filter(|file| !file.is_empty())?;
+            Some(Self {
+                file: file.to_owned(),
+                line,
+                column,
+            })
+        })
+    }
+}
+
+// Triple, CPU, features, optimization level, relocation mode and code model of a target machine.
+type TargetMachineKey = (String, Cpu, String, OptLevel, RelocMode, CodeModel);
+
+/// Diagnostic reported by LLVM while linking
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+    /// Source location, when it could be found in the message.
+    pub location: Option<SourceLocation>,
+}
+
+/// Returns the major, minor and patch version of the LLVM library the linker uses.
+pub fn llvm_version() -> (u32, u32, u32) {
+    let (mut major, mut minor, mut patch) = (0, 0, 0);
+    unsafe { LLVMGetVersion(&mut major, &mut minor, &mut patch) };
+    (major, minor, patch)
+}
+
+/// BPF Linker
+///
+/// Errors reported by LLVM are returned as [`LinkerError`]s, except for LLVM fatal errors: LLVM
+/// exits the process after logging them, so they can't be recovered from. Embedders which must
+/// survive bad inputs should run the linker in a separate process.
+///
+/// A linker owns an LLVM context, which isn't thread safe, so it's neither `Send` nor `Sync`. To
+/// link on multiple threads, create a linker on each of them with the same LLVM options: LLVM's
+/// command line is global to the process, see [`LinkerOptions::llvm_args`].
+pub struct Linker {
+    options: LinkerOptions,
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    target_machine: LLVMTargetMachineRef,
+    target_machines: HashMap<TargetMachineKey, LLVMTargetMachineRef>,
+    error_count: usize,
+    diagnostics: Vec<Diagnostic>,
+    dependencies: Vec<PathBuf>,
+    archive_members: Vec<(PathBuf, PathBuf)>,
+    input_triples: Vec<(PathBuf, String)>,
+    skipped_inputs: Vec<(PathBuf, &'static str)>,
+    program_sections: Vec<String>,
+    input_data_layout: Option<(PathBuf, String)>,
+    bitcode_blocks: Vec<(PathBuf, Result<Vec<BitcodeBlock>, BitcodeError>)>,
+    function_stats: Vec<(String, usize, usize)>,
+}
+
+impl Linker {
+    /// Create a new linker instance with the given options.
+    pub fn new(options: LinkerOptions) -> Self {
+        Linker {
+            options,
+            context: ptr::null_mut(),
+            module: ptr::null_mut(),
+            target_machine: ptr::null_mut(),
+            target_machines: HashMap::new(),
+            error_count: 0,
+            diagnostics: Vec::new(),
+            dependencies: Vec::new(),
+            archive_members: Vec::new(),
+            input_triples: Vec::new(),
+            skipped_inputs: Vec::new(),
+            program_sections: Vec::new(),
+            input_data_layout: None,
+            bitcode_blocks: Vec::new(),
+            function_stats: Vec::new(),
+        }
+    }
+
+    /// Link and write the output code to [`LinkerOptions::output`].
+    pub fn link(&mut self) -> Result<(), LinkerError> {
+        let outputs = [(self.options.output_type, self.options.output.clone())];
+        self.link_to_files(&outputs)
+    }
+
+    /// Link `inputs` and write the output code to `output`, with the other options of this
+    /// linker.
+    ///
+    /// Unlike creating a new [`Linker`] for each output, the LLVM context and the target machines
+    /// are created once and reused by the following calls. The diagnostics and the inputs reported
+    /// by the linker only cover the last call.
+    pub fn link_one(
+        &mut self,
+        inputs: Vec<LinkerInput>,
+        output: impl Into<PathBuf>,
+    ) -> Result<(), LinkerError> {
+        self.reset();
+        self.options.inputs = inputs;
+        self.options.output = output.into();
+        self.link()
+    }
+
+    // Drop the module and the state of the previous link, keeping the context and the target
+    // machines.
+    fn reset(&mut self) {
+        if !self.module.is_null() {
+            unsafe { LLVMDisposeModule(self.module) };
+            self.module = ptr::null_mut();
+        }
+        self.target_machine = ptr::null_mut();
+        self.error_count = 0;
+        self.diagnostics.clear();
+        self.dependencies.clear();
+        self.archive_members.clear();
+        self.input_triples.clear();
+        self.skipped_inputs.clear();
+        self.program_sections.clear();
+        self.input_data_layout = None;
+        self.bitcode_blocks.clear();
+        self.function_stats.clear();
+    }
+
+    /// Link once and write the output code of each of the given types to the given path.
+    pub fn link_to_files(&mut self, outputs: &[(OutputType, PathBuf)]) -> Result<(), LinkerError> {
+        let fingerprint = match &self.options.fingerprint {
+            Some(path) if !self.options.dry_run => {
+                let fingerprint = self.fingerprint(outputs);
+                if fingerprint.is_some()
+                    && fs::read_to_string(path).ok() == fingerprint
+                    && outputs
+                        .iter()
+                        .map(|(_, output)| output)
+                        .chain(&self.side_outputs())
+                        .all(|output| output.exists())
+                {
+                    info!("inputs and options unchanged, not linking");
+                    return Ok(());
+                }
+                // The outputs are about to be overwritten, don't let a failed link leave a stale
+                // fingerprint behind.
+                match fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(LinkerError::IoError(path.clone(), e)),
+                }
+                fingerprint.map(|fingerprint| (path.clone(), fingerprint))
+            }
+            _ => None,
+        };
+
+        self.link_and_optimize()?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+        self.save_temps("codegen")?;
+
+        // Generating machine code modifies the module, so write the IR outputs first and
+        // generate all but the last machine code output from a copy of the module.
+        let emit_btf = self.options.emit_btf.clone();
+        if let Some(path) = &emit_btf {
+            // Generate an object just for the BTF when no object output was requested.
+            if !outputs
+                .iter()
+                .any(|(output_type, _)| matches!(output_type, OutputType::Object))
+            {
+                let object = self.codegen_module_copy(OutputType::Object, true)?;
+                self.write_btf(path, &object)?;
+            }
+        }
+
+        let mut outputs = outputs.iter().collect::<Vec<_>>();
+        outputs.sort_by_key(|(output_type, _)| output_type.is_machine_code());
+        let mut machine_code_outputs = outputs
+            .iter()
+            .filter(|(output_type, _)| output_type.is_machine_code())
+            .count();
+        for (output_type, path) in outputs {
+            let output = if output_type.is_machine_code() {
+                machine_code_outputs -= 1;
+                self.codegen_module_copy(*output_type, machine_code_outputs > 0)?
+            } else {
+                self.codegen(*output_type)?
+            };
+            self.check_error_count()?;
+            if let (OutputType::Object, Some(btf_path)) = (output_type, &emit_btf) {
+                self.write_btf(btf_path, &output)?;
+            }
+            info!("writing output to {:?}", path);
+            fs::write(path, output).map_err(|e| LinkerError::IoError(path.clone(), e))?;
+        }
+
+        if let Some((path, fingerprint)) = fingerprint {
+            info!("writing fingerprint to {:?}", path);
+            fs::write(&path, fingerprint).map_err(|e| LinkerError::IoError(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    // Files written by `link_to_files` besides the requested outputs.
+    fn side_outputs(&self) -> Vec<PathBuf> {
+        let LinkerOptions {
+            save_temps,
+            emit_deps,
+            report_internalized,
+            link_map,
+            emit_btf,
+            ..
+        } = &self.options;
+        let temps = save_temps.iter().flat_map(|dir| {
+            ["linked", "optimized", "codegen"]
+                .into_iter()
+                .flat_map(|stage| ["bc", "ll"].map(|ext| dir.join(format!("{stage}.{ext}"))))
+        });
+        temps
+            .chain(
+                [emit_deps, report_internalized, link_map, emit_btf]
+                    .into_iter()
+                    .flatten()
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    // Hashes the inputs, the options which affect the output and the requested outputs. Returns
+    // `None` when an input can't be read, linking then reports the error.
+    fn fingerprint(&self, outputs: &[(OutputType, PathBuf)]) -> Option<String> {
+        fn push_data(fingerprint: &mut String, name: &Path, data: &[u8]) {
+            fingerprint.push_str(&format!(
+                "input {} {:032x}\n",
+                name.display(),
+                llvm::fnv1a_128(data)
+            ));
+        }
+
+        // Options which only control diagnostics are left out, extra outputs are hashed as
+        // paths below.
+        let LinkerOptions {
+            target,
+            cpu,
+            cpu_features,
+            output_type: _,
+            libs,
+            optimize,
+            passes,
+            reloc_mode,
+            code_model,
+            export_symbols,
+            export_patterns,
+            internalize_only,
+            unroll_loops,
+            unroll_threshold,
+            unroll_max_upperbound,
+            ignore_inline_never,
+            data_layout,
+            llvm_args,
+            disable_expand_memcpy_in_order,
+            memory_builtins,
+            btf,
+            preserve_debug_info,
+            downgrade_enum64,
+            btf_type_name_max_len,
+            keep_variant_enums,
+            dedup_btf_types,
+            bitcode_section,
+            gc_functions,
+            section_renames,
+            link_map_format,
+            ..
+        } = &self.options;
+        // Sort the sets, their iteration order changes across runs.
+        let mut export_symbols = export_symbols.iter().collect::<Vec<_>>();
+        export_symbols.sort();
+        let mut internalize_only = internalize_only.iter().collect::<Vec<_>>();
+        internalize_only.sort();
+
+        let mut fingerprint = format!(
+            "bpf-linker {}\n{target:?} {cpu} {cpu_features} {libs:?} {optimize:?} {passes:?} \
+             {reloc_mode} {code_model} {export_symbols:?} {export_patterns:?} \
+             {internalize_only:?} {unroll_loops} {unroll_threshold:?} {unroll_max_upperbound:?} \
+             {ignore_inline_never} {data_layout:?} {llvm_args:?} {disable_expand_memcpy_in_order} \
+             {memory_builtins:?} \
+             {btf} {preserve_debug_info} {downgrade_enum64} {btf_type_name_max_len:?} \
+             {keep_variant_enums} {dedup_btf_types} {bitcode_section:?} {gc_functions} \
+             {section_renames:?} {link_map_format:?}\n",
+            env!("CARGO_PKG_VERSION"),
+        );
+        for (output_type, path) in outputs {
+            fingerprint.push_str(&format!("output {output_type:?} {}\n", path.display()));
+        }
+        for path in self.side_outputs() {
+            fingerprint.push_str(&format!("side output {}\n", path.display()));
+        }
+        let libraries = self.library_inputs().ok()?;
+        for input in self.options.inputs.iter().chain(&libraries) {
+            match input {
+                LinkerInput::File { path } => {
+                    let file = File::open(path).ok()?;
+                    let data = unsafe { Mmap::map(&file) }.ok()?;
+                    push_data(&mut fingerprint, path, &data);
+                    // The members of thin archives are stored next to them.
+                    if data.starts_with(THIN_ARCHIVE_MAGIC) {
+                        let dir = path.parent().unwrap_or(Path::new(""));
+                        for member in thin_archive_members(&data).ok()? {
+                            let member = dir.join(member);
+                            push_data(&mut fingerprint, &member, &fs::read(&member).ok()?);
+                        }
+                    }
+                }
+                LinkerInput::Buffer { name, bytes } => {
+                    push_data(&mut fingerprint, Path::new(name), bytes)
+                }
+            }
+        }
+
+        Some(format!(
+            "{:032x}\n",
+            llvm::fnv1a_128(fingerprint.as_bytes())
+        ))
+    }
+
+    /// Link and generate the output code into an in-memory buffer.
+    pub fn link_to_buffer(&mut self) -> Result<Vec<u8>, LinkerError> {
+        self.link_and_optimize()?;
+        if self.options.dry_run {
+            return Ok(Vec::new());
+        }
+        self.save_temps("codegen")?;
+        let output = self.codegen(self.options.output_type)?;
+        self.check_error_count()?;
+        Ok(output)
+    }
+
+    fn link_and_optimize(&mut self) -> Result<(), LinkerError> {
+        if (!self.options.export_symbols.is_empty() || !self.options.export_patterns.is_empty())
+            && !self.options.internalize_only.is_empty()
+        {
+            return Err(LinkerError::ConflictingExportOptions);
+        }
+        if let Some(max_len) = self
+            .options
+            .btf_type_name_max_len
+            .filter(|max_len| *max_len < MIN_BTF_TYPE_NAME_LEN)
+        {
+            return Err(LinkerError::InvalidBtfTypeNameMaxLen(max_len));
+        }
+        self.check_cancelled()?;
+        self.llvm_init()?;
+        if let Some(path) = &self.options.save_temps {
+            fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.clone(), err))?;
+        }
+        self.link_modules()?;
+        self.check_cancelled()?;
+        self.save_temps("linked")?;
+        if self.options.dry_run {
+            info!(
+                "dry run: linked {} inputs, skipped {}",
+                self.input_triples.len(),
+                self.skipped_inputs.len()
+            );
+            return Ok(());
+        }
+        if let Some(path) = &self.options.emit_deps {
+            self.write_dependency_file(path)?;
+        }
+        if !self.options.section_renames.is_empty() {
+            let renamed =
+                unsafe { llvm::rename_sections(self.module, &self.options.section_renames) }
+                    .map_err(LinkerError::InvalidSectionName)?;
+            info!("renamed the sections of {renamed} functions");
+        }
+        if let Some(data_layout) = &self.options.data_layout {
+            let c_data_layout = CString::new(data_layout.as_str())
+                .map_err(|_| LinkerError::InvalidDataLayout(data_layout.clone()))?;
+            info!("setting data layout {data_layout:?}");
+            unsafe { LLVMSetDataLayout(self.module, c_data_layout.as_ptr()) };
+        }
+        self.create_target_machine()?;
+        if let Some(path) = &self.options.dump_module {
+            std::fs::create_dir_all(path).map_err(|err| LinkerError::IoError(path.clone(), err))?;
         }
         if let Some(path) = &self.options.dump_module {
             // dump IR before optimization
             let path = path.join("pre-opt.ll");
-            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| LinkerError::InvalidOutputPath(path))?;
             self.write_ir(&path)?;
         };
+        self.check_cancelled()?;
         self.optimize()?;
+        self.check_error_count()?;
+        self.check_cancelled()?;
+        if self.options.print_stats {
+            self.function_stats = unsafe { llvm::function_stats(self.module) };
+        }
+        if self.options.verify_module {
+            self.verify_module()?;
+        }
+        self.save_temps("optimized")?;
         if let Some(path) = &self.options.dump_module {
             // dump IR before optimization
             let path = path.join("post-opt.ll");
-            let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| LinkerError::InvalidOutputPath(path))?;
             self.write_ir(&path)?;
         };
-        self.codegen()?;
         Ok(())
     }
 
     pub fn has_errors(&self) -> bool {
-        self.has_errors
+        self.error_count > 0
+    }
+
+    // abort when the embedder asked to cancel linking
+    fn check_cancelled(&self) -> Result<(), LinkerError> {
+        match &self.options.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(LinkerError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    // abort once LLVM reported `max_errors` errors
+    fn check_error_count(&self) -> Result<(), LinkerError> {
+        if self.too_many_errors() {
+            return Err(LinkerError::TooManyErrors(self.error_count));
+        }
+        Ok(())
+    }
+
+    // whether the error count reached `max_errors`
+    fn too_many_errors(&self) -> bool {
+        self.options
+            .max_errors
+            .is_some_and(|max_errors| self.error_count >= max_errors.max(1))
+    }
+
+    /// Returns the diagnostics reported by LLVM so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Returns the inputs and archive members whose bitcode was linked so far.
+    pub fn linked_inputs(&self) -> impl Iterator<Item = &Path> {
+        self.input_triples.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Returns the inputs and archive members which were skipped so far, with the reason.
+    pub fn skipped_inputs(&self) -> &[(PathBuf, &'static str)] {
+        &self.skipped_inputs
+    }
+
+    /// Returns the sorted sections of the programs left after optimization. Empty until the
+    /// module has been optimized.
+    pub fn program_sections(&self) -> &[String] {
+        &self.program_sections
+    }
+
+    /// Returns the block structure of the bitcode inputs and archive members read so far, when
+    /// [`LinkerOptions::dump_bitcode_blocks`] is set. The blocks are read without parsing the
+    /// bitcode with LLVM, which helps debugging inputs LLVM rejects.
+    pub fn bitcode_blocks(&self) -> &[(PathBuf, Result<Vec<BitcodeBlock>, BitcodeError>)] {
+        &self.bitcode_blocks
+    }
+
+    /// Returns the name, the number of basic blocks and the number of IR instructions of each
+    /// function left after optimization, when [`LinkerOptions::print_stats`] is set. See
+    /// [`format_function_stats`].
+    pub fn function_stats(&self) -> &[(String, usize, usize)] {
+        &self.function_stats
+    }
+
+    // Look up the libraries to link in the library search path.
+    fn library_inputs(&self) -> Result<Vec<LinkerInput>, LinkerError> {
+        self.options
+            .link_libs
+            .iter()
+            .map(|name| {
+                let path = find_library(name, &self.options.libs)
+                    .ok_or_else(|| LinkerError::LibraryNotFound(name.clone()))?;
+                debug!("found library {name} at {path:?}");
+                Ok(LinkerInput::new_from_file(path))
+            })
+            .collect()
     }
 
     fn link_modules(&mut self) -> Result<(), LinkerError> {
-        // buffer used to perform file type detection
-        let mut buf = [0u8; 8];
-        for path in self.options.inputs.clone() {
-            let mut file = File::open(&path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
-
-            // determine whether the input is bitcode, ELF with embedded bitcode, an archive file
-            // or an invalid file
-            file.read_exact(&mut buf)
-                .map_err(|e| LinkerError::IoError(path.clone(), e))?;
-            file.rewind()
-                .map_err(|e| LinkerError::IoError(path.clone(), e))?;
-            let in_type = detect_input_type(&buf)
-                .ok_or_else(|| LinkerError::InvalidInputType(path.clone()))?;
-
-            match in_type {
-                InputType::Archive => {
-                    info!("linking archive {:?}", path);
-
-                    // Extract the archive and call link_reader() for each item.
-                    let mut archive = Archive::new(file);
-                    while let Some(Ok(item)) = archive.next_entry() {
-                        let name =
-                            PathBuf::from(str::from_utf8(item.header().identifier()).unwrap());
-                        info!("linking archive item {:?}", name);
-
-                        match self.link_reader(&name, item, None) {
-                            Ok(_) => continue,
-                            Err(LinkerError::InvalidInputType(_)) => {
-                                info!("ignoring archive item {:?}: invalid type", name);
-                                continue;
-                            }
-                            Err(LinkerError::MissingBitcodeSection(_)) => {
-                                warn!("ignoring archive item {:?}: no embedded bitcode", name);
-                                continue;
-                            }
-                            Err(_) => return Err(LinkerError::LinkArchiveModuleError(path, name)),
-                        };
+        let libraries = self.library_inputs()?;
+        let inputs = std::mem::take(&mut self.options.inputs);
+        let result = inputs
+            .iter()
+            .chain(&libraries)
+            .try_for_each(|input| match input {
+                LinkerInput::File { path } => {
+                    let file =
+                        File::open(path).map_err(|e| LinkerError::IoError(path.clone(), e))?;
+                    // Map the file instead of reading it, so that large archives don't need to be
+                    // copied into memory in full.
+                    let data = unsafe { Mmap::map(&file) }
+                        .map_err(|e| LinkerError::IoError(path.clone(), e))?;
+                    // Files which were skipped are only reported in `skipped_inputs`. The members
+                    // of thin archives come after the archive.
+                    let index = self.dependencies.len();
+                    if self.link_input(path.clone(), &data)? {
+                        self.dependencies.insert(index, path.clone());
                     }
+                    self.check_error_count()
                 }
-                ty => {
-                    info!("linking file {:?} type {}", path, ty);
-                    match self.link_reader(&path, file, Some(ty)) {
-                        Ok(_) => {}
-                        Err(LinkerError::InvalidInputType(_)) => {
-                            info!("ignoring file {:?}: invalid type", path);
-                            continue;
-                        }
-                        Err(LinkerError::MissingBitcodeSection(_)) => {
-                            warn!("ignoring file {:?}: no embedded bitcode", path);
-                        }
-                        err => return err,
-                    }
+                LinkerInput::Buffer { name, bytes } => {
+                    let _: bool = self.link_input(PathBuf::from(name), bytes)?;
+                    self.check_error_count()
                 }
-            }
-        }
+            });
+        self.options.inputs = inputs;
+        result
+    }
+
+    // link a single input, which can be a file or an in-memory buffer, returns whether anything
+    // was linked
+    fn link_input(&mut self, path: PathBuf, data: &[u8]) -> Result<bool, LinkerError> {
+        // determine whether the input is bitcode, ELF with embedded bitcode, an archive file
+        // or an invalid file
+        let in_type =
+            detect_input_type(data).ok_or_else(|| LinkerError::InvalidInputType(path.clone()))?;
+
+        let linked = match in_type {
+            InputType::Archive => {
+                info!("linking archive {:?}", path);
+
+                // The data of the items is borrowed from the archive. When they are linked on
+                // multiple threads, all the items are gathered first, otherwise each item is
+                // linked as soon as it's read.
+                let parallel = self.options.jobs.is_some_and(|jobs| jobs > 1);
+                let mut items = Vec::new();
+                let mut linked = Vec::new();
+                for_each_archive_item(&path, data, |name, data| {
+                    if self.skip_archive_metadata(&name) {
+                        return Ok(());
+                    }
+                    if parallel {
+                        items.push((name, data));
+                    } else if self.link_archive_item(&path, &name, data)? {
+                        linked.push(name);
+                    }
+                    Ok(())
+                })?;
+                if parallel {
+                    linked = self.link_archive_items(path.clone(), &items)?;
+                }
+                debug!("linked {} members of archive {:?}", linked.len(), path);
+                // The members only exist inside of the archive, which is the dependency.
+                let any_linked = !linked.is_empty();
+                self.archive_members
+                    .extend(linked.into_iter().map(|member| (path.clone(), member)));
+                any_linked
+            }
+            InputType::ThinArchive => {
+                info!("linking thin archive {:?}", path);
+
+                let members = thin_archive_members(data)
+                    .map_err(|e| LinkerError::InvalidThinArchive(path.clone(), e))?;
+
+                // Thin archives only store the paths of their members, relative to the
+                // directory of the archive.
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let parallel = self.options.jobs.is_some_and(|jobs| jobs > 1);
+                let mut maps = Vec::new();
+                let mut linked = Vec::new();
+                for member in members {
+                    if self.skip_archive_metadata(&member) {
+                        continue;
+                    }
+                    let member = dir.join(member);
+                    let data = map_thin_archive_member(&path, &member)?;
+                    if parallel {
+                        maps.push((member, data));
+                    } else if self.link_archive_item(&path, &member, &data)? {
+                        linked.push(member);
+                    }
+                }
+                if parallel {
+                    let items = maps
+                        .iter()
+                        .map(|(member, data)| (member.clone(), &data[..]))
+                        .collect::<Vec<_>>();
+                    linked = self.link_archive_items(path, &items)?;
+                }
+                let any_linked = !linked.is_empty();
+                self.dependencies.extend(linked);
+                any_linked
+            }
+            InputType::LlvmIr => {
+                info!("linking LLVM IR {:?}", path);
+                self.link_ir(&path, data)?;
+                true
+            }
+            ty => {
+                info!("linking file {:?} type {}", path, ty);
+                let section = self.options.bitcode_section.as_deref();
+                let bitcode =
+                    unsafe { extract_bitcode(self.context, &path, data, Some(ty), section) };
+                match bitcode.and_then(|bitcode| self.link_bitcode(&path, &bitcode)) {
+                    Ok(()) => true,
+                    Err(LinkerError::InvalidInputType(_)) => {
+                        info!("ignoring file {:?}: invalid type", path);
+                        self.skipped_inputs.push((path, "invalid type"));
+                        false
+                    }
+                    Err(LinkerError::MissingBitcodeSection(..)) => {
+                        warn!("ignoring file {:?}: no embedded bitcode", path);
+                        self.skipped_inputs.push((path, "no embedded bitcode"));
+                        false
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        Ok(linked)
+    }
+
+    // Skip the rlib metadata and the BSD symbol tables, which never contain bitcode.
+    fn skip_archive_metadata(&mut self, name: &Path) -> bool {
+        if !is_archive_metadata(name) {
+            return false;
+        }
+        info!("ignoring archive item {:?}: metadata", name);
+        self.skipped_inputs.push((name.to_owned(), "metadata"));
+        true
+    }
+
+    // link the items of an archive file on multiple threads, returns the names of the items which
+    // contained bitcode
+    fn link_archive_items(
+        &mut self,
+        path: PathBuf,
+        items: &[(PathBuf, &[u8])],
+    ) -> Result<Vec<PathBuf>, LinkerError> {
+        let jobs = self.options.jobs.unwrap_or(1).min(items.len());
+        if jobs <= 1 {
+            return self.link_archive_chunk(&path, items);
+        }
+
+        // LLVM can only link modules of the same context, and a context can't be shared across
+        // threads. The items are split in contiguous chunks, each worker parses and links its
+        // chunk into a module of its own context and hands it back as bitcode. The first chunk
+        // is linked in place, meanwhile. Linking the chunks in order keeps the output the same as
+        // when linking the items one by one.
+        let chunks = items.chunks(items.len().div_ceil(jobs)).collect::<Vec<_>>();
+        debug!(
+            "linking {} archive items in {} chunks",
+            items.len(),
+            chunks.len()
+        );
+        let (first, rest) = chunks.split_first().unwrap();
+        let section = self.options.bitcode_section.clone();
+        let dump_blocks = self.options.dump_bitcode_blocks;
+        let (first, workers) = thread::scope(|s| {
+            let workers = rest
+                .iter()
+                .map(|chunk| {
+                    let section = section.as_deref();
+                    s.spawn(move || unsafe { link_archive_chunk(chunk, section, dump_blocks) })
+                })
+                .collect::<Vec<_>>();
+            let first = self.link_archive_chunk(&path, first);
+            let workers = workers
+                .into_iter()
+                .map(|worker| worker.join())
+                .collect::<Vec<_>>();
+            (first, workers)
+        });
+
+        let mut linked = first?;
+        for worker in workers {
+            let chunk = worker.map_err(|_| LinkerError::ArchiveWorkerPanicked(path.clone()))?;
+            for (severity, message) in chunk.diagnostics.0 {
+                llvm::LLVMDiagnosticHandler::handle_diagnostic(self, severity, &message);
+            }
+            let mut chunk_linked = Vec::new();
+            for item in chunk.items {
+                if let Some(blocks) = item.blocks {
+                    self.bitcode_blocks.push((item.name.clone(), blocks));
+                }
+                let result = item.target.map(|(triple, data_layout)| {
+                    self.check_data_layout(&item.name, data_layout);
+                    self.input_triples.push((item.name.clone(), triple));
+                });
+                if self.archive_item_linked(&path, &item.name, result)? {
+                    chunk_linked.push(item.name);
+                }
+            }
+            if chunk_linked.is_empty() {
+                continue;
+            }
+            let _: (String, String) =
+                unsafe { llvm::link_bitcode_buffer(self.context, self.module, &chunk.bitcode) }
+                    .ok_or_else(|| LinkerError::LinkModuleError(path.clone()))?;
+            linked.extend(chunk_linked);
+        }
+
+        Ok(linked)
+    }
+
+    // link the given archive items one by one, in the context of the linker
+    fn link_archive_chunk(
+        &mut self,
+        path: &Path,
+        items: &[(PathBuf, &[u8])],
+    ) -> Result<Vec<PathBuf>, LinkerError> {
+        let mut linked = Vec::new();
+        for (name, data) in items {
+            if self.link_archive_item(path, name, data)? {
+                linked.push(name.to_owned());
+            }
+        }
+
+        Ok(linked)
+    }
+
+    // link a single archive item in the context of the linker, returns whether it was linked
+    fn link_archive_item(
+        &mut self,
+        path: &Path,
+        name: &Path,
+        data: &[u8],
+    ) -> Result<bool, LinkerError> {
+        info!("linking archive item {:?}", name);
+
+        let section = self.options.bitcode_section.as_deref();
+        let bitcode = unsafe { extract_bitcode(self.context, name, data, None, section) };
+        let result = bitcode.and_then(|bitcode| self.link_bitcode(name, &bitcode));
+        self.archive_item_linked(path, name, result)
+    }
+
+    // Handles the result of linking an archive item. Returns whether the item was linked, or
+    // whether it was skipped because it doesn't contain bitcode.
+    fn archive_item_linked(
+        &mut self,
+        path: &Path,
+        name: &Path,
+        result: Result<(), LinkerError>,
+    ) -> Result<bool, LinkerError> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(LinkerError::InvalidInputType(_)) => {
+                info!("ignoring archive item {:?}: invalid type", name);
+                self.skipped_inputs.push((name.to_owned(), "invalid type"));
+                Ok(false)
+            }
+            Err(LinkerError::MissingBitcodeSection(..)) => {
+                warn!("ignoring archive item {:?}: no embedded bitcode", name);
+                self.skipped_inputs
+                    .push((name.to_owned(), "no embedded bitcode"));
+                Ok(false)
+            }
+            Err(_) => Err(LinkerError::LinkArchiveModuleError(
+                path.to_owned(),
+                name.to_owned(),
+            )),
+        }
+    }
+
+    fn link_bitcode(&mut self, path: &Path, bitcode: &[u8]) -> Result<(), LinkerError> {
+        if self.options.dump_bitcode_blocks {
+            self.bitcode_blocks
+                .push((path.to_owned(), llvm::bitcode_blocks(bitcode)));
+        }
+        let (triple, data_layout) =
+            unsafe { llvm::link_bitcode_buffer(self.context, self.module, bitcode) }.ok_or_else(
+                || {
+                    log_bitcode_producer(path, bitcode);
+                    LinkerError::LinkModuleError(path.to_owned())
+                },
+            )?;
+        self.check_data_layout(path, data_layout);
+        self.input_triples.push((path.to_owned(), triple));
+
+        Ok(())
+    }
+
+    fn link_ir(&mut self, path: &Path, ir: &[u8]) -> Result<(), LinkerError> {
+        let ir = ir.strip_prefix(UTF8_BOM).unwrap_or(ir);
+        let (triple, data_layout) = unsafe { llvm::link_ir_buffer(self.context, self.module, ir) }
+            .map_err(|e| LinkerError::ParseIRError(path.to_owned(), e))?
+            .ok_or_else(|| LinkerError::LinkModuleError(path.to_owned()))?;
+        self.check_data_layout(path, data_layout);
+        self.input_triples.push((path.to_owned(), triple));
+
+        Ok(())
+    }
+
+    // Warn about the inputs whose data layout differs from the one of the first input, unless the
+    // data layout of the output is set explicitly.
+    fn check_data_layout(&mut self, path: &Path, data_layout: String) {
+        if self.options.data_layout.is_some() || data_layout.is_empty() {
+            return;
+        }
+        match &self.input_data_layout {
+            None => self.input_data_layout = Some((path.to_owned(), data_layout)),
+            Some((first, expected)) if *expected != data_layout => warn!(
+                "data layout {data_layout:?} of {} differs from {expected:?} of {}, consider setting --data-layout",
+                path.display(),
+                first.display()
+            ),
+            Some(_) => {}
+        }
+    }
+
+    // write a make rule with the output as target and the linked inputs as prerequisites, followed
+    // by the linked members of archives as comments
+    fn write_dependency_file(&self, path: &Path) -> Result<(), LinkerError> {
+        fn escape(path: &Path) -> String {
+            path.to_string_lossy()
+                .replace('$', "$$")
+                .replace(' ', "\\ ")
+                .replace('#', "\\#")
+        }
+
+        let mut contents = format!("{}:", escape(&self.options.output));
+        for dependency in &self.dependencies {
+            contents.push_str(" \\\n  ");
+            contents.push_str(&escape(dependency));
+        }
+        contents.push('\n');
+        // Make can't depend on archive members without them being targets of their own, so they
+        // don't go in the rule.
+        for (archive, member) in &self.archive_members {
+            contents.push_str(&format!("# {}({})\n", archive.display(), member.display()));
+        }
+
+        info!("writing dependency file to {:?}", path);
+        fs::write(path, contents).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
+    fn create_target_machine(&mut self) -> Result<(), LinkerError> {
+        let Self {
+            options:
+                LinkerOptions {
+                    target,
+                    cpu,
+                    cpu_features,
+                    optimize,
+                    reloc_mode,
+                    code_model,
+                    ..
+                },
+            module,
+            target_machine,
+            target_machines,
+            input_triples,
+            ..
+        } = self;
+        // Here's how the output target is selected:
+        //
+        // 1) rustc with builtin BPF support: cargo build --target=bpf[el|eb]-unknown-none
+        //      the input modules are already configured for the correct output target
+        //
+        // 2) rustc with no BPF support: cargo rustc -- -C linker-flavor=bpf-linker -C linker=bpf-linker -C link-arg=--target=bpf[el|eb]
+        //      the input modules are configured for the *host* target, and the output target
+        //      is configured with the `--target` linker argument
+        //
+        // 3) rustc with no BPF support: cargo rustc -- -C linker-flavor=bpf-linker -C linker=bpf-linker
+        //      the input modules are configured for the *host* target, the output target isn't
+        //      set via `--target`, so default to `bpf` (bpfel or bpfeb depending on the host
+        //      endianness)
+        let (triple, target) = match target {
+            // case 1
+            Some(triple) => {
+                let c_triple = CString::new(triple.as_str())
+                    .map_err(|_| LinkerError::InvalidTarget(triple.clone()))?;
+                (triple.as_str(), unsafe {
+                    llvm::target_from_triple(&c_triple)
+                })
+            }
+            None => {
+                let c_triple = unsafe { LLVMGetTarget(*module) };
+                let triple = unsafe { CStr::from_ptr(c_triple) }.to_str().unwrap();
+                if triple.starts_with("bpf") {
+                    // case 2
+                    (triple, unsafe { llvm::target_from_module(*module) })
+                } else {
+                    // case 3.
+                    info!("detected non-bpf input target {} and no explicit output --target specified, selecting `bpf'", triple);
+                    let triple = "bpf";
+                    let c_triple = CString::new(triple).unwrap();
+                    (triple, unsafe { llvm::target_from_triple(&c_triple) })
+                }
+            }
+        };
+        let target = target.map_err(|_msg| LinkerError::InvalidTarget(triple.to_owned()))?;
+
+        // Inputs compiled for the BPF target of the other endianness would silently produce code
+        // with the wrong byte order. Inputs for `bpf` and for the host (case 3) are retargeted.
+        let expected = match triple.split('-').next() {
+            Some("bpf") if cfg!(target_endian = "big") => Some(Endianness::Big),
+            Some("bpf") => Some(Endianness::Little),
+            _ => bpf_endianness(triple),
+        };
+        if let Some(expected) = expected {
+            if let Some((input, _)) = input_triples
+                .iter()
+                .find(|(_, input)| bpf_endianness(input).is_some_and(|e| e != expected))
+            {
+                return Err(LinkerError::EndiannessMismatch {
+                    input: input.clone(),
+                    expected: triple.to_owned(),
+                });
+            }
+        }
+
+        // Modules built for BPF carry the features they were compiled with. Use them when no
+        // features were requested, since codegen would otherwise disagree with the IR.
+        let module_triple = unsafe { CStr::from_ptr(LLVMGetTarget(*module)) };
+        let module_features = module_triple
+            .to_bytes()
+            .starts_with(b"bpf")
+            .then(|| unsafe { llvm::module_target_features(*module) })
+            .flatten()
+            .and_then(|features| match features.parse::<BpfCpuFeatures>() {
+                Ok(features) => Some(features),
+                Err(e) => {
+                    warn!("ignoring the target features of the input modules: {e}");
+                    None
+                }
+            });
+        let cpu_features = match module_features {
+            Some(module_features) if *cpu_features == BpfCpuFeatures::default() => {
+                info!("using the target features of the input modules: {module_features}");
+                module_features
+            }
+            Some(module_features) => {
+                if !module_features.same_features(cpu_features) {
+                    warn!(
+                        "target features {cpu_features} differ from the features {module_features} the input modules were compiled with"
+                    );
+                }
+                cpu_features.clone()
+            }
+            None => cpu_features.clone(),
+        };
+
+        let key = (
+            triple.to_owned(),
+            *cpu,
+            cpu_features.to_string(),
+            *optimize,
+            *reloc_mode,
+            *code_model,
+        );
+        if let Some(cached) = target_machines.get(&key) {
+            debug!("reusing target machine for triple: {triple}");
+            *target_machine = *cached;
+            return Ok(());
+        }
+
+        debug!(
+            "creating target machine: triple: {} cpu: {} features: {} opt level: {:?}",
+            triple, cpu, cpu_features, optimize,
+        );
+
+        *target_machine = unsafe {
+            llvm::create_target_machine(
+                target,
+                triple,
+                cpu.to_str(),
+                &key.2,
+                *optimize,
+                *reloc_mode,
+                *code_model,
+            )
+        }
+        .ok_or_else(|| LinkerError::InvalidTarget(triple.to_owned()))?;
+        let _: Option<LLVMTargetMachineRef> = target_machines.insert(key, *target_machine);
+
+        Ok(())
+    }
+
+    fn optimize(&mut self) -> Result<(), LinkerError> {
+        self.check_exports()?;
+
+        let mut exported = self.options.export_symbols.clone();
+        exported.extend(
+            self.options
+                .memory_builtins
+                .iter()
+                .map(|builtin| builtin.to_str().into()),
+        );
+        if !self.options.export_patterns.is_empty() {
+            let defined = unsafe { llvm::defined_symbols(self.module) };
+            exported.extend(
+                defined
+                    .into_iter()
+                    .filter(|name| {
+                        self.options
+                            .export_patterns
+                            .iter()
+                            .any(|pattern| pattern.is_match(name))
+                    })
+                    .map(Into::into),
+            );
+        }
+        let export_symbols = if self.options.internalize_only.is_empty() {
+            llvm::ExportSymbols::Only(&exported)
+        } else {
+            llvm::ExportSymbols::AllExcept(&self.options.internalize_only)
+        };
+        debug!(
+            "linking exporting symbols {:?}, opt level {:?}",
+            export_symbols, self.options.optimize
+        );
+        let symbols_before = self
+            .options
+            .report_internalized
+            .is_some()
+            .then(|| unsafe { llvm::symbol_linkages(self.module) });
+        // run optimizations. Will optionally remove noinline attributes, intern all non exported
+        // programs and maps and remove dead code.
+
+        if self.options.preserve_debug_info {
+            debug!("preserving the debug info of the inputs");
+        } else if self.options.btf {
+            // if we want to emit BTF, we need to sanitize the debug information
+            match llvm::DISanitizer::new(
+                self.context,
+                self.module,
+                self.options.downgrade_enum64,
+                self.options
+                    .btf_type_name_max_len
+                    .unwrap_or(llvm::MAX_KSYM_NAME_LEN),
+                self.options.keep_variant_enums,
+                self.options.dedup_btf_types,
+            ) {
+                Ok(sanitizer) => sanitizer.run(&export_symbols)?,
+                Err(BtfSanitizeError::NoDebugInfo) => warn!(
+                    "BTF was requested but the inputs have no debug info, build them with -C debuginfo=2"
+                ),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            // if we don't need BTF emission, we can strip DI
+            let ok = unsafe { llvm::strip_debug_info(self.module) };
+            debug!("Stripping DI, changed={}", ok);
+        }
+
+        unsafe {
+            llvm::optimize(
+                self.target_machine,
+                self.module,
+                self.options.optimize,
+                self.options.passes.as_deref(),
+                self.options.ignore_inline_never,
+                &export_symbols,
+            )
+        }
+        .map_err(LinkerError::OptimizeError)?;
+
+        if self.options.gc_functions {
+            let removed = unsafe { llvm::gc_functions(self.module, &export_symbols) };
+            info!("removed {removed} unreachable functions");
+        }
+
+        if let (Some(path), Some(before)) = (&self.options.report_internalized, symbols_before) {
+            let after = unsafe { llvm::symbol_linkages(self.module) };
+            write_internalized_report(path, &before, &after)?;
+        }
+
+        self.program_sections = unsafe { llvm::program_sections(self.module) };
+
+        if let Some(path) = &self.options.link_map {
+            let symbols = unsafe { llvm::link_map(self.module, self.target_machine) };
+            write_link_map(path, self.options.link_map_format, &symbols)?;
+        }
+
+        Ok(())
+    }
+
+    fn codegen(&mut self, output_type: OutputType) -> Result<Vec<u8>, LinkerError> {
+        self.check_cancelled()?;
+        match output_type {
+            OutputType::Bitcode => Ok(self.write_bitcode()),
+            OutputType::LlvmAssembly => Ok(self.write_ir_to_buffer()),
+            OutputType::Assembly => self.emit(LLVMCodeGenFileType::LLVMAssemblyFile),
+            OutputType::Object => self.emit(LLVMCodeGenFileType::LLVMObjectFile),
+        }
+    }
+
+    fn write_bitcode(&mut self) -> Vec<u8> {
+        info!("writing bitcode");
+
+        unsafe { llvm::write_bitcode_to_vec(self.module) }
+    }
+
+    // Catch the passes which leave the module in an invalid state before codegen trips over it.
+    fn verify_module(&self) -> Result<(), LinkerError> {
+        info!("verifying module");
+
+        unsafe { llvm::verify_module(self.module) }.map_err(LinkerError::ModuleVerificationFailed)
+    }
+
+    // Write the module to `<stage>.bc` and `<stage>.ll` in the --save-temps directory.
+    fn save_temps(&mut self, stage: &str) -> Result<(), LinkerError> {
+        let Some(dir) = self.options.save_temps.clone() else {
+            return Ok(());
+        };
+        let path = dir.join(format!("{stage}.bc"));
+        let bitcode = self.write_bitcode();
+        info!("writing bitcode to {:?}", path);
+        fs::write(&path, bitcode).map_err(|e| LinkerError::IoError(path, e))?;
+        let path = dir.join(format!("{stage}.ll"));
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| LinkerError::InvalidOutputPath(path))?;
+        self.write_ir(&path)
+    }
+
+    fn write_ir(&mut self, output: &CStr) -> Result<(), LinkerError> {
+        info!("writing IR to {:?}", output);
+
+        unsafe { llvm::write_ir(self.module, output) }.map_err(LinkerError::WriteIRError)
+    }
+
+    // write the .BTF section of the given object file
+    fn write_btf(&self, path: &Path, object: &[u8]) -> Result<(), LinkerError> {
+        let btf = unsafe { llvm::find_section(self.context, object, ".BTF") }
+            .map_err(LinkerError::ReadObjectError)?
+            .ok_or(LinkerError::MissingBtfSection)?;
+
+        info!("writing BTF to {:?}", path);
+        fs::write(path, btf).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+    }
+
+    fn write_ir_to_buffer(&mut self) -> Vec<u8> {
+        info!("writing IR");
+
+        unsafe { llvm::write_ir_to_vec(self.module) }
+    }
+
+    fn emit(&mut self, output_type: LLVMCodeGenFileType) -> Result<Vec<u8>, LinkerError> {
+        info!("emitting {:?}", output_type);
+
+        unsafe { llvm::codegen_to_vec(self.target_machine, self.module, output_type) }
+            .map_err(LinkerError::EmitCodeError)
+    }
+
+    // Generate the output from a copy of the module when `copy` is set, leaving the module
+    // untouched for generating further outputs.
+    fn codegen_module_copy(
+        &mut self,
+        output_type: OutputType,
+        copy: bool,
+    ) -> Result<Vec<u8>, LinkerError> {
+        if !copy {
+            return self.codegen(output_type);
+        }
+
+        let module = self.module;
+        self.module = unsafe { LLVMCloneModule(module) };
+        let output = self.codegen(output_type);
+        unsafe { LLVMDisposeModule(self.module) };
+        self.module = module;
+        output
+    }
+
+    // Report the symbols to export which aren't defined by the linked module, which usually means
+    // there is a typo in their name.
+    fn check_exports(&self) -> Result<(), LinkerError> {
+        if self.options.export_symbols.is_empty() {
+            return Ok(());
+        }
+        let defined = unsafe { llvm::defined_symbols(self.module) };
+        let mut missing = self
+            .options
+            .export_symbols
+            .iter()
+            .filter(|symbol| !defined.contains(symbol.as_ref()))
+            .map(|symbol| symbol.to_string())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        missing.sort_unstable();
+        if self.options.error_on_missing_export {
+            return Err(LinkerError::MissingExports(missing));
+        }
+        for symbol in missing {
+            warn!("exported symbol {symbol} not found");
+        }
+
+        Ok(())
+    }
+
+    fn llvm_init(&mut self) -> Result<(), LinkerError> {
+        // Validate the output path before initializing LLVM.
+        let name = module_name(&self.options.output)?.into_owned();
+
+        if self.context.is_null() {
+            self.create_context();
+        }
+        unsafe {
+            // Set the handler on every link, the linker might have moved since the previous one.
+            LLVMContextSetDiagnosticHandler(
+                self.context,
+                Some(llvm::diagnostic_handler::<Self>),
+                self as *mut _ as _,
+            );
+            self.module = llvm::create_module(&name, self.context)
+                .map_err(|_| LinkerError::InvalidModuleName(name))?;
+        }
+
+        Ok(())
+    }
+
+    // Initialize LLVM and create the context, once per linker. LLVM itself is only initialized
+    // by the first linker of the process.
+    fn create_context(&mut self) {
+        let mut args = Vec::<Cow<str>>::new();
+        args.push("bpf-linker".into());
+        // Disable cold call site detection. Many accessors in aya-ebpf return Result<T, E>
+        // where the layout is larger than 64 bits, but the LLVM BPF target only supports
+        // up to 64 bits return values. Since the accessors are tiny in terms of code, we
+        // avoid the issue by annotating them with #[inline(always)]. If they are classified
+        // as cold though - and they often are starting from LLVM17 - #[inline(always)]
+        // is ignored and the BPF target fails codegen.
+        args.push("--cold-callsite-rel-freq=0".into());
+        if self.options.unroll_loops {
+            // setting cmdline arguments is the only way to customize the unroll pass with the
+            // C API.
+            args.extend([
+                "--unroll-runtime".into(),
+                "--unroll-runtime-multi-exit".into(),
+                format!(
+                    "--unroll-max-upperbound={}",
+                    self.options.unroll_max_upperbound.unwrap_or(u32::MAX)
+                )
+                .into(),
+                format!(
+                    "--unroll-threshold={}",
+                    self.options.unroll_threshold.unwrap_or(u32::MAX)
+                )
+                .into(),
+            ]);
+        }
+        if !self.options.disable_expand_memcpy_in_order {
+            args.push("--bpf-expand-memcpy-in-order".into());
+        }
+        if self.options.time_passes {
+            // The report is printed when the pass managers are destroyed, that is once
+            // optimization and code generation are done.
+            args.push("--time-passes".into());
+        }
+        if self.options.print_stats {
+            args.push("--stats".into());
+        }
+        if self.options.time_passes || self.options.print_stats {
+            if let Some(path) = &self.options.time_passes_file {
+                args.push(format!("--info-output-file={}", path.display()).into());
+            }
+        }
+        args.extend(self.options.llvm_args.iter().map(Into::into));
+        info!("LLVM command line: {:?}", args);
+        unsafe {
+            llvm::init(&args, "BPF linker");
+
+            self.context = LLVMContextCreate();
+            LLVMInstallFatalErrorHandler(Some(llvm::fatal_error));
+            LLVMEnablePrettyStackTrace();
+        }
+    }
+}
+
+// Write the symbols that were external before optimizing and are internal afterwards, followed by
+// the symbols that were removed altogether.
+fn write_internalized_report(
+    path: &Path,
+    before: &BTreeMap<String, bool>,
+    after: &BTreeMap<String, bool>,
+) -> Result<(), LinkerError> {
+    let mut contents = String::from("internalized:\n");
+    for (name, _) in before
+        .iter()
+        .filter(|&(name, &internal)| !internal && after.get(name) == Some(&true))
+    {
+        contents.push_str("  ");
+        contents.push_str(name);
+        contents.push('\n');
+    }
+    contents.push_str("removed:\n");
+    for name in before
+        .keys()
+        .filter(|name| !after.contains_key(name.as_str()))
+    {
+        contents.push_str("  ");
+        contents.push_str(name);
+        contents.push('\n');
+    }
+
+    info!("writing internalized symbols report to {:?}", path);
+    fs::write(path, contents).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+}
+
+fn write_link_map(
+    path: &Path,
+    format: LinkMapFormat,
+    symbols: &[llvm::LinkMapSymbol],
+) -> Result<(), LinkerError> {
+    let mut symbols = symbols.iter().collect::<Vec<_>>();
+    symbols.sort_by(|a, b| (&a.section, &a.name).cmp(&(&b.section, &b.name)));
+
+    let mut contents = String::new();
+    match format {
+        LinkMapFormat::Text => {
+            for llvm::LinkMapSymbol {
+                name,
+                function,
+                section,
+                linkage,
+                size,
+            } in symbols
+            {
+                contents.push_str(&format!(
+                    "{:<24} {:<8} {:<12} {size:>8} {name}\n",
+                    section.as_deref().unwrap_or("-"),
+                    if *function { "function" } else { "global" },
+                    linkage,
+                ));
+            }
+        }
+        LinkMapFormat::Json => {
+            #[derive(Serialize)]
+            struct JsonSymbol<'a> {
+                name: &'a str,
+                kind: &'static str,
+                section: Option<&'a str>,
+                linkage: &'static str,
+                size: u64,
+            }
+
+            let symbols = symbols
+                .into_iter()
+                .map(
+                    |llvm::LinkMapSymbol {
+                         name,
+                         function,
+                         section,
+                         linkage,
+                         size,
+                     }| JsonSymbol {
+                        name,
+                        kind: if *function { "function" } else { "global" },
+                        section: section.as_deref(),
+                        linkage: *linkage,
+                        size: *size,
+                    },
+                )
+                .collect::<Vec<_>>();
+            contents = serde_json::to_string(&symbols)
+                .map_err(|e| LinkerError::IoError(path.to_owned(), e.into()))?;
+            contents.push('\n');
+        }
+    }
+
+    info!("writing link map to {:?}", path);
+    fs::write(path, contents).map_err(|e| LinkerError::IoError(path.to_owned(), e))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endianness {
+    Little,
+    Big,
+}
+
+// Returns the endianness of a `bpfel` or `bpfeb` triple. Other triples, including `bpf`, don't
+// imply any BPF endianness.
+fn bpf_endianness(triple: &str) -> Option<Endianness> {
+    match triple.split('-').next() {
+        Some("bpfel") => Some(Endianness::Little),
+        Some("bpfeb") => Some(Endianness::Big),
+        _ => None,
+    }
+}
+
+// Name the module after the stem of the output file, e.g. `foo` for `target/foo.o`.
+fn module_name(output: &Path) -> Result<Cow<'_, str>, LinkerError> {
+    // The module name is only informative, so don't reject output paths which aren't UTF-8.
+    let name = output
+        .file_stem()
+        .map(OsStr::to_string_lossy)
+        .ok_or_else(|| LinkerError::InvalidOutputPath(output.to_owned()))?;
+    if name.contains('\0') {
+        return Err(LinkerError::InvalidModuleName(name.into_owned()));
+    }
+    Ok(name)
+}
+
+/// Link the given inputs and return the generated code.
+///
+/// This is a convenience wrapper around [`Linker::link_to_buffer`]. Diagnostics
+/// with error severity reported by LLVM are turned into
+/// [`LinkerError::LLVMError`].
+pub fn link_to_vec(options: LinkerOptions) -> Result<Vec<u8>, LinkerError> {
+    let mut linker = Linker::new(options);
+    let output = linker.link_to_buffer()?;
+    if linker.has_errors() {
+        return Err(LinkerError::LLVMError);
+    }
+    Ok(output)
+}
+
+impl llvm::LLVMDiagnosticHandler for Linker {
+    fn handle_diagnostic(&mut self, severity: llvm_sys::LLVMDiagnosticSeverity, message: &str) {
+        // TODO(https://reviews.llvm.org/D155894): Remove this when LLVM no longer emits these
+        // errors.
+        //
+        // See https://github.com/rust-lang/compiler-builtins/blob/a61823f/src/mem/mod.rs#L22-L68.
+        const MATCHERS: &[&str] = &[
+            "A call to built-in function 'memcpy' is not supported.\n",
+            "A call to built-in function 'memmove' is not supported.\n",
+            "A call to built-in function 'memset' is not supported.\n",
+            "A call to built-in function 'memcmp' is not supported.\n",
+            "A call to built-in function 'bcmp' is not supported.\n",
+            "A call to built-in function 'strlen' is not supported.\n",
+        ];
+
+        if self
+            .options
+            .suppress_diagnostics
+            .iter()
+            .any(|matcher| message.contains(matcher.as_str()))
+        {
+            if self.options.list_suppressed {
+                info!("llvm: suppressed: {}", message);
+            }
+            return;
+        }
+
+        let suppressed = MATCHERS.iter().any(|matcher| message.ends_with(matcher));
+        let severity = match severity {
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning
+                if self.options.deny_warnings && !suppressed =>
+            {
+                llvm_sys::LLVMDiagnosticSeverity::LLVMDSError
+            }
+            severity => severity,
+        };
+
+        match severity {
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSError => {
+                if suppressed {
+                    if self.options.list_suppressed {
+                        info!("llvm: suppressed: {}", message);
+                    }
+                    return;
+                }
+                // The current phase is aborted once it's done, don't flood the logs meanwhile.
+                // The diagnostic is still recorded below.
+                let log = !self.too_many_errors();
+                self.error_count += 1;
+                if log {
+                    error!("llvm: {}", message)
+                }
+            }
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => warn!("llvm: {}", message),
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => debug!("remark: {}", message),
+            llvm_sys::LLVMDiagnosticSeverity::LLVMDSNote => debug!("note: {}", message),
+        }
+
+        self.diagnostics.push(Diagnostic {
+            severity: severity.into(),
+            message: message.to_owned(),
+            location: SourceLocation::parse(message),
+        });
+    }
+}
+
+impl Drop for Linker {
+    fn drop(&mut self) {
+        unsafe {
+            // `target_machine` is one of the cached target machines.
+            for target_machine in self.target_machines.values() {
+                LLVMDisposeTargetMachine(*target_machine);
+            }
+            if !self.module.is_null() {
+                LLVMDisposeModule(self.module);
+            }
+            if !self.context.is_null() {
+                LLVMContextDispose(self.context);
+            }
+        }
+    }
+}
+
+// Extract the bitcode from `data`, which can be a bitcode file or an object file with embedded
+// bitcode. `in_type` is unknown when we're linking an item from an archive file.
+unsafe fn extract_bitcode<'a>(
+    context: LLVMContextRef,
+    path: &Path,
+    data: &'a [u8],
+    in_type: Option<InputType>,
+    section: Option<&str>,
+) -> Result<Cow<'a, [u8]>, LinkerError> {
+    let in_type = in_type
+        .or_else(|| detect_input_type(data))
+        .ok_or_else(|| LinkerError::InvalidInputType(path.to_owned()))?;
+
+    use InputType::*;
+    match in_type {
+        Bitcode => Ok(Cow::Borrowed(data)),
+        // archive files can contain mach-o files too, eg somecrate.rlib containing lib.rmeta which
+        // is mach-o on macos
+        Elf | MachO => {
+            // The section is called `.llvmbc` in ELF files and `__bitcode` (in the `__LLVM`
+            // segment) in Mach-O files.
+            let section = section.unwrap_or(match in_type {
+                MachO => "__bitcode",
+                _ => ".llvmbc",
+            });
+            match llvm::find_section(context, data, section) {
+                Ok(Some(bitcode)) => {
+                    decompress_section(path, data, section, bitcode).map(Cow::Owned)
+                }
+                // BPF objects defining symbols can't be dropped without breaking the references
+                // to them, unlike other objects without bitcode, e.g. rustc's `symbols.o`.
+                Ok(None) if is_bpf_object(data) => {
+                    match llvm::object_defines_symbols(context, data) {
+                        Ok(true) => Err(LinkerError::PrecompiledObject(path.to_owned())),
+                        Ok(false) => Err(LinkerError::MissingBitcodeSection(
+                            path.to_owned(),
+                            section.to_owned(),
+                        )),
+                        Err(e) => Err(LinkerError::EmbeddedBitcodeError(e)),
+                    }
+                }
+                Ok(None) => Err(LinkerError::MissingBitcodeSection(
+                    path.to_owned(),
+                    section.to_owned(),
+                )),
+                Err(e) => Err(LinkerError::EmbeddedBitcodeError(e)),
+            }
+        }
+        // archive items are expected to be bitcode or object files
+        LlvmIr => Err(LinkerError::InvalidInputType(path.to_owned())),
+        // this can't really happen
+        Archive | ThinArchive => panic!("nested archives not supported duh"),
+    }
+}
+
+fn detect_input_type(data: &[u8]) -> Option<InputType> {
+    if data.len() < 8 {
+        return None;
+    }
+
+    use InputType::*;
+    match &data[..4] {
+        b"\x42\x43\xC0\xDE" | b"\xDE\xC0\x17\x0b" => Some(Bitcode),
+        b"\x7FELF" => Some(Elf),
+        b"\xcf\xfa\xed\xfe" => Some(MachO),
+        _ => {
+            if &data[..8] == b"!<arch>\x0A" {
+                Some(Archive)
+            } else if &data[..8] == THIN_ARCHIVE_MAGIC {
+                Some(ThinArchive)
+            } else if is_llvm_ir(data) {
+                Some(LlvmIr)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Whether `data` is an ELF file for the BPF machine (EM_BPF).
+fn is_bpf_object(data: &[u8]) -> bool {
+    const EM_BPF: u16 = 247;
+    // `e_machine` follows `e_ident` and `e_type`, in the byte order given by `EI_DATA`.
+    let Some(machine) = data.get(18..20) else {
+        return false;
+    };
+    let machine = [machine[0], machine[1]];
+    let machine = match data[5] {
+        2 => u16::from_be_bytes(machine),
+        _ => u16::from_le_bytes(machine),
+    };
+    data.starts_with(b"\x7FELF") && machine == EM_BPF
+}
+
+// See `Elf32_Chdr` and `Elf64_Chdr` in elf(5).
+const ELFCOMPRESS_ZLIB: u32 = 1;
+const ELFCOMPRESS_ZSTD: u32 = 2;
+const SHF_COMPRESSED: u64 = 0x800;
+
+// Returns the flags of the section named `name`, read from the section header table of the given
+// ELF file.
+fn elf_section_flags(elf: &[u8], name: &str) -> Option<u64> {
+    let is_64 = match elf.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let big_endian = *elf.get(5)? == 2;
+    // Reads the integer of `len` bytes at `offset`, in the byte order of the file.
+    let read = |offset: usize, len: usize| {
+        let bytes = elf.get(offset..offset.checked_add(len)?)?;
+        let mut value = [0; 8];
+        Some(if big_endian {
+            value[8 - len..].copy_from_slice(bytes);
+            u64::from_be_bytes(value)
+        } else {
+            value[..len].copy_from_slice(bytes);
+            u64::from_le_bytes(value)
+        })
+    };
+    // Size of the addresses and offsets, offsets of e_shoff and of sh_offset.
+    let (word, e_shoff, sh_offset) = if is_64 {
+        (8, 0x28, 0x18)
+    } else {
+        (4, 0x20, 0x10)
+    };
+    // e_shentsize, e_shnum and e_shstrndx follow e_flags, e_ehsize, e_phentsize and e_phnum.
+    let e_shentsize = e_shoff + word + 10;
+    let shoff = usize::try_from(read(e_shoff, word)?).ok()?;
+    let shentsize = read(e_shentsize, 2)? as usize;
+    let shnum = read(e_shentsize + 2, 2)? as usize;
+    let shstrndx = read(e_shentsize + 4, 2)? as usize;
+    // Offset of the field at `offset` in the header of the section at `index`.
+    let field = |index: usize, offset: usize| {
+        shoff
+            .checked_add(index.checked_mul(shentsize)?)?
+            .checked_add(offset)
+    };
+
+    let strtab = usize::try_from(read(field(shstrndx, sh_offset)?, word)?).ok()?;
+    (0..shnum).find_map(|index| {
+        let sh_name = strtab.checked_add(read(field(index, 0)?, 4)? as usize)?;
+        if elf.get(sh_name..)?.split(|byte| *byte == 0).next()? != name.as_bytes() {
+            return None;
+        }
+        // sh_flags follows sh_name and sh_type.
+        read(field(index, 8)?, word)
+    })
+}
+
+// Decompress the contents of the section `name` of the given ELF file, if it's compressed
+// (SHF_COMPRESSED). Compressed sections start with a compression header.
+fn decompress_section(
+    path: &Path,
+    elf: &[u8],
+    name: &str,
+    section: Vec<u8>,
+) -> Result<Vec<u8>, LinkerError> {
+    if !elf.starts_with(b"\x7FELF")
+        || !elf_section_flags(elf, name).is_some_and(|flags| flags & SHF_COMPRESSED != 0)
+    {
+        return Ok(section);
+    }
+    let header_len = match elf.get(4) {
+        Some(1) => 12,
+        Some(2) => 24,
+        _ => return Ok(section),
+    };
+    let Some(ch_type) = section.get(..4) else {
+        return Ok(section);
+    };
+    let ch_type = ch_type.try_into().unwrap();
+    let ch_type = match elf.get(5) {
+        Some(2) => u32::from_be_bytes(ch_type),
+        _ => u32::from_le_bytes(ch_type),
+    };
+    let compressed = section.get(header_len..).unwrap_or_default();
+
+    let mut decompressed = Vec::new();
+    match ch_type {
+        ELFCOMPRESS_ZLIB => {
+            let _: usize = ZlibDecoder::new(compressed)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| LinkerError::EmbeddedBitcodeError(e.to_string()))?;
+        }
+        ELFCOMPRESS_ZSTD => {
+            decompressed = zstd::decode_all(compressed)
+                .map_err(|e| LinkerError::EmbeddedBitcodeError(e.to_string()))?;
+        }
+        ch_type => {
+            return Err(LinkerError::UnsupportedSectionCompression(
+                path.to_owned(),
+                ch_type,
+            ))
+        }
+    }
+
+    if detect_input_type(&decompressed) != Some(InputType::Bitcode) {
+        return Err(LinkerError::EmbeddedBitcodeError(format!(
+            "the compressed section {name} of {} doesn't contain bitcode",
+            path.display()
+        )));
+    }
+    Ok(decompressed)
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Textual IR has no magic, so look for the things llvm-dis and clang -S -emit-llvm put at the top
+// of a module: a `; ModuleID = ...` comment, the source file name, the data layout or a function.
+fn is_llvm_ir(data: &[u8]) -> bool {
+    const PREFIXES: &[&str] = &["source_filename =", "target datalayout", "define "];
+
+    let data = data.strip_prefix(UTF8_BOM).unwrap_or(data);
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    head.trim_start().starts_with(';')
+        || head.lines().any(|line| {
+            let line = line.trim_start();
+            PREFIXES.iter().any(|prefix| line.starts_with(prefix))
+        })
+}
+
+// An archive item linked by a worker thread, see `Linker::link_archive_items`.
+struct ChunkItem {
+    name: PathBuf,
+    // The target triple and the data layout of the item
+    target: Result<(String, String), LinkerError>,
+    blocks: Option<Result<Vec<BitcodeBlock>, BitcodeError>>,
+}
+
+struct LinkedChunk {
+    // The items up to the first one which failed to link
+    items: Vec<ChunkItem>,
+    diagnostics: DeferredDiagnostics,
+    // The module the items were linked into
+    bitcode: Vec<u8>,
+}
+
+// The diagnostics LLVM reports in the context of a worker thread, which are handled by the linker
+// once the thread is done.
+#[derive(Default)]
+struct DeferredDiagnostics(Vec<(llvm_sys::LLVMDiagnosticSeverity, String)>);
+
+impl llvm::LLVMDiagnosticHandler for DeferredDiagnostics {
+    fn handle_diagnostic(&mut self, severity: llvm_sys::LLVMDiagnosticSeverity, message: &str) {
+        self.0.push((severity, message.to_owned()))
+    }
+}
+
+// Link the given archive items into a module of a new context.
+unsafe fn link_archive_chunk(
+    items: &[(PathBuf, &[u8])],
+    section: Option<&str>,
+    dump_blocks: bool,
+) -> LinkedChunk {
+    let context = LLVMContextCreate();
+    // Without a handler, LLVM exits the process on the first error.
+    let mut diagnostics = DeferredDiagnostics::default();
+    LLVMContextSetDiagnosticHandler(
+        context,
+        Some(llvm::diagnostic_handler::<DeferredDiagnostics>),
+        &mut diagnostics as *mut _ as _,
+    );
+    let module = llvm::create_module("archive", context).unwrap();
+
+    let mut linked = Vec::new();
+    for (name, data) in items {
+        info!("linking archive item {:?}", name);
+
+        let mut blocks = None;
+        let target = extract_bitcode(context, name, data, None, section).and_then(|bitcode| {
+            if dump_blocks {
+                blocks = Some(llvm::bitcode_blocks(&bitcode));
+            }
+            llvm::link_bitcode_buffer(context, module, &bitcode).ok_or_else(|| {
+                log_bitcode_producer(name, &bitcode);
+                LinkerError::LinkModuleError(name.to_owned())
+            })
+        });
+        let failed = !matches!(
+            target,
+            Ok(_) | Err(LinkerError::InvalidInputType(_) | LinkerError::MissingBitcodeSection(..))
+        );
+        linked.push(ChunkItem {
+            name: name.to_owned(),
+            target,
+            blocks,
+        });
+        if failed {
+            break;
+        }
+    }
+
+    let bitcode = llvm::write_bitcode_to_vec(module);
+    LLVMDisposeModule(module);
+    LLVMContextDispose(context);
+
+    LinkedChunk {
+        items: linked,
+        diagnostics,
+        bitcode,
+    }
+}
+
+// Logs the producer of bitcode LLVM failed to parse or link, which is usually a newer LLVM than the
+// one of the linker.
+fn log_bitcode_producer(path: &Path, bitcode: &[u8]) {
+    match llvm::bitcode_producer_string(bitcode) {
+        Ok(producer) => warn!("{:?} was produced by {}", path, producer),
+        Err(e) => warn!("failed to read the producer of {:?}: {}", path, e),
+    }
+}
+
+/// Formats the basic block and instruction counts of each function as a table, followed by the
+/// totals.
+pub fn format_function_stats(stats: &[(String, usize, usize)]) -> String {
+    let width = stats
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .chain(["function".len()])
+        .max()
+        .unwrap_or_default();
+    let mut table = format!(
+        "{:<width$} {:>8} {:>12}\n",
+        "function", "blocks", "instructions"
+    );
+    let (mut total_blocks, mut total_instructions) = (0, 0);
+    for (name, blocks, instructions) in stats {
+        table.push_str(&format!("{name:<width$} {blocks:>8} {instructions:>12}\n"));
+        total_blocks += blocks;
+        total_instructions += instructions;
+    }
+    table.push_str(&format!(
+        "{:<width$} {total_blocks:>8} {total_instructions:>12}\n",
+        "total"
+    ));
+    table
+}
+
+// Finds the archive of the library `name`, given like the `-l` argument of a linker, in the
+// library search path.
+fn find_library(name: &str, search_path: &[PathBuf]) -> Option<PathBuf> {
+    // Strip the kind of the library, like in `static=foo`.
+    let name = name.split_once('=').map_or(name, |(_, name)| name);
+    search_path.iter().find_map(|dir| {
+        ["a", "rlib"]
+            .into_iter()
+            .map(|extension| dir.join(format!("lib{name}.{extension}")))
+            .find(|path| path.is_file())
+    })
+}
+
+// Calls `f` with the name and the data of each item of the archive in `data`, in order. The data of
+// the items is borrowed from the archive instead of being copied.
+fn for_each_archive_item<'a>(
+    path: &Path,
+    data: &'a [u8],
+    mut f: impl FnMut(PathBuf, &'a [u8]) -> Result<(), LinkerError>,
+) -> Result<(), LinkerError> {
+    let pos = Cell::new(0);
+    let mut archive = Archive::new(ArchiveReader { data, pos: &pos });
+    let mut index = 0;
+    while let Some(item) = archive.next_entry() {
+        // The name of an item is part of its header, so only its index is known when the header
+        // is corrupt.
+        let item = item.map_err(|e| {
+            warn!("invalid header of item #{index} of archive {:?}: {e}", path);
+            LinkerError::LinkArchiveModuleError(path.to_owned(), PathBuf::from(format!("#{index}")))
+        })?;
+        // Member names aren't necessarily valid UTF-8, keep their bytes as they are.
+        let name = PathBuf::from(OsStr::from_bytes(item.header().identifier()));
+        // The archive stops reading at the end of the header, where the data of the item starts.
+        let size = item.header().size() as usize;
+        let item_data = data[pos.get()..]
+            .get(..size)
+            .ok_or_else(|| LinkerError::LinkArchiveModuleError(path.to_owned(), name.clone()))?;
+        f(name, item_data)?;
+        index += 1;
+    }
+
+    Ok(())
+}
+
+// Reads an archive from memory, keeping track of the position so that the data of its items can be
+// borrowed.
+struct ArchiveReader<'a, 'b> {
+    data: &'a [u8],
+    pos: &'b Cell<usize>,
+}
+
+impl Read for ArchiveReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = (&self.data[self.pos.get()..]).read(buf)?;
+        self.pos.set(self.pos.get() + read);
+        Ok(read)
+    }
+}
+
+const THIN_ARCHIVE_MAGIC: &[u8] = b"!<thin>\x0A";
+
+// Returns the paths of the members of a GNU thin archive, as stored in the archive. Unlike in
+// regular archives, the contents of the members are not stored in the archive, only the symbol
+// table and the table of long member names are.
+fn thin_archive_members(data: &[u8]) -> Result<Vec<PathBuf>, String> {
+    const HEADER_LEN: usize = 60;
+
+    let mut data = data
+        .strip_prefix(THIN_ARCHIVE_MAGIC)
+        .ok_or("invalid magic")?;
+    let mut long_names: &[u8] = &[];
+    let mut members = Vec::new();
+    while !data.is_empty() {
+        if data.len() < HEADER_LEN {
+            return Err("truncated member header".to_owned());
+        }
+        let (header, rest) = data.split_at(HEADER_LEN);
+        if &header[58..] != b"`\n" {
+            return Err("invalid member header".to_owned());
+        }
+        let name = &header[..16];
+        let name = &name[..name.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1)];
+        let size = str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim_end().parse::<usize>().ok())
+            .ok_or("invalid member size")?;
+
+        match name {
+            // The symbol tables and the long names table are stored in the archive.
+            b"/" | b"/SYM64/" | b"//" => {
+                let contents = rest.get(..size).ok_or("truncated member")?;
+                if name == b"//" {
+                    long_names = contents;
+                }
+                // Members are aligned to 2 bytes.
+                data = rest.get(size + size % 2..).unwrap_or(&[]);
+            }
+            name => {
+                let name = match name.strip_prefix(b"/") {
+                    Some(offset) => {
+                        let offset = str::from_utf8(offset)
+                            .ok()
+                            .and_then(|offset| offset.parse::<usize>().ok())
+                            .ok_or("invalid long name offset")?;
+                        let name = long_names.get(offset..).ok_or("invalid long name offset")?;
+                        let end = name
+                            .windows(2)
+                            .position(|w| w == b"/\n")
+                            .ok_or("unterminated long name")?;
+                        &name[..end]
+                    }
+                    None => name.strip_suffix(b"/").unwrap_or(name),
+                };
+                members.push(PathBuf::from(OsStr::from_bytes(name)));
+                data = rest;
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+// Map a member of a thin archive, which is a file of its own.
+fn map_thin_archive_member(path: &Path, member: &Path) -> Result<Mmap, LinkerError> {
+    let file = File::open(member).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => {
+            LinkerError::MissingThinArchiveMember(path.to_owned(), member.to_owned())
+        }
+        _ => LinkerError::IoError(member.to_owned(), e),
+    })?;
+    unsafe { Mmap::map(&file) }.map_err(|e| LinkerError::IoError(member.to_owned(), e))
+}
+
+// Whether the archive member is the metadata of a Rust rlib (`lib.rmeta`, which is a Mach-O file
+// on macOS) or a BSD symbol table (`__.SYMDEF`, `__.SYMDEF SORTED`, `__.SYMDEF_64`).
+fn is_archive_metadata(name: &Path) -> bool {
+    name.extension() == Some(OsStr::new("rmeta"))
+        || name
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"__.SYMDEF"))
+}
+
+#[cfg(test)]
+mod test {
+    use llvm_sys::core::{
+        LLVMGetBasicBlockTerminator, LLVMGetFirstBasicBlock, LLVMGetNamedFunction,
+        LLVMInstructionEraseFromParent, LLVMSetTarget,
+    };
+
+    use super::*;
+
+    // A bpfel module with a single XDP program, `prog`.
+    const PROG_IR: &str = r#"
+target triple = "bpfel"
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 0
+}
+"#;
+
+    // Returns an empty directory under `target/` for the files of the test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/target/test")).join(name);
+        let _: io::Result<()> = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Links `ir` into a fresh module, passes it to `f` and disposes of the
+    // context once `f` returns.
+    unsafe fn with_module<T>(ir: &str, f: impl FnOnce(LLVMModuleRef) -> T) -> T {
+        let context = LLVMContextCreate();
+        let module = llvm::create_module("test", context).unwrap();
+        let _: Option<(String, String)> =
+            llvm::link_ir_buffer(context, module, ir.as_bytes()).unwrap();
+        let result = f(module);
+        LLVMContextDispose(context);
+        result
+    }
+
+    #[test]
+    fn test_thin_archive_members() {
+        fn header(name: &str, size: usize) -> String {
+            format!("{name:<16}{:<12}{:<6}{:<6}{:<8}{size:<10}`\n", 0, 0, 0, 644)
+        }
+
+        let long_names = "deps/a_very_long_member_name.o/\n";
+        let mut archive = String::from("!<thin>\n");
+        archive += &header("/", 5);
+        archive += "\0\0\0\0\0\n";
+        archive += &header("//", long_names.len());
+        archive += long_names;
+        archive += &header("short.o/", 1234);
+        archive += &header("/0", 4321);
+
+        assert_eq!(
+            thin_archive_members(archive.as_bytes()).unwrap(),
+            [
+                PathBuf::from("short.o"),
+                PathBuf::from("deps/a_very_long_member_name.o"),
+            ]
+        );
+        assert!(thin_archive_members(b"!<arch>\n").is_err());
+        assert!(thin_archive_members(&archive.as_bytes()[..100]).is_err());
+    }
+
+    #[test]
+    fn test_is_archive_metadata() {
+        assert!(is_archive_metadata(Path::new("lib.rmeta")));
+        assert!(is_archive_metadata(Path::new("__.SYMDEF")));
+        assert!(is_archive_metadata(Path::new("__.SYMDEF SORTED")));
+        assert!(!is_archive_metadata(Path::new("foo.foo.1234-cgu.0.rcgu.o")));
+        assert!(!is_archive_metadata(Path::new("rmeta")));
+    }
+
+    #[test]
+    fn test_find_library() {
+        let dir = test_dir("find-library");
+        let other = dir.join("other");
+        fs::create_dir_all(&other).unwrap();
+        fs::write(dir.join("libfoo.a"), b"").unwrap();
+        fs::write(other.join("libbar.rlib"), b"").unwrap();
+        let search_path = [dir.clone(), other.clone()];
+
+        assert_eq!(
+            find_library("foo", &search_path),
+            Some(dir.join("libfoo.a"))
+        );
+        assert_eq!(
+            find_library("static=bar", &search_path),
+            Some(other.join("libbar.rlib"))
+        );
+        assert_eq!(find_library("baz", &search_path), None);
+        assert_eq!(find_library("foo", &[]), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_features() {
+        let features: BpfCpuFeatures = "+alu32,-dwarfris".parse().unwrap();
+        assert_eq!(features.to_string(), "+alu32,-dwarfris");
+
+        // The last occurrence of a feature wins.
+        let features: BpfCpuFeatures = "+alu32,+dummy,-alu32".parse().unwrap();
+        assert_eq!(features.to_string(), "+dummy,-alu32");
+
+        let features: BpfCpuFeatures = "".parse().unwrap();
+        assert_eq!(features, BpfCpuFeatures::default());
+        assert_eq!(features.to_string(), "");
+
+        assert!(matches!(
+            "+alu23".parse::<BpfCpuFeatures>(),
+            Err(LinkerError::InvalidCpuFeature(feature)) if feature == "+alu23"
+        ));
+        assert!(matches!(
+            "alu32".parse::<BpfCpuFeatures>(),
+            Err(LinkerError::InvalidCpuFeature(feature)) if feature == "alu32"
+        ));
+
+        let features =
+            BpfCpuFeatures::parse_unchecked("+alu32,+allows-misaligned-mem-access,-foo,+foo")
+                .unwrap();
+        assert_eq!(
+            features.to_string(),
+            "+alu32,+allows-misaligned-mem-access,+foo"
+        );
+        assert!(matches!(
+            BpfCpuFeatures::parse_unchecked("foo"),
+            Err(LinkerError::InvalidCpuFeature(feature)) if feature == "foo"
+        ));
+    }
+
+    #[test]
+    fn test_cpu_feature_suggestion() {
+        assert_eq!(CpuFeature::suggestion("+alu23").as_deref(), Some("+alu32"));
+        assert_eq!(
+            CpuFeature::suggestion("-dwarfri").as_deref(),
+            Some("-dwarfris")
+        );
+        assert_eq!(CpuFeature::suggestion("alu32").as_deref(), Some("+alu32"));
+        assert_eq!(CpuFeature::suggestion("+bswap"), None);
+
+        let err = "+alu23".parse::<BpfCpuFeatures>().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid CPU feature `+alu23` (did you mean `+alu32`?), expected"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_memory_builtins() {
+        let options = LinkerOptionsBuilder::new("out.o")
+            .disable_memory_builtin("memcpy".parse().unwrap())
+            .disable_memory_builtin(MemoryBuiltin::Bcmp)
+            .build();
+        assert_eq!(
+            options.memory_builtins.iter().collect::<Vec<_>>(),
+            [
+                MemoryBuiltin::Memmove,
+                MemoryBuiltin::Memset,
+                MemoryBuiltin::Memcmp
+            ]
+        );
+
+        let mut builtins = MemoryBuiltinSet::none();
+        builtins.enable(MemoryBuiltin::Memset);
+        builtins.enable(MemoryBuiltin::Memset);
+        assert_eq!(builtins.iter().collect::<Vec<_>>(), [MemoryBuiltin::Memset]);
+
+        let options = LinkerOptionsBuilder::new("out.o")
+            .disable_memory_builtins(true)
+            .export_memory_builtin(MemoryBuiltin::Memcpy)
+            .export_memory_builtin("strlen".parse().unwrap())
+            .build();
+        assert_eq!(
+            options.memory_builtins.iter().collect::<Vec<_>>(),
+            [MemoryBuiltin::Memcpy, MemoryBuiltin::Strlen]
+        );
+        assert!(!MemoryBuiltinSet::default().contains(MemoryBuiltin::Strlen));
+
+        assert!(matches!(
+            "strcpy".parse::<MemoryBuiltin>(),
+            Err(LinkerError::InvalidMemoryBuiltin(builtin)) if builtin == "strcpy"
+        ));
+    }
+
+    #[test]
+    fn test_parse_source_location() {
+        let message = "src/main.rs:12:5: in function foo i32 (ptr): A call to built-in function 'memcpy' is not supported.\n";
+        assert_eq!(
+            SourceLocation::parse(message),
+            Some(SourceLocation {
+                file: "src/main.rs".to_owned(),
+                line: 12,
+                column: 5,
+            })
+        );
+
+        let message = "<unknown>:0:0: in function foo i32 (ptr): too many arguments";
+        assert_eq!(
+            SourceLocation::parse(message),
+            Some(SourceLocation {
+                file: "<unknown>".to_owned(),
+                line: 0,
+                column: 0,
+            })
+        );
+
+        assert_eq!(
+            SourceLocation::parse("Looks like the BPF stack limit is exceeded"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_module_name() {
+        assert_eq!(module_name(Path::new("target/foo.o")).unwrap(), "foo");
+        assert_eq!(module_name(Path::new("foo")).unwrap(), "foo");
+        assert!(matches!(
+            module_name(Path::new("foo\0bar")),
+            Err(LinkerError::InvalidModuleName(name)) if name == "foo\0bar"
+        ));
+        for path in ["", "..", "target/.."] {
+            assert!(matches!(
+                module_name(Path::new(path)),
+                Err(LinkerError::InvalidOutputPath(p)) if p == Path::new(path)
+            ));
+        }
+        assert_eq!(
+            module_name(Path::new(OsStr::from_bytes(b"foo\xFF.o"))).unwrap(),
+            "foo\u{FFFD}"
+        );
+
+        // Linking to an output path with a NUL byte fails cleanly.
+        let mut linker = Linker::new(LinkerOptionsBuilder::new("foo\0bar.o").build());
+        assert!(matches!(
+            linker.link(),
+            Err(LinkerError::InvalidModuleName(name)) if name == "foo\0bar"
+        ));
+    }
+
+    #[test]
+    fn test_detect_llvm_ir() {
+        let ir = b"; ModuleID = 'foo.c'\nsource_filename = \"foo.c\"\n";
+        assert_eq!(detect_input_type(ir), Some(InputType::LlvmIr));
+        let ir = b"\ntarget datalayout = \"e-m:e-p:64:64-i64:64-i128:128-n32:64-S128\"\n";
+        assert_eq!(detect_input_type(ir), Some(InputType::LlvmIr));
+        let ir = b"define i32 @foo() {\n  ret i32 0\n}\n";
+        assert_eq!(detect_input_type(ir), Some(InputType::LlvmIr));
+        let ir = b"\xEF\xBB\xBF; ModuleID = 'foo.c'\n";
+        assert_eq!(detect_input_type(ir), Some(InputType::LlvmIr));
+        let ir = b"\n  \tsource_filename = \"foo.c\"\n";
+        assert_eq!(detect_input_type(ir), Some(InputType::LlvmIr));
+
+        assert_eq!(
+            detect_input_type(b"\x42\x43\xC0\xDE\x35\x14\x00\x00"),
+            Some(InputType::Bitcode)
+        );
+        assert_eq!(detect_input_type(b"int main(void) { return 0; }\n"), None);
+    }
+
+    #[test]
+    fn test_options_builder() {
+        let options = LinkerOptionsBuilder::new("out.o")
+            .target("bpfel-unknown-none")
+            .input(PathBuf::from("in.o"))
+            .optimize(OptLevel::Aggressive)
+            .export_symbol("prog")
+            .llvm_arg("--bpf-stack-size=1024")
+            .btf(true)
+            .build();
+
+        assert_eq!(options.target.as_deref(), Some("bpfel-unknown-none"));
+        assert!(matches!(options.cpu, Cpu::Generic));
+        assert!(
+            matches!(options.inputs.as_slice(), [LinkerInput::File { path }] if path == Path::new("in.o"))
+        );
+        assert_eq!(options.output, Path::new("out.o"));
+        assert!(matches!(options.output_type, OutputType::Object));
+        assert!(matches!(options.optimize, OptLevel::Aggressive));
+        assert_eq!(options.export_symbols, HashSet::from(["prog".into()]));
+        assert_eq!(options.llvm_args, ["--bpf-stack-size=1024"]);
+        assert!(options.btf);
+        assert_eq!(options.memory_builtins, MemoryBuiltinSet::default());
+    }
+
+    #[test]
+    fn test_bitcode_target_triple() {
+        for triple in ["bpfel", "bpfeb", "x86_64-unknown-linux-gnu", ""] {
+            let bitcode = unsafe {
+                let context = LLVMContextCreate();
+                let module = llvm::create_module("triple", context).unwrap();
+                let c_triple = CString::new(triple).unwrap();
+                LLVMSetTarget(module, c_triple.as_ptr());
+                let bitcode = llvm::write_bitcode_to_vec(module);
+                LLVMContextDispose(context);
+                bitcode
+            };
+            assert_eq!(
+                bitcode_target_triple(&bitcode).unwrap().as_deref(),
+                (!triple.is_empty()).then_some(triple)
+            );
+        }
+        assert_eq!(
+            bitcode_target_triple(b"not bitcode"),
+            Err(BitcodeError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn test_gc_functions() {
+        const IR: &str = r#"
+@table = global ptr @by_pointer
+
+define i32 @prog(ptr %ctx) section "xdp" {
+  %r = call i32 @helper()
+  ret i32 %r
+}
+
+define i32 @helper() {
+  ret i32 0
+}
+
+define void @by_pointer() {
+  ret void
+}
+
+define void @exported() {
+  call void @external()
+  ret void
+}
+
+define i32 @orphan() {
+  %r = call i32 @orphan_callee()
+  ret i32 %r
+}
+
+define i32 @orphan_callee() {
+  %r = call i32 @orphan()
+  ret i32 %r
+}
+
+declare void @external()
+"#;
+
+        unsafe {
+            with_module(IR, |module| {
+                let exported = HashSet::from(["exported".into()]);
+                assert_eq!(
+                    llvm::gc_functions(module, &llvm::ExportSymbols::Only(&exported)),
+                    2
+                );
+                assert_eq!(
+                    llvm::defined_symbols(module),
+                    HashSet::from(
+                        ["table", "prog", "helper", "by_pointer", "exported"].map(String::from)
+                    )
+                );
+            })
+        }
+    }
+
+    #[test]
+    fn test_program_sections() {
+        const IR: &str = r#"
+define i32 @ingress(ptr %ctx) section "xdp" {
+  ret i32 0
+}
+
+define i32 @egress(ptr %ctx) section "xdp" {
+  ret i32 0
+}
+
+define i32 @alloc(ptr %task) section "lsm/task_alloc" {
+  ret i32 0
+}
+
+define i32 @helper() {
+  ret i32 0
+}
+"#;
+
+        unsafe {
+            with_module(IR, |module| {
+                assert_eq!(llvm::program_sections(module), ["lsm/task_alloc", "xdp"]);
+            })
+        }
+    }
+
+    #[test]
+    fn test_verify_module() {
+        const IR: &str = r#"
+define i32 @prog(ptr %ctx) section "xdp" {
+  ret i32 0
+}
+"#;
+
+        unsafe {
+            with_module(IR, |module| {
+                assert_eq!(llvm::verify_module(module), Ok(()));
+
+                // Remove the terminator of the only basic block.
+                let name = CString::new("prog").unwrap();
+                let function = LLVMGetNamedFunction(module, name.as_ptr());
+                let ret = LLVMGetBasicBlockTerminator(LLVMGetFirstBasicBlock(function));
+                LLVMInstructionEraseFromParent(ret);
+                let err = llvm::verify_module(module).unwrap_err();
+                assert!(err.contains("terminator"), "unexpected error: {err}");
+            })
+        }
+    }
+
+    #[test]
+    fn test_module_target_features() {
+        const IR: &str = r#"
+define i32 @prog(ptr %ctx) #0 {
+  ret i32 0
+}
+
+define i32 @helper() #0 {
+  ret i32 0
+}
+
+define i32 @other() #1 {
+  ret i32 0
+}
+
+declare i32 @external() #1
+
+attributes #0 = { "target-features"="+alu32,-dwarfris" }
+attributes #1 = { "target-features"="+dummy" }
+"#;
+
+        unsafe {
+            with_module(IR, |module| {
+                let features = llvm::module_target_features(module).unwrap();
+                assert_eq!(features, "+alu32,-dwarfris");
+            })
+        }
+
+        let features: BpfCpuFeatures = "+alu32,-dwarfris".parse().unwrap();
+        assert!(features.same_features(&"-dwarfris,+alu32".parse().unwrap()));
+        assert!(!features.same_features(&"+alu32".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_decompress_section() {
+        fn compression_header(ch_type: u32, size: usize) -> Vec<u8> {
+            // Elf64_Chdr, little endian.
+            [
+                ch_type.to_le_bytes().as_slice(),
+                &0u32.to_le_bytes(),
+                &(size as u64).to_le_bytes(),
+                &1u64.to_le_bytes(),
+            ]
+            .concat()
+        }
+
+        // A little endian ELF64 header, followed by the headers of the null section, of .llvmbc
+        // with the given flags and of .shstrtab, followed by the section names.
+        fn elf(flags: u64) -> Vec<u8> {
+            let mut elf = vec![0; 64];
+            elf[..8].copy_from_slice(b"\x7FELF\x02\x01\x01\x00");
+            elf[0x28..0x30].copy_from_slice(&64u64.to_le_bytes());
+            elf[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+            elf[0x3C..0x3E].copy_from_slice(&3u16.to_le_bytes());
+            elf[0x3E..0x40].copy_from_slice(&2u16.to_le_bytes());
+            for (name, flags, offset) in [(0u32, 0, 0u64), (1, flags, 0), (9, 0, 256)] {
+                let mut header = [0; 64];
+                header[..4].copy_from_slice(&name.to_le_bytes());
+                header[8..16].copy_from_slice(&flags.to_le_bytes());
+                header[0x18..0x20].copy_from_slice(&offset.to_le_bytes());
+                elf.extend(header);
+            }
+            elf.extend(b"\0.llvmbc\0.shstrtab\0");
+            elf
+        }
+
+        let path = Path::new("foo.o");
+        let (elf, compressed_elf) = (elf(0), elf(SHF_COMPRESSED));
+        let bitcode = b"\x42\x43\xC0\xDE\x35\x14\x00\x00".repeat(16);
+        assert_eq!(
+            elf_section_flags(&compressed_elf, ".llvmbc"),
+            Some(SHF_COMPRESSED)
+        );
+        assert_eq!(elf_section_flags(&compressed_elf, ".shstrtab"), Some(0));
+        assert_eq!(elf_section_flags(&compressed_elf, ".text"), None);
+
+        assert_eq!(
+            decompress_section(path, &elf, ".llvmbc", bitcode.clone()).unwrap(),
+            bitcode
+        );
+
+        let mut section = compression_header(ELFCOMPRESS_ZSTD, bitcode.len());
+        section.extend(zstd::encode_all(bitcode.as_slice(), 0).unwrap());
+        // Without SHF_COMPRESSED, the compression header is taken as the contents of the section.
+        assert_eq!(
+            decompress_section(path, &elf, ".llvmbc", section.clone()).unwrap(),
+            section
+        );
+        assert_eq!(
+            decompress_section(path, &compressed_elf, ".llvmbc", section).unwrap(),
+            bitcode
+        );
 
-        Ok(())
-    }
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            compression_header(ELFCOMPRESS_ZLIB, bitcode.len()),
+            flate2::Compression::default(),
+        );
+        std::io::Write::write_all(&mut encoder, &bitcode).unwrap();
+        let section = encoder.finish().unwrap();
+        assert_eq!(
+            decompress_section(path, &compressed_elf, ".llvmbc", section).unwrap(),
+            bitcode
+        );
 
-    // link in a `Read`-er, which can be a file or an archive item
-    fn link_reader(
-        &mut self,
-        path: &Path,
-        mut reader: impl Read,
-        in_type: Option<InputType>,
-    ) -> Result<(), LinkerError> {
-        let mut data = Vec::new();
-        let _: usize = reader
-            .read_to_end(&mut data)
-            .map_err(|e| LinkerError::IoError(path.to_owned(), e))?;
-        // in_type is unknown when we're linking an item from an archive file
-        let in_type = in_type
-            .or_else(|| detect_input_type(&data))
-            .ok_or_else(|| LinkerError::InvalidInputType(path.to_owned()))?;
+        let mut section = compression_header(ELFCOMPRESS_ZSTD, 16);
+        section.extend(zstd::encode_all([0; 16].as_slice(), 0).unwrap());
+        assert!(matches!(
+            decompress_section(path, &compressed_elf, ".llvmbc", section),
+            Err(LinkerError::EmbeddedBitcodeError(_))
+        ));
 
-        use InputType::*;
-        let bitcode = match in_type {
-            Bitcode => data,
-            Elf => match unsafe { llvm::find_embedded_bitcode(self.context, &data) } {
-                Ok(Some(bitcode)) => bitcode,
-                Ok(None) => return Err(LinkerError::MissingBitcodeSection(path.to_owned())),
-                Err(e) => return Err(LinkerError::EmbeddedBitcodeError(e)),
-            },
-            // we need to handle this here since archive files could contain
-            // mach-o files, eg somecrate.rlib containing lib.rmeta which is
-            // mach-o on macos
-            InputType::MachO => return Err(LinkerError::InvalidInputType(path.to_owned())),
-            // this can't really happen
-            Archive => panic!("nested archives not supported duh"),
-        };
+        let section = compression_header(3, bitcode.len());
+        assert!(matches!(
+            decompress_section(path, &compressed_elf, ".llvmbc", section),
+            Err(LinkerError::UnsupportedSectionCompression(p, 3)) if p == path
+        ));
+    }
 
-        if unsafe { !llvm::link_bitcode_buffer(self.context, self.module, &bitcode) } {
-            return Err(LinkerError::LinkModuleError(path.to_owned()));
+    #[test]
+    fn test_link_one() {
+        let dir = test_dir("link-one");
+        let mut linker = Linker::new(LinkerOptionsBuilder::new("unused.o").build());
+        for name in ["first", "second"] {
+            let output = dir.join(format!("{name}.o"));
+            linker
+                .link_one(
+                    vec![LinkerInput::new_from_bytes(
+                        name,
+                        PROG_IR.as_bytes().to_vec(),
+                    )],
+                    &output,
+                )
+                .unwrap();
+            assert!(output.exists());
+            assert_eq!(
+                linker.linked_inputs().collect::<Vec<_>>(),
+                [Path::new(name)]
+            );
         }
-
-        Ok(())
+        assert_eq!(linker.target_machines.len(), 1);
     }
 
-    fn create_target_machine(&mut self) -> Result<(), LinkerError> {
-        let Self {
-            options:
-                LinkerOptions {
-                    target,
-                    cpu,
-                    cpu_features,
-                    ..
-                },
-            module,
-            target_machine,
-            ..
-        } = self;
-        // Here's how the output target is selected:
-        //
-        // 1) rustc with builtin BPF support: cargo build --target=bpf[el|eb]-unknown-none
-        //      the input modules are already configured for the correct output target
-        //
-        // 2) rustc with no BPF support: cargo rustc -- -C linker-flavor=bpf-linker -C linker=bpf-linker -C link-arg=--target=bpf[el|eb]
-        //      the input modules are configured for the *host* target, and the output target
-        //      is configured with the `--target` linker argument
-        //
-        // 3) rustc with no BPF support: cargo rustc -- -C linker-flavor=bpf-linker -C linker=bpf-linker
-        //      the input modules are configured for the *host* target, the output target isn't
-        //      set via `--target`, so default to `bpf` (bpfel or bpfeb depending on the host
-        //      endianness)
-        let (triple, target) = match target {
-            // case 1
-            Some(triple) => {
-                let c_triple = CString::new(triple.as_str()).unwrap();
-                (triple.as_str(), unsafe {
-                    llvm::target_from_triple(&c_triple)
-                })
-            }
-            None => {
-                let c_triple = unsafe { LLVMGetTarget(*module) };
-                let triple = unsafe { CStr::from_ptr(c_triple) }.to_str().unwrap();
-                if triple.starts_with("bpf") {
-                    // case 2
-                    (triple, unsafe { llvm::target_from_module(*module) })
-                } else {
-                    // case 3.
-                    info!("detected non-bpf input target {} and no explicit output --target specified, selecting `bpf'", triple);
-                    let triple = "bpf";
-                    let c_triple = CString::new(triple).unwrap();
-                    (triple, unsafe { llvm::target_from_triple(&c_triple) })
-                }
-            }
-        };
-        let target = target.map_err(|_msg| LinkerError::InvalidTarget(triple.to_owned()))?;
+    #[test]
+    fn test_max_errors() {
+        let options = LinkerOptionsBuilder::new("out.o").max_errors(2).build();
+        let mut linker = Linker::new(options);
+        linker.error_count = 1;
+        assert!(linker.check_error_count().is_ok());
+        linker.error_count = 2;
+        assert!(matches!(
+            linker.check_error_count(),
+            Err(LinkerError::TooManyErrors(2))
+        ));
 
-        debug!(
-            "creating target machine: triple: {} cpu: {} features: {}",
-            triple, cpu, cpu_features,
+        // Without any error, even a limit of zero links.
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.ll")
+                .input(LinkerInput::new_from_bytes(
+                    "prog.ll",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .output_type(OutputType::LlvmAssembly)
+                .max_errors(0)
+                .build(),
         );
+        assert!(linker.link_to_buffer().is_ok());
+        linker.error_count = 1;
+        assert!(matches!(
+            linker.check_error_count(),
+            Err(LinkerError::TooManyErrors(1))
+        ));
+    }
 
-        *target_machine =
-            unsafe { llvm::create_target_machine(target, triple, cpu.to_str(), cpu_features) }
-                .ok_or_else(|| LinkerError::InvalidTarget(triple.to_owned()))?;
+    #[test]
+    fn test_max_errors_records_diagnostics() {
+        use llvm::LLVMDiagnosticHandler as _;
+        use llvm_sys::LLVMDiagnosticSeverity::LLVMDSError;
 
-        Ok(())
+        let options = LinkerOptionsBuilder::new("out.o").max_errors(1).build();
+        let mut linker = Linker::new(options);
+        linker.handle_diagnostic(LLVMDSError, "first error");
+        linker.handle_diagnostic(LLVMDSError, "second error");
+        assert_eq!(linker.error_count, 2);
+        assert_eq!(
+            linker
+                .diagnostics()
+                .iter()
+                .map(|diagnostic| diagnostic.message.as_str())
+                .collect::<Vec<_>>(),
+            ["first error", "second error"]
+        );
     }
 
-    fn optimize(&mut self) -> Result<(), LinkerError> {
-        if !self.options.disable_memory_builtins {
-            self.options.export_symbols.extend(
-                ["memcpy", "memmove", "memset", "memcmp", "bcmp"]
-                    .into_iter()
-                    .map(Into::into),
-            );
-        };
-        debug!(
-            "linking exporting symbols {:?}, opt level {:?}",
-            self.options.export_symbols, self.options.optimize
+    #[test]
+    fn test_deny_warnings() {
+        use llvm::LLVMDiagnosticHandler as _;
+        use llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning;
+
+        let options = LinkerOptionsBuilder::new("out.o")
+            .deny_warnings(true)
+            .build();
+        let mut linker = Linker::new(options);
+        linker.handle_diagnostic(
+            LLVMDSWarning,
+            "A call to built-in function 'memcpy' is not supported.\n",
         );
-        // run optimizations. Will optionally remove noinline attributes, intern all non exported
-        // programs and maps and remove dead code.
+        assert!(!linker.has_errors());
+        linker.handle_diagnostic(LLVMDSWarning, "stack size exceeded");
+        assert!(linker.has_errors());
+        let severities = linker
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.severity)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            severities,
+            [DiagnosticSeverity::Warning, DiagnosticSeverity::Error]
+        );
+    }
 
-        if self.options.btf {
-            // if we want to emit BTF, we need to sanitize the debug information
-            llvm::DISanitizer::new(self.context, self.module).run(&self.options.export_symbols);
-        } else {
-            // if we don't need BTF emission, we can strip DI
-            let ok = unsafe { llvm::strip_debug_info(self.module) };
-            debug!("Stripping DI, changed={}", ok);
-        }
+    #[test]
+    fn test_suppress_diagnostics() {
+        use llvm::LLVMDiagnosticHandler as _;
+        use llvm_sys::LLVMDiagnosticSeverity::{LLVMDSError, LLVMDSWarning};
 
-        unsafe {
-            llvm::optimize(
-                self.target_machine,
-                self.module,
-                self.options.optimize,
-                self.options.ignore_inline_never,
-                &self.options.export_symbols,
-            )
-        }
-        .map_err(LinkerError::OptimizeError)?;
+        let options = LinkerOptionsBuilder::new("out.o")
+            .suppress_diagnostic("unsupported signed division")
+            .build();
+        let mut linker = Linker::new(options);
+        linker.handle_diagnostic(
+            LLVMDSError,
+            "A call to built-in function 'memset' is not supported.\n",
+        );
+        linker.handle_diagnostic(
+            LLVMDSError,
+            "prog.c:1:2: in function prog i32 (i32): unsupported signed division, please convert to unsigned div/mod.",
+        );
+        linker.handle_diagnostic(LLVMDSWarning, "stack size exceeded");
+        assert!(!linker.has_errors());
+        assert_eq!(linker.diagnostics().len(), 1);
+    }
 
-        Ok(())
+    #[test]
+    fn test_conflicting_export_options() {
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.o")
+                .export_symbol("foo")
+                .internalize_symbol("bar")
+                .build(),
+        );
+        assert!(matches!(
+            linker.link_to_buffer(),
+            Err(LinkerError::ConflictingExportOptions)
+        ));
     }
 
-    fn codegen(&mut self) -> Result<(), LinkerError> {
-        let output = CString::new(self.options.output.as_os_str().to_str().unwrap()).unwrap();
-        match self.options.output_type {
-            OutputType::Bitcode => self.write_bitcode(&output),
-            OutputType::LlvmAssembly => self.write_ir(&output),
-            OutputType::Assembly => self.emit(&output, LLVMCodeGenFileType::LLVMAssemblyFile),
-            OutputType::Object => self.emit(&output, LLVMCodeGenFileType::LLVMObjectFile),
+    #[test]
+    fn test_btf_type_name_max_len() {
+        for (max_len, valid) in [
+            (MIN_BTF_TYPE_NAME_LEN - 1, false),
+            (MIN_BTF_TYPE_NAME_LEN, true),
+        ] {
+            let options = LinkerOptionsBuilder::new("out.o")
+                .input(LinkerInput::new_from_bytes(
+                    "input",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .export_symbol("prog")
+                .btf(true)
+                .btf_type_name_max_len(max_len)
+                .build();
+            let result = Linker::new(options).link_to_buffer();
+            assert_eq!(
+                matches!(result, Err(LinkerError::InvalidBtfTypeNameMaxLen(len)) if len == max_len),
+                !valid,
+                "{result:?}"
+            );
         }
     }
 
-    fn write_bitcode(&mut self, output: &CStr) -> Result<(), LinkerError> {
-        info!("writing bitcode to {:?}", output);
+    #[test]
+    fn test_export_patterns() {
+        const IR: &str = r#"
+target triple = "bpfel"
 
-        if unsafe { LLVMWriteBitcodeToFile(self.module, output.as_ptr()) } == 1 {
-            return Err(LinkerError::WriteBitcodeError);
-        }
+define i32 @xdp_pass(ptr %ctx) {
+  ret i32 2
+}
 
-        Ok(())
+define i32 @xdp_drop(ptr %ctx) {
+  ret i32 1
+}
+
+define i32 @helper(ptr %ctx) {
+  ret i32 0
+}
+"#;
+
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.ll")
+                .input(LinkerInput::new_from_bytes("input", IR.as_bytes().to_vec()))
+                .output_type(OutputType::LlvmAssembly)
+                .export_pattern(Regex::new("^xdp_").unwrap())
+                .build(),
+        );
+        let output = String::from_utf8(linker.link_to_buffer().unwrap()).unwrap();
+        assert!(output.contains("define i32 @xdp_pass("), "{output}");
+        assert!(output.contains("define i32 @xdp_drop("), "{output}");
+        assert!(!output.contains("@helper"), "{output}");
+
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.o")
+                .export_pattern(Regex::new("^xdp_").unwrap())
+                .internalize_symbol("helper")
+                .build(),
+        );
+        assert!(matches!(
+            linker.link_to_buffer(),
+            Err(LinkerError::ConflictingExportOptions)
+        ));
     }
 
-    fn write_ir(&mut self, output: &CStr) -> Result<(), LinkerError> {
-        info!("writing IR to {:?}", output);
+    #[test]
+    fn test_codegen_opt_level() {
+        use llvm_sys::target_machine::LLVMCodeGenOptLevel::*;
 
-        unsafe { llvm::write_ir(self.module, output) }.map_err(LinkerError::WriteIRError)
+        assert!(matches!(
+            llvm::codegen_opt_level(OptLevel::No),
+            LLVMCodeGenLevelNone
+        ));
+        assert!(matches!(
+            llvm::codegen_opt_level(OptLevel::Less),
+            LLVMCodeGenLevelLess
+        ));
+        assert!(matches!(
+            llvm::codegen_opt_level(OptLevel::Default),
+            LLVMCodeGenLevelDefault
+        ));
+        assert!(matches!(
+            llvm::codegen_opt_level(OptLevel::Aggressive),
+            LLVMCodeGenLevelAggressive
+        ));
+        assert!(matches!(
+            llvm::codegen_opt_level(OptLevel::SizeMin),
+            LLVMCodeGenLevelDefault
+        ));
+    }
+
+    #[test]
+    fn test_bpf_endianness() {
+        assert_eq!(bpf_endianness("bpfel"), Some(Endianness::Little));
+        assert_eq!(
+            bpf_endianness("bpfel-unknown-none"),
+            Some(Endianness::Little)
+        );
+        assert_eq!(bpf_endianness("bpfeb"), Some(Endianness::Big));
+        assert_eq!(bpf_endianness("bpf"), None);
+        assert_eq!(bpf_endianness("x86_64-unknown-linux-gnu"), None);
     }
 
-    fn emit(&mut self, output: &CStr, output_type: LLVMCodeGenFileType) -> Result<(), LinkerError> {
-        info!("emitting {:?} to {:?}", output_type, output);
+    #[test]
+    fn test_precompiled_object() {
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("precompiled.o")
+                .input(LinkerInput::new_from_bytes(
+                    "input",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .output_type(OutputType::Object)
+                .export_symbol("prog")
+                .build(),
+        );
+        let object = linker.link_to_buffer().unwrap();
+        assert!(is_bpf_object(&object));
+        assert!(!is_bpf_object(b"\x7FELF"));
 
-        unsafe { llvm::codegen(self.target_machine, self.module, output, output_type) }
-            .map_err(LinkerError::EmitCodeError)
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.o")
+                .input(LinkerInput::new_from_bytes("precompiled.o", object))
+                .build(),
+        );
+        assert!(matches!(
+            linker.link_to_buffer(),
+            Err(LinkerError::PrecompiledObject(path)) if path == Path::new("precompiled.o")
+        ));
     }
 
-    fn llvm_init(&mut self) {
-        let mut args = Vec::<Cow<str>>::new();
-        args.push("bpf-linker".into());
-        // Disable cold call site detection. Many accessors in aya-ebpf return Result<T, E>
-        // where the layout is larger than 64 bits, but the LLVM BPF target only supports
-        // up to 64 bits return values. Since the accessors are tiny in terms of code, we
-        // avoid the issue by annotating them with #[inline(always)]. If they are classified
-        // as cold though - and they often are starting from LLVM17 - #[inline(always)]
-        // is ignored and the BPF target fails codegen.
-        args.push("--cold-callsite-rel-freq=0".into());
-        if self.options.unroll_loops {
-            // setting cmdline arguments is the only way to customize the unroll pass with the
-            // C API.
-            args.extend([
-                "--unroll-runtime".into(),
-                "--unroll-runtime-multi-exit".into(),
-                format!("--unroll-max-upperbound={}", u32::MAX).into(),
-                format!("--unroll-threshold={}", u32::MAX).into(),
-            ]);
-        }
-        if !self.options.disable_expand_memcpy_in_order {
-            args.push("--bpf-expand-memcpy-in-order".into());
-        }
-        args.extend(self.options.llvm_args.iter().map(Into::into));
-        info!("LLVM command line: {:?}", args);
-        unsafe {
-            llvm::init(&args, "BPF linker");
+    #[test]
+    fn test_preserve_debug_info() {
+        const IR: &str = r#"
+target triple = "bpfel"
 
-            self.context = LLVMContextCreate();
-            LLVMContextSetDiagnosticHandler(
-                self.context,
-                Some(llvm::diagnostic_handler::<Self>),
-                self as *mut _ as _,
+define i32 @prog(ptr %ctx) section "xdp" !dbg !3 {
+  ret i32 0, !dbg !7
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!2}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_Rust, file: !1, producer: "rustc", isOptimized: true, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !DIFile(filename: "main.rs", directory: "/src")
+!2 = !{i32 2, !"Debug Info Version", i32 3}
+!3 = distinct !DISubprogram(name: "prog", scope: !1, file: !1, line: 1, type: !4, scopeLine: 1, spFlags: DISPFlagDefinition | DISPFlagOptimized, unit: !0)
+!4 = !DISubroutineType(types: !5)
+!5 = !{!6}
+!6 = !DIBasicType(name: "i32", size: 32, encoding: DW_ATE_signed)
+!7 = !DILocation(line: 2, column: 5, scope: !3)
+"#;
+
+        for preserve_debug_info in [false, true] {
+            let mut linker = Linker::new(
+                LinkerOptionsBuilder::new("out.ll")
+                    .input(LinkerInput::new_from_bytes("input", IR.as_bytes().to_vec()))
+                    .output_type(OutputType::LlvmAssembly)
+                    .export_symbol("prog")
+                    .preserve_debug_info(preserve_debug_info)
+                    .build(),
+            );
+            let output = String::from_utf8(linker.link_to_buffer().unwrap()).unwrap();
+            assert_eq!(
+                output.contains("!DICompileUnit("),
+                preserve_debug_info,
+                "{output}"
             );
-            LLVMInstallFatalErrorHandler(Some(llvm::fatal_error));
-            LLVMEnablePrettyStackTrace();
-            self.module = llvm::create_module(
-                self.options.output.file_stem().unwrap().to_str().unwrap(),
-                self.context,
-            )
-            .unwrap();
         }
     }
+
+    #[test]
+    fn test_rename_sections() {
+        const IR: &str = r#"
+define i32 @probe(ptr %ctx) section "kprobe/do_unlinkat" {
+  ret i32 0
 }
 
-impl llvm::LLVMDiagnosticHandler for Linker {
-    fn handle_diagnostic(&mut self, severity: llvm_sys::LLVMDiagnosticSeverity, message: &str) {
-        // TODO(https://reviews.llvm.org/D155894): Remove this when LLVM no longer emits these
-        // errors.
-        //
-        // See https://github.com/rust-lang/compiler-builtins/blob/a61823f/src/mem/mod.rs#L22-L68.
-        const MATCHERS: &[&str] = &[
-            "A call to built-in function 'memcpy' is not supported.\n",
-            "A call to built-in function 'memmove' is not supported.\n",
-            "A call to built-in function 'memset' is not supported.\n",
-            "A call to built-in function 'memcmp' is not supported.\n",
-            "A call to built-in function 'bcmp' is not supported.\n",
-            "A call to built-in function 'strlen' is not supported.\n",
-        ];
+define i32 @probe_all(ptr %ctx) section "kprobe" {
+  ret i32 0
+}
 
-        match severity {
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSError => {
-                if MATCHERS.iter().any(|matcher| message.ends_with(matcher)) {
-                    return;
-                }
-                self.has_errors = true;
+define i32 @probe_multi(ptr %ctx) section "kprobe.multi/do_unlinkat" {
+  ret i32 0
+}
 
-                error!("llvm: {}", message)
-            }
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSWarning => warn!("llvm: {}", message),
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSRemark => debug!("remark: {}", message),
-            llvm_sys::LLVMDiagnosticSeverity::LLVMDSNote => debug!("note: {}", message),
+define i32 @ingress(ptr %ctx) section "xdp" {
+  ret i32 0
+}
+
+declare i32 @external() section "kprobe/external"
+"#;
+
+        unsafe {
+            with_module(IR, |module| {
+                let renames = [("kprobe".to_owned(), "kprobe.multi".to_owned())];
+                assert_eq!(llvm::rename_sections(module, &renames), Ok(2));
+                assert_eq!(
+                    llvm::program_sections(module),
+                    ["kprobe.multi", "kprobe.multi/do_unlinkat", "xdp"]
+                );
+
+                let renames = [("xdp".to_owned(), "xdp\0frags".to_owned())];
+                assert_eq!(
+                    llvm::rename_sections(module, &renames),
+                    Err("xdp\0frags".to_owned())
+                );
+            })
         }
     }
+
+    #[test]
+    fn test_function_stats() {
+        const IR: &str = r#"
+define i32 @prog(ptr %ctx, i1 %c) section "xdp" {
+entry:
+  br i1 %c, label %yes, label %no
+yes:
+  ret i32 1
+no:
+  ret i32 0
 }
 
-impl Drop for Linker {
-    fn drop(&mut self) {
+define i32 @helper() {
+  ret i32 0
+}
+
+declare void @external()
+"#;
+
         unsafe {
-            if !self.target_machine.is_null() {
-                LLVMDisposeTargetMachine(self.target_machine);
-            }
-            if !self.module.is_null() {
-                LLVMDisposeModule(self.module);
-            }
-            if !self.context.is_null() {
-                LLVMContextDispose(self.context);
-            }
+            with_module(IR, |module| {
+                let stats = llvm::function_stats(module);
+                assert_eq!(
+                    stats,
+                    [("prog".to_owned(), 3, 3), ("helper".to_owned(), 1, 1)]
+                );
+                assert_eq!(
+                    format_function_stats(&stats),
+                    "function   blocks instructions\n\
+                     prog            3            3\n\
+                     helper          1            1\n\
+                     total           4            4\n"
+                );
+            })
         }
+
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.o")
+                .input(LinkerInput::new_from_bytes(
+                    "input",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .export_symbol("prog")
+                .print_stats(true)
+                .build(),
+        );
+        let _: Vec<u8> = linker.link_to_buffer().unwrap();
+        assert_eq!(linker.function_stats(), [("prog".to_owned(), 1, 1)]);
     }
-}
 
-fn detect_input_type(data: &[u8]) -> Option<InputType> {
-    if data.len() < 8 {
-        return None;
+    #[test]
+    fn test_non_utf8_archive_member() {
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("member.bc")
+                .input(LinkerInput::new_from_bytes(
+                    "input",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .output_type(OutputType::Bitcode)
+                .export_symbol("prog")
+                .build(),
+        );
+        let bitcode = linker.link_to_buffer().unwrap();
+
+        let mut builder = ar::Builder::new(Vec::new());
+        builder
+            .append(
+                &ar::Header::new(b"member\xFF.o".to_vec(), bitcode.len() as u64),
+                bitcode.as_slice(),
+            )
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.ll")
+                .input(LinkerInput::new_from_bytes("libmember.a", archive))
+                .output_type(OutputType::LlvmAssembly)
+                .export_symbol("prog")
+                .build(),
+        );
+        let output = String::from_utf8(linker.link_to_buffer().unwrap()).unwrap();
+        assert!(output.contains("define i32 @prog("), "{output}");
     }
 
-    use InputType::*;
-    match &data[..4] {
-        b"\x42\x43\xC0\xDE" | b"\xDE\xC0\x17\x0b" => Some(Bitcode),
-        b"\x7FELF" => Some(Elf),
-        b"\xcf\xfa\xed\xfe" => Some(MachO),
-        _ => {
-            if &data[..8] == b"!<arch>\x0A" {
-                Some(Archive)
-            } else {
-                None
+    #[test]
+    fn test_archive_jobs() {
+        let mut builder = ar::Builder::new(Vec::new());
+        for i in 0..5 {
+            let ir = format!(
+                r#"
+target triple = "bpfel"
+
+define i32 @prog{i}(ptr %ctx) section "xdp" {{
+  ret i32 {i}
+}}
+"#
+            );
+            let mut linker = Linker::new(
+                LinkerOptionsBuilder::new("member.bc")
+                    .input(LinkerInput::new_from_bytes("input", ir.into_bytes()))
+                    .output_type(OutputType::Bitcode)
+                    .export_symbol(format!("prog{i}"))
+                    .build(),
+            );
+            let bitcode = linker.link_to_buffer().unwrap();
+            builder
+                .append(
+                    &ar::Header::new(format!("member{i}.o").into_bytes(), bitcode.len() as u64),
+                    bitcode.as_slice(),
+                )
+                .unwrap();
+        }
+        let notes = b"not bitcode";
+        builder
+            .append(
+                &ar::Header::new(b"notes.txt".to_vec(), notes.len() as u64),
+                notes.as_slice(),
+            )
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        // Linking the members on multiple threads gives the same module as linking them one by
+        // one.
+        let outputs = [1, 2, 3].map(|jobs| {
+            let mut builder = LinkerOptionsBuilder::new("out.ll")
+                .input(LinkerInput::new_from_bytes("libmembers.a", archive.clone()))
+                .output_type(OutputType::LlvmAssembly)
+                .dump_bitcode_blocks(true)
+                .jobs(jobs);
+            for i in 0..5 {
+                builder = builder.export_symbol(format!("prog{i}"));
             }
+            let mut linker = Linker::new(builder.build());
+            let output = String::from_utf8(linker.link_to_buffer().unwrap()).unwrap();
+            assert_eq!(linker.skipped_inputs().len(), 1, "{jobs} jobs");
+            assert_eq!(
+                linker
+                    .bitcode_blocks()
+                    .iter()
+                    .map(|(name, blocks)| (name.clone(), blocks.is_ok()))
+                    .collect::<Vec<_>>(),
+                (0..5)
+                    .map(|i| (PathBuf::from(format!("member{i}.o")), true))
+                    .collect::<Vec<_>>(),
+                "{jobs} jobs"
+            );
+            output
+        });
+        for i in 0..5 {
+            assert!(
+                outputs[0].contains(&format!("define i32 @prog{i}(")),
+                "{}",
+                outputs[0]
+            );
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn test_archive_corrupt_header() {
+        let mut archive = ar::Builder::new(Vec::new());
+        let bitcode = unsafe { with_module(PROG_IR, |module| llvm::write_bitcode_to_vec(module)) };
+        archive
+            .append(
+                &ar::Header::new(b"member.o".to_vec(), bitcode.len() as u64),
+                bitcode.as_slice(),
+            )
+            .unwrap();
+        let mut archive = archive.into_inner().unwrap();
+        archive.extend_from_slice(b"not a member header");
+
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("out.ll")
+                .input(LinkerInput::new_from_bytes("libcorrupt.a", archive))
+                .output_type(OutputType::LlvmAssembly)
+                .build(),
+        );
+        assert!(matches!(
+            linker.link_to_buffer(),
+            Err(LinkerError::LinkArchiveModuleError(path, name))
+                if path == Path::new("libcorrupt.a") && name == Path::new("#1")
+        ));
+    }
+
+    #[test]
+    fn test_cancel() {
+        for cancelled in [false, true] {
+            let mut linker = Linker::new(
+                LinkerOptionsBuilder::new("out.o")
+                    .input(LinkerInput::new_from_bytes(
+                        "input",
+                        PROG_IR.as_bytes().to_vec(),
+                    ))
+                    .cancel(Arc::new(AtomicBool::new(cancelled)))
+                    .build(),
+            );
+            let result = linker.link_to_buffer();
+            assert_eq!(
+                matches!(result, Err(LinkerError::Cancelled)),
+                cancelled,
+                "{result:?}"
+            );
         }
     }
+
+    #[test]
+    fn test_dependency_file() {
+        let mut linker = Linker::new(
+            LinkerOptionsBuilder::new("member.bc")
+                .input(LinkerInput::new_from_bytes(
+                    "input",
+                    PROG_IR.as_bytes().to_vec(),
+                ))
+                .output_type(OutputType::Bitcode)
+                .export_symbol("prog")
+                .build(),
+        );
+        let bitcode = linker.link_to_buffer().unwrap();
+
+        let dir = test_dir("dependency-file");
+        let mut builder = ar::Builder::new(Vec::new());
+        builder
+            .append(
+                &ar::Header::new(b"member.o".to_vec(), bitcode.len() as u64),
+                bitcode.as_slice(),
+            )
+            .unwrap();
+        let archive = dir.join("libmember.a");
+        fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        // An archive without bitcode is skipped, and isn't a dependency.
+        let mut builder = ar::Builder::new(Vec::new());
+        let notes = b"not bitcode";
+        builder
+            .append(
+                &ar::Header::new(b"notes.txt".to_vec(), notes.len() as u64),
+                notes.as_slice(),
+            )
+            .unwrap();
+        let notes = dir.join("libnotes.a");
+        fs::write(&notes, builder.into_inner().unwrap()).unwrap();
+
+        let deps = dir.join("out.d");
+        let options = LinkerOptionsBuilder::new(dir.join("out.o"))
+            .input(LinkerInput::new_from_file(&archive))
+            .input(LinkerInput::new_from_file(&notes))
+            .export_symbol("prog")
+            .emit_deps(&deps)
+            .build();
+        let mut linker = Linker::new(options);
+        let _: Vec<u8> = linker.link_to_buffer().unwrap();
+        assert_eq!(
+            fs::read_to_string(&deps).unwrap(),
+            format!(
+                "{}: \\\n  {}\n# {}(member.o)\n",
+                dir.join("out.o").display(),
+                archive.display(),
+                archive.display()
+            )
+        );
+    }
 }
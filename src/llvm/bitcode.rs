@@ -0,0 +1,679 @@
+//! Walker over the block structure of LLVM bitcode, see
+//! <https://llvm.org/docs/BitCodeFormat.html>. The records are decoded without being interpreted,
+//! which makes it usable on inputs LLVM fails to parse.
+
+use std::{collections::HashMap, fmt::Write as _};
+
+use thiserror::Error;
+
+// Bitcode wrapper header, followed by its version, the offset and the size of the bitcode and the
+// CPU type.
+const WRAPPER_MAGIC: &[u8] = b"\xDE\xC0\x17\x0B";
+const WRAPPER_HEADER_LEN: usize = 20;
+const MAGIC: &[u8] = b"BC\xC0\xDE";
+
+// Abbreviation IDs with a fixed meaning.
+const END_BLOCK: u64 = 0;
+const ENTER_SUBBLOCK: u64 = 1;
+const DEFINE_ABBREV: u64 = 2;
+const UNABBREV_RECORD: u64 = 3;
+
+const BLOCKINFO_BLOCK_ID: u64 = 0;
+const BLOCKINFO_CODE_SETBID: u64 = 1;
+const IDENTIFICATION_BLOCK_ID: u64 = 13;
+const IDENTIFICATION_CODE_STRING: u64 = 1;
+const MODULE_BLOCK_ID: u64 = 8;
+const MODULE_CODE_TRIPLE: u64 = 2;
+
+/// Error walking the blocks of a bitcode file
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BitcodeError {
+    /// The data doesn't start with the bitcode magic.
+    #[error("invalid bitcode magic")]
+    InvalidMagic,
+
+    /// The bitcode wrapper header is truncated or points outside of the data.
+    #[error("invalid bitcode wrapper header")]
+    InvalidWrapper,
+
+    /// The data ends in the middle of a block.
+    #[error("unexpected end of bitcode at bit {0}")]
+    UnexpectedEof(usize),
+
+    /// A record uses an abbreviation which wasn't defined.
+    #[error("undefined abbreviation {abbrev} in block {block}")]
+    UndefinedAbbrev { block: u64, abbrev: u64 },
+
+    /// An abbreviation definition uses an unknown operand encoding.
+    #[error("invalid abbreviation operand encoding {0}")]
+    InvalidEncoding(u64),
+
+    /// Something else than a block was found at the top level.
+    #[error("expected a block at the top level, found abbreviation {0}")]
+    ExpectedBlock(u64),
+
+    /// The bitcode has no identification block naming its producer.
+    #[error("no identification block")]
+    MissingIdentification,
+}
+
+/// Block of a bitcode file
+#[derive(Debug, PartialEq, Eq)]
+pub struct BitcodeBlock {
+    pub id: u64,
+    /// Width of the abbreviation IDs in the block.
+    pub abbrev_width: u64,
+    /// Number of records in the block, not counting the abbreviation definitions.
+    pub records: usize,
+    pub blocks: Vec<BitcodeBlock>,
+}
+
+impl BitcodeBlock {
+    /// Returns the name of the block in LLVM IR bitcode, if it's known.
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self.id {
+            0 => "BLOCKINFO",
+            8 => "MODULE_BLOCK",
+            9 => "PARAMATTR_BLOCK",
+            10 => "PARAMATTR_GROUP_BLOCK",
+            11 => "CONSTANTS_BLOCK",
+            12 => "FUNCTION_BLOCK",
+            13 => "IDENTIFICATION_BLOCK",
+            14 => "VALUE_SYMTAB_BLOCK",
+            15 => "METADATA_BLOCK",
+            16 => "METADATA_ATTACHMENT",
+            17 => "TYPE_BLOCK",
+            18 => "USELIST_BLOCK",
+            19 => "MODULE_STRTAB_BLOCK",
+            20 => "GLOBALVAL_SUMMARY_BLOCK",
+            21 => "OPERAND_BUNDLE_TAGS_BLOCK",
+            22 => "METADATA_KIND_BLOCK",
+            23 => "STRTAB_BLOCK",
+            24 => "FULL_LTO_GLOBALVAL_SUMMARY_BLOCK",
+            25 => "SYMTAB_BLOCK",
+            26 => "SYNC_SCOPE_NAMES_BLOCK",
+            _ => return None,
+        })
+    }
+}
+
+/// Formats the block hierarchy, one block per line, indenting the nested blocks.
+pub fn format_blocks(blocks: &[BitcodeBlock]) -> String {
+    fn format(blocks: &[BitcodeBlock], depth: usize, out: &mut String) {
+        for block in blocks {
+            let BitcodeBlock {
+                id,
+                abbrev_width,
+                records,
+                blocks,
+            } = block;
+            let name = block.name().unwrap_or("unknown block");
+            let indent = depth * 2;
+            let _: Result<(), _> = writeln!(
+                out,
+                "{:indent$}{name} ({id}): abbrev width {abbrev_width}, {records} records",
+                ""
+            );
+            format(blocks, depth + 1, out);
+        }
+    }
+
+    let mut out = String::new();
+    format(blocks, 0, &mut out);
+    out
+}
+
+/// Returns the top level blocks of the bitcode in `data`, which may start with a bitcode wrapper
+/// header.
+pub fn bitcode_blocks(data: &[u8]) -> Result<Vec<BitcodeBlock>, BitcodeError> {
+    walk_blocks(data, &mut |_, _, _| {})
+}
+
+/// Returns the string identifying the producer of the bitcode in `data`, e.g.
+/// `LLVM19.1.0-rust-1.83.0`.
+pub fn bitcode_producer_string(data: &[u8]) -> Result<String, BitcodeError> {
+    let mut producer = None;
+    let result = walk_blocks(data, &mut |block, code, operands| {
+        if block == IDENTIFICATION_BLOCK_ID
+            && code == IDENTIFICATION_CODE_STRING
+            && producer.is_none()
+        {
+            producer = Some(operands.iter().map(|ch| char::from(*ch as u8)).collect());
+        }
+    });
+    // The identification block comes first, the rest of the bitcode doesn't need to be valid.
+    match (producer, result) {
+        (Some(producer), _) => Ok(producer),
+        (None, Err(e)) => Err(e),
+        (None, Ok(_)) => Err(BitcodeError::MissingIdentification),
+    }
+}
+
+/// Returns the target triple of the bitcode module in `data`, or `None` if the module doesn't
+/// specify a target.
+pub fn bitcode_target_triple(data: &[u8]) -> Result<Option<String>, BitcodeError> {
+    let mut triple = None;
+    let _: Vec<BitcodeBlock> = walk_blocks(data, &mut |block, code, operands| {
+        if block == MODULE_BLOCK_ID && code == MODULE_CODE_TRIPLE && triple.is_none() {
+            triple = Some(operands.iter().map(|ch| char::from(*ch as u8)).collect());
+        }
+    })?;
+    Ok(triple)
+}
+
+// Walks the blocks of the bitcode in `data`, calling `on_record` with the block ID, the code and the
+// operands of each record.
+fn walk_blocks(
+    data: &[u8],
+    on_record: &mut dyn FnMut(u64, u64, &[u64]),
+) -> Result<Vec<BitcodeBlock>, BitcodeError> {
+    let data = strip_wrapper(data)?;
+    let data = data.strip_prefix(MAGIC).ok_or(BitcodeError::InvalidMagic)?;
+
+    let mut cursor = BitCursor { data, pos: 0 };
+    let mut block_info = HashMap::new();
+    let mut blocks = Vec::new();
+    while !cursor.at_end() {
+        match cursor.read(2)? {
+            ENTER_SUBBLOCK => blocks.push(read_block(&mut cursor, &mut block_info, on_record)?),
+            abbrev => return Err(BitcodeError::ExpectedBlock(abbrev)),
+        }
+    }
+    Ok(blocks)
+}
+
+fn strip_wrapper(data: &[u8]) -> Result<&[u8], BitcodeError> {
+    if !data.starts_with(WRAPPER_MAGIC) {
+        return Ok(data);
+    }
+    if data.len() < WRAPPER_HEADER_LEN {
+        return Err(BitcodeError::InvalidWrapper);
+    }
+    let field = |index: usize| {
+        u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap()) as usize
+    };
+    let (offset, size) = (field(2), field(3));
+    // The bitcode can't overlap with the header.
+    if offset < WRAPPER_HEADER_LEN {
+        return Err(BitcodeError::InvalidWrapper);
+    }
+    offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(BitcodeError::InvalidWrapper)
+}
+
+#[derive(Clone, Copy)]
+enum AbbrevOp {
+    Literal(u64),
+    Fixed(u64),
+    Vbr(u64),
+    Array,
+    Char6,
+    Blob,
+}
+
+// Reads the block following an ENTER_SUBBLOCK abbreviation ID. `block_info` holds the
+// abbreviations defined for each block ID in the BLOCKINFO blocks.
+fn read_block(
+    cursor: &mut BitCursor<'_>,
+    block_info: &mut HashMap<u64, Vec<Vec<AbbrevOp>>>,
+    on_record: &mut dyn FnMut(u64, u64, &[u64]),
+) -> Result<BitcodeBlock, BitcodeError> {
+    let id = cursor.read_vbr(8)?;
+    let abbrev_width = cursor.read_vbr(4)?;
+    cursor.align32();
+    // Length of the block in 32-bit words, not needed to walk it.
+    let _: u64 = cursor.read(32)?;
+
+    let mut abbrevs = block_info.get(&id).cloned().unwrap_or_default();
+    let mut block = BitcodeBlock {
+        id,
+        abbrev_width,
+        records: 0,
+        blocks: Vec::new(),
+    };
+    // Block whose abbreviations are defined in a BLOCKINFO block.
+    let mut info_block_id = None;
+    loop {
+        match cursor.read(abbrev_width)? {
+            END_BLOCK => {
+                cursor.align32();
+                return Ok(block);
+            }
+            ENTER_SUBBLOCK => block
+                .blocks
+                .push(read_block(cursor, block_info, on_record)?),
+            DEFINE_ABBREV => {
+                let abbrev = read_abbrev(cursor)?;
+                match info_block_id {
+                    Some(info_block_id) if id == BLOCKINFO_BLOCK_ID => {
+                        block_info.entry(info_block_id).or_default().push(abbrev)
+                    }
+                    _ => abbrevs.push(abbrev),
+                }
+            }
+            abbrev => {
+                let (code, operands) = if abbrev == UNABBREV_RECORD {
+                    let code = cursor.read_vbr(6)?;
+                    let len = cursor.read_vbr(6)?;
+                    let operands = (0..len)
+                        .map(|_| cursor.read_vbr(6))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    (code, operands)
+                } else {
+                    let ops = usize::try_from(abbrev - 4)
+                        .ok()
+                        .and_then(|index| abbrevs.get(index))
+                        .ok_or(BitcodeError::UndefinedAbbrev { block: id, abbrev })?;
+                    read_abbreviated_record(cursor, ops)?
+                };
+                if id == BLOCKINFO_BLOCK_ID && code == BLOCKINFO_CODE_SETBID {
+                    info_block_id = operands.first().copied();
+                }
+                on_record(id, code, &operands);
+                block.records += 1;
+            }
+        }
+    }
+}
+
+fn read_abbrev(cursor: &mut BitCursor<'_>) -> Result<Vec<AbbrevOp>, BitcodeError> {
+    let operands = cursor.read_vbr(5)?;
+    let mut ops = Vec::new();
+    for _ in 0..operands {
+        let op = if cursor.read(1)? == 1 {
+            AbbrevOp::Literal(cursor.read_vbr(8)?)
+        } else {
+            match cursor.read(3)? {
+                // Zero width operands are always zero, like literals.
+                1 => match cursor.read_vbr(5)? {
+                    0 => AbbrevOp::Literal(0),
+                    width => AbbrevOp::Fixed(width),
+                },
+                2 => match cursor.read_vbr(5)? {
+                    0 => AbbrevOp::Literal(0),
+                    width => AbbrevOp::Vbr(width),
+                },
+                3 => AbbrevOp::Array,
+                4 => AbbrevOp::Char6,
+                5 => AbbrevOp::Blob,
+                encoding => return Err(BitcodeError::InvalidEncoding(encoding)),
+            }
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
+// Reads a record using the abbreviation `ops`, returns its code and its operands. The characters
+// of char6 operands and the bytes of blobs are returned as operands of their own.
+fn read_abbreviated_record(
+    cursor: &mut BitCursor<'_>,
+    ops: &[AbbrevOp],
+) -> Result<(u64, Vec<u64>), BitcodeError> {
+    fn read_scalar(cursor: &mut BitCursor<'_>, op: AbbrevOp) -> Result<u64, BitcodeError> {
+        Ok(match op {
+            AbbrevOp::Literal(value) => value,
+            AbbrevOp::Fixed(width) => cursor.read(width)?,
+            AbbrevOp::Vbr(width) => cursor.read_vbr(width)?,
+            AbbrevOp::Char6 => {
+                const CHARS: &[u8; 64] =
+                    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789._";
+                CHARS[cursor.read(6)? as usize].into()
+            }
+            AbbrevOp::Array | AbbrevOp::Blob => 0,
+        })
+    }
+
+    let mut values = Vec::new();
+    let mut ops = ops.iter();
+    while let Some(op) = ops.next() {
+        match op {
+            // The element type of an array is the operand following it.
+            AbbrevOp::Array => {
+                let len = cursor.read_vbr(6)?;
+                let element = ops.next().copied().unwrap_or(AbbrevOp::Literal(0));
+                for _ in 0..len {
+                    values.push(read_scalar(cursor, element)?);
+                }
+            }
+            AbbrevOp::Blob => {
+                let len = cursor.read_vbr(6)?;
+                cursor.align32();
+                values.extend(cursor.read_bytes(len)?.iter().map(|byte| u64::from(*byte)));
+                cursor.align32();
+            }
+            op => values.push(read_scalar(cursor, *op)?),
+        }
+    }
+    // The first operand of an abbreviation is the code of the record.
+    if values.is_empty() {
+        return Ok((0, values));
+    }
+    let code = values.remove(0);
+    Ok((code, values))
+}
+
+// Reads the bitstream, least significant bit first.
+struct BitCursor<'a> {
+    data: &'a [u8],
+    // Position in bits.
+    pos: usize,
+}
+
+impl BitCursor<'_> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len() * 8
+    }
+
+    fn read(&mut self, width: u64) -> Result<u64, BitcodeError> {
+        let mut value = 0;
+        for bit in 0..width.min(64) {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or(BitcodeError::UnexpectedEof(self.pos))?;
+            value |= u64::from((byte >> (self.pos % 8)) & 1) << bit;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_vbr(&mut self, width: u64) -> Result<u64, BitcodeError> {
+        let continuation = 1 << (width - 1);
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let chunk = self.read(width)?;
+            if shift < 64 {
+                value |= (chunk & (continuation - 1)) << shift;
+            }
+            if chunk & continuation == 0 {
+                return Ok(value);
+            }
+            shift += width - 1;
+        }
+    }
+
+    fn align32(&mut self) {
+        self.pos = self.pos.next_multiple_of(32);
+    }
+
+    // Reads `len` bytes, the position must be byte aligned.
+    fn read_bytes(&mut self, len: u64) -> Result<&[u8], BitcodeError> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len * 8))
+            .filter(|end| *end <= self.data.len() * 8)
+            .ok_or(BitcodeError::UnexpectedEof(self.data.len() * 8))?;
+        let bytes = &self.data[self.pos / 8..end / 8];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Writes a bitstream, least significant bit first.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u64, width: u64) {
+            for bit in 0..width {
+                if self.pos % 8 == 0 {
+                    self.data.push(0);
+                }
+                let last = self.data.last_mut().unwrap();
+                *last |= (((value >> bit) & 1) as u8) << (self.pos % 8);
+                self.pos += 1;
+            }
+        }
+
+        fn write_vbr(&mut self, mut value: u64, width: u64) {
+            let continuation = 1 << (width - 1);
+            while value >= continuation {
+                self.write((value & (continuation - 1)) | continuation, width);
+                value >>= width - 1;
+            }
+            self.write(value, width);
+        }
+
+        fn align32(&mut self) {
+            while self.pos % 32 != 0 {
+                self.write(0, 1);
+            }
+        }
+
+        fn enter_block(&mut self, abbrev_width: u64, id: u64, new_abbrev_width: u64) {
+            self.write(ENTER_SUBBLOCK, abbrev_width);
+            self.write_vbr(id, 8);
+            self.write_vbr(new_abbrev_width, 4);
+            self.align32();
+            // The length isn't checked.
+            self.write(0, 32);
+        }
+
+        fn end_block(&mut self, abbrev_width: u64) {
+            self.write(END_BLOCK, abbrev_width);
+            self.align32();
+        }
+
+        fn unabbrev_record(&mut self, abbrev_width: u64, code: u64, operands: &[u64]) {
+            self.write(UNABBREV_RECORD, abbrev_width);
+            self.write_vbr(code, 6);
+            self.write_vbr(operands.len() as u64, 6);
+            for operand in operands {
+                self.write_vbr(*operand, 6);
+            }
+        }
+    }
+
+    fn bitcode() -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        for byte in MAGIC {
+            writer.write(u64::from(*byte), 8);
+        }
+        // An identification block with a string record.
+        writer.enter_block(2, 13, 5);
+        writer.unabbrev_record(5, 1, &[b'L'.into(), b'L'.into(), b'V'.into(), b'M'.into()]);
+        writer.end_block(5);
+        // A module block defining an abbreviation for its functions in a BLOCKINFO block.
+        writer.enter_block(2, 8, 3);
+        writer.enter_block(3, BLOCKINFO_BLOCK_ID, 2);
+        writer.unabbrev_record(2, BLOCKINFO_CODE_SETBID, &[12]);
+        // [literal 7, fixed(4), array of char6]
+        writer.write(DEFINE_ABBREV, 2);
+        writer.write_vbr(4, 5);
+        writer.write(1, 1);
+        writer.write_vbr(7, 8);
+        writer.write(0, 1);
+        writer.write(1, 3);
+        writer.write_vbr(4, 5);
+        writer.write(0, 1);
+        writer.write(3, 3);
+        writer.write(0, 1);
+        writer.write(4, 3);
+        writer.end_block(2);
+        writer.unabbrev_record(3, 1, &[2]);
+        writer.enter_block(3, 12, 4);
+        writer.write(4, 4);
+        writer.write(9, 4);
+        writer.write_vbr(3, 6);
+        for _ in 0..3 {
+            writer.write(1, 6);
+        }
+        writer.unabbrev_record(4, 10, &[]);
+        writer.end_block(4);
+        writer.end_block(3);
+        writer.data
+    }
+
+    #[test]
+    fn test_bitcode_blocks() {
+        let blocks = bitcode_blocks(&bitcode()).unwrap();
+        assert_eq!(
+            format_blocks(&blocks),
+            "IDENTIFICATION_BLOCK (13): abbrev width 5, 1 records\n\
+             MODULE_BLOCK (8): abbrev width 3, 1 records\n  \
+             BLOCKINFO (0): abbrev width 2, 1 records\n  \
+             FUNCTION_BLOCK (12): abbrev width 4, 2 records\n"
+        );
+    }
+
+    #[test]
+    fn test_records() {
+        let mut records = Vec::new();
+        let _: Vec<BitcodeBlock> = walk_blocks(&bitcode(), &mut |block, code, operands| {
+            records.push((block, code, operands.to_vec()))
+        })
+        .unwrap();
+        assert_eq!(
+            records,
+            [
+                (13, 1, b"LLVM".map(u64::from).to_vec()),
+                (BLOCKINFO_BLOCK_ID, BLOCKINFO_CODE_SETBID, vec![12]),
+                (8, 1, vec![2]),
+                // The abbreviated record, with its literal code and its char6 array.
+                (
+                    12,
+                    7,
+                    [9].into_iter().chain(b"bbb".map(u64::from)).collect()
+                ),
+                (12, 10, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wrapped_bitcode() {
+        let bitcode = bitcode();
+        // The wrapper header, followed by padding as emitted on Darwin.
+        let mut wrapped = Vec::new();
+        for field in [0x0B17C0DE, 0, 20, bitcode.len() as u32, 0x01000007] {
+            wrapped.extend_from_slice(&field.to_le_bytes());
+        }
+        wrapped.extend_from_slice(&bitcode);
+        wrapped.extend_from_slice(&[0; 12]);
+
+        assert_eq!(bitcode_blocks(&wrapped), bitcode_blocks(&bitcode));
+        assert_eq!(bitcode_producer_string(&wrapped).as_deref(), Ok("LLVM"));
+    }
+
+    #[test]
+    fn test_bitcode_producer_string() {
+        assert_eq!(bitcode_producer_string(&bitcode()).as_deref(), Ok("LLVM"));
+
+        // LLVM abbreviates the string record, as a char6 array when possible.
+        let mut writer = BitWriter::default();
+        for byte in MAGIC {
+            writer.write(u64::from(*byte), 8);
+        }
+        writer.enter_block(2, IDENTIFICATION_BLOCK_ID, 5);
+        // [literal 1, array of char6]
+        writer.write(DEFINE_ABBREV, 5);
+        writer.write_vbr(3, 5);
+        writer.write(1, 1);
+        writer.write_vbr(IDENTIFICATION_CODE_STRING, 8);
+        writer.write(0, 1);
+        writer.write(3, 3);
+        writer.write(0, 1);
+        writer.write(4, 3);
+        writer.write(4, 5);
+        writer.write_vbr(6, 6);
+        // LLVM19
+        for ch in [37, 37, 47, 38, 53, 61] {
+            writer.write(ch, 6);
+        }
+        writer.end_block(5);
+        // Walking the truncated block which follows fails, after the string was read.
+        writer.write(5, 2);
+        assert_eq!(
+            bitcode_producer_string(&writer.data).as_deref(),
+            Ok("LLVM19")
+        );
+
+        let mut writer = BitWriter::default();
+        for byte in MAGIC {
+            writer.write(u64::from(*byte), 8);
+        }
+        writer.enter_block(2, 8, 3);
+        writer.end_block(3);
+        assert_eq!(
+            bitcode_producer_string(&writer.data),
+            Err(BitcodeError::MissingIdentification)
+        );
+    }
+
+    #[test]
+    fn test_bitcode_target_triple() {
+        assert_eq!(bitcode_target_triple(&bitcode()), Ok(None));
+
+        let mut writer = BitWriter::default();
+        for byte in MAGIC {
+            writer.write(u64::from(*byte), 8);
+        }
+        writer.enter_block(2, MODULE_BLOCK_ID, 3);
+        writer.unabbrev_record(3, 1, &[2]);
+        writer.unabbrev_record(3, MODULE_CODE_TRIPLE, &b"bpfel".map(u64::from));
+        writer.end_block(3);
+        assert_eq!(
+            bitcode_target_triple(&writer.data),
+            Ok(Some("bpfel".to_owned()))
+        );
+
+        assert_eq!(
+            bitcode_target_triple(b"not bitcode"),
+            Err(BitcodeError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn test_bitcode_blocks_errors() {
+        let bitcode = bitcode();
+        assert!(matches!(
+            bitcode_blocks(&bitcode[..bitcode.len() - 8]),
+            Err(BitcodeError::UnexpectedEof(_))
+        ));
+        assert_eq!(bitcode_blocks(b"BC\xC0"), Err(BitcodeError::InvalidMagic));
+        assert_eq!(
+            bitcode_blocks(b"\xDE\xC0\x17\x0B\0\0\0\0\xFF\0\0\0\x04\0\0\0\0\0\0\0"),
+            Err(BitcodeError::InvalidWrapper)
+        );
+        // A truncated header, and a header pointing at itself.
+        assert_eq!(
+            bitcode_blocks(b"\xDE\xC0\x17\x0B\0\0\0\0\x14\0\0\0\x04\0\0\0"),
+            Err(BitcodeError::InvalidWrapper)
+        );
+        assert_eq!(
+            bitcode_blocks(b"\xDE\xC0\x17\x0B\0\0\0\0\x0C\0\0\0\x04\0\0\0\0\0\0\0"),
+            Err(BitcodeError::InvalidWrapper)
+        );
+
+        // A wrapped stream with an undefined abbreviation.
+        let mut writer = BitWriter::default();
+        for byte in MAGIC {
+            writer.write(u64::from(*byte), 8);
+        }
+        writer.enter_block(2, 8, 3);
+        writer.write(5, 3);
+        let mut wrapped = Vec::new();
+        for field in [0x0B17C0DE, 0, 20, writer.data.len() as u32, 0] {
+            wrapped.extend_from_slice(&field.to_le_bytes());
+        }
+        wrapped.extend_from_slice(&writer.data);
+        assert_eq!(
+            bitcode_blocks(&wrapped),
+            Err(BitcodeError::UndefinedAbbrev {
+                block: 8,
+                abbrev: 5
+            })
+        );
+    }
+}
@@ -1,38 +1,125 @@
 use std::{
-    borrow::Cow,
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
-    ffi::c_char,
-    hash::Hasher,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ffi::{c_char, CStr, CString},
     ptr,
 };
 
-use gimli::{DW_TAG_pointer_type, DW_TAG_structure_type, DW_TAG_variant_part};
+use gimli::{
+    DW_ATE_signed, DW_ATE_unsigned, DW_TAG_enumeration_type, DW_TAG_pointer_type,
+    DW_TAG_structure_type, DW_TAG_union_type, DW_TAG_variant_part, DwTag,
+};
 use llvm_sys::{core::*, debuginfo::*, prelude::*};
-use tracing::{span, trace, warn, Level};
+use thiserror::Error;
+use tracing::{debug, field, span, trace, warn, Level};
 
 use super::types::{
-    di::DIType,
+    di::{DICompositeType, DIFile, DIType},
     ir::{Function, MDNode, Metadata, Value},
 };
-use crate::llvm::{iter::*, types::di::DISubprogram};
+use crate::llvm::{iter::*, types::di::DISubprogram, ExportSymbols};
 
 // KSYM_NAME_LEN from linux kernel intentionally set
 // to lower value found accross kernel versions to ensure
 // backward compatibility
-const MAX_KSYM_NAME_LEN: usize = 128;
+pub const MAX_KSYM_NAME_LEN: usize = 128;
+
+/// Shortest limit of the BTF type names. Truncated names are suffixed with an underscore and a
+/// 32 characters hash, this keeps at least one character of the name.
+pub const MIN_BTF_TYPE_NAME_LEN: usize = 34;
+
+/// Error sanitizing the debug info for BTF
+#[derive(Debug, Error)]
+pub enum BtfSanitizeError {
+    /// An item which must have a value has none.
+    #[error("{0} has no value")]
+    NullMetadata(String),
+
+    /// The context or the module to sanitize is null.
+    #[error("the {0} to sanitize is null")]
+    NullPointer(&'static str),
+
+    /// The module has no debug info to sanitize.
+    #[error("the module has no debug info")]
+    NoDebugInfo,
+
+    /// A metadata node is not debug info metadata.
+    #[error("metadata node is not debug info metadata")]
+    UnexpectedMetadata,
+
+    /// A subprogram is missing a field needed to rewrite it.
+    #[error("subprogram of function `{function}` has no {field}")]
+    IncompleteSubprogram {
+        function: String,
+        field: &'static str,
+    },
+
+    /// The new name of a type contains a NUL byte.
+    #[error(
+        "cannot rename `{name}` ({location}) to `{new_name}`: the new name contains a NUL byte"
+    )]
+    InvalidName {
+        name: String,
+        new_name: String,
+        location: String,
+    },
+}
+
+// Formats the location of a debug info node as `file:line`.
+fn location(file: &DIFile, line: u32) -> String {
+    let filename = file
+        .filename()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .unwrap_or_else(|| "<unknown>".to_owned());
+    format!("{filename}:{line}")
+}
 
 pub struct DISanitizer {
     context: LLVMContextRef,
     module: LLVMModuleRef,
     builder: LLVMDIBuilderRef,
-    visited_nodes: HashSet<u64>,
+    // Ids of the visited nodes, assigned in visiting order. Nodes are keyed by their value: values
+    // and metadata are owned by the context and nothing is freed while the sanitizer runs (nodes
+    // which get replaced stay alive until the context is disposed), so a value can't be reused
+    // for a different node during a run.
+    node_ids: HashMap<LLVMValueRef, u64>,
     item_stack: Vec<Item>,
-    replace_operands: HashMap<u64, LLVMMetadataRef>,
+    replace_operands: HashMap<LLVMValueRef, LLVMMetadataRef>,
     skipped_types: Vec<String>,
+    downgrade_enum64: bool,
+    max_name_len: usize,
+    keep_variant_enums: bool,
+    dedup_types: bool,
+    // Definitions of the named structs and unions, in visiting order. Only collected when
+    // `dedup_types` is set.
+    composite_types: Vec<LLVMValueRef>,
+    // Set while merging duplicate types: the nodes are only rewired, they were already sanitized.
+    merging: bool,
+}
+
+// Structs and unions with the same key have the same sanitized name and the same members, and
+// are emitted as identical BTF types.
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct CompositeTypeKey {
+    tag: DwTag,
+    name: CString,
+    size_in_bits: u64,
+    // Name, offset and size in bits and base type of each member.
+    members: Vec<(Option<CString>, usize, u64, LLVMValueRef)>,
+}
+
+// 128-bit FNV-1a. Unlike `DefaultHasher`, its output is stable across Rust versions and platforms,
+// which keeps the emitted BTF reproducible.
+pub(crate) fn fnv1a_128(bytes: &[u8]) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u128::from(*byte)).wrapping_mul(PRIME)
+    })
 }
 
 // Sanitize Rust type names to be valid C type names.
-fn sanitize_type_name<T: AsRef<str>>(name: T) -> String {
+fn sanitize_type_name<T: AsRef<str>>(name: T, max_len: usize) -> String {
     let n: String = name
         .as_ref()
         .chars()
@@ -47,33 +134,66 @@ fn sanitize_type_name<T: AsRef<str>>(name: T) -> String {
         .collect();
 
     // we trim type name if it is too long
-    if n.len() > MAX_KSYM_NAME_LEN {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(n.as_bytes());
-        let hash = format!("{:x}", hasher.finish());
+    if n.len() > max_len {
+        let hash = format!("{:032x}", fnv1a_128(n.as_bytes()));
         // leave space for underscore
-        let trim = MAX_KSYM_NAME_LEN - hash.len() - 1;
+        let trim = max_len.saturating_sub(hash.len() + 1);
         return format!("{}_{hash}", &n[..trim]);
     }
 
     n
 }
 
+/// Sanitizes a Rust type name the same way the linker does when emitting BTF, hashing names which
+/// are longer than [`MAX_KSYM_NAME_LEN`].
+pub fn sanitize_btf_type_name(name: &str) -> String {
+    sanitize_type_name(name, MAX_KSYM_NAME_LEN)
+}
+
 impl DISanitizer {
-    pub fn new(context: LLVMContextRef, module: LLVMModuleRef) -> DISanitizer {
-        DISanitizer {
+    /// Creates a sanitizer for the debug info of `module`. Fails with
+    /// [`BtfSanitizeError::NoDebugInfo`] when the module has no compile unit, since there's
+    /// nothing to sanitize.
+    pub(crate) fn new(
+        context: LLVMContextRef,
+        module: LLVMModuleRef,
+        downgrade_enum64: bool,
+        max_name_len: usize,
+        keep_variant_enums: bool,
+        dedup_types: bool,
+    ) -> Result<DISanitizer, BtfSanitizeError> {
+        if context.is_null() {
+            return Err(BtfSanitizeError::NullPointer("context"));
+        }
+        if module.is_null() {
+            return Err(BtfSanitizeError::NullPointer("module"));
+        }
+        let compile_units = unsafe {
+            LLVMGetNamedMetadataNumOperands(module, b"llvm.dbg.cu\0".as_ptr() as *const c_char)
+        };
+        if compile_units == 0 {
+            return Err(BtfSanitizeError::NoDebugInfo);
+        }
+
+        Ok(DISanitizer {
             context,
             module,
             builder: unsafe { LLVMCreateDIBuilder(module) },
-            visited_nodes: HashSet::new(),
+            node_ids: HashMap::new(),
             item_stack: Vec::new(),
             replace_operands: HashMap::new(),
             skipped_types: Vec::new(),
-        }
+            downgrade_enum64,
+            max_name_len,
+            keep_variant_enums,
+            dedup_types,
+            composite_types: Vec::new(),
+            merging: false,
+        })
     }
 
-    fn visit_mdnode(&mut self, mdnode: MDNode) {
-        match mdnode.try_into().expect("MDNode is not Metadata") {
+    fn visit_mdnode(&mut self, mdnode: MDNode) -> Result<(), BtfSanitizeError> {
+        match Metadata::try_from(mdnode).map_err(|()| BtfSanitizeError::UnexpectedMetadata)? {
             Metadata::DICompositeType(mut di_composite_type) => {
                 #[allow(clippy::single_match)]
                 #[allow(non_upper_case_globals)]
@@ -82,7 +202,8 @@ impl DISanitizer {
                         let names = match di_composite_type.name() {
                             Some(name) => {
                                 let original_name = name.to_string_lossy().to_string();
-                                let sanitized_name = sanitize_type_name(&original_name);
+                                let sanitized_name =
+                                    sanitize_type_name(&original_name, self.max_name_len);
 
                                 Some((original_name, sanitized_name))
                             }
@@ -93,7 +214,7 @@ impl DISanitizer {
                         // anything on the declaration, we're going to process
                         // the actual definition.
                         if di_composite_type.flags() == LLVMDIFlagFwdDecl {
-                            return;
+                            return Ok(());
                         }
 
                         let mut is_data_carrying_enum = false;
@@ -106,8 +227,13 @@ impl DISanitizer {
                                     // as a member of another composite type means that we are
                                     // processing a data-carrying enum. Such types are not supported
                                     // by the Linux kernel. We need to remove the children, so BTF
-                                    // doesn't contain data carried by the enum variant.
+                                    // doesn't contain data carried by the enum variant, unless
+                                    // asked to keep them.
                                     match di_composite_type_inner.tag() {
+                                        DW_TAG_variant_part if self.keep_variant_enums => {
+                                            is_data_carrying_enum = true;
+                                            break;
+                                        }
                                         DW_TAG_variant_part => {
                                             let line = di_composite_type.line();
                                             let file = di_composite_type.file();
@@ -170,25 +296,62 @@ impl DISanitizer {
                             }
                         }
                         if is_data_carrying_enum {
-                            di_composite_type.replace_elements(MDNode::empty(self.context));
+                            if !self.keep_variant_enums {
+                                di_composite_type.replace_elements(MDNode::empty(self.context));
+                            }
                         } else if !members.is_empty() {
                             members.sort_by_cached_key(|di_type| di_type.offset_in_bits());
                             let sorted_elements =
                                 MDNode::with_elements(self.context, members.as_mut_slice());
                             di_composite_type.replace_elements(sorted_elements);
                         }
-                        if remove_name {
+                        let new_name = if remove_name {
                             // `AyaBtfMapMarker` is a type which is used in fields of BTF map
                             // structs. We need to make such structs anonymous in order to get
                             // BTF maps accepted by the Linux kernel.
-                            di_composite_type.replace_name(self.context, "").unwrap();
-                        } else if let Some((_, sanitized_name)) = names {
+                            Some("")
+                        } else {
                             // Clear the name from characters incompatible with C.
-                            di_composite_type
-                                .replace_name(self.context, sanitized_name.as_str())
-                                .unwrap();
+                            names
+                                .as_ref()
+                                .map(|(_, sanitized_name)| sanitized_name.as_str())
+                        };
+                        if let Some(new_name) = new_name {
+                            let name = names.as_ref().map(|(original_name, _)| original_name);
+                            self.replace_composite_type_name(
+                                &mut di_composite_type,
+                                name.map(String::as_str),
+                                new_name,
+                            )?;
+                        }
+                        if self.dedup_types {
+                            self.composite_types.push(di_composite_type.value_ref);
                         }
                     }
+                    DW_TAG_union_type => {
+                        // Same as for structs, process the definition rather than the
+                        // declaration.
+                        if di_composite_type.flags() == LLVMDIFlagFwdDecl {
+                            return Ok(());
+                        }
+
+                        if let Some(name) = di_composite_type.name() {
+                            // Clear the name from characters incompatible with C.
+                            let name = name.to_string_lossy().to_string();
+                            let sanitized_name = sanitize_type_name(&name, self.max_name_len);
+                            self.replace_composite_type_name(
+                                &mut di_composite_type,
+                                Some(&name),
+                                &sanitized_name,
+                            )?;
+                        }
+                        if self.dedup_types {
+                            self.composite_types.push(di_composite_type.value_ref);
+                        }
+                    }
+                    DW_TAG_enumeration_type if self.downgrade_enum64 => {
+                        self.downgrade_enum64_base_type(&mut di_composite_type)
+                    }
                     _ => (),
                 }
             }
@@ -198,7 +361,9 @@ impl DISanitizer {
                 match di_derived_type.tag() {
                     DW_TAG_pointer_type => {
                         // remove rust names
-                        di_derived_type.replace_name(self.context, "").unwrap();
+                        di_derived_type
+                            .replace_name(self.context, "")
+                            .expect("empty name has no NUL byte");
                     }
                     _ => (),
                 }
@@ -206,53 +371,147 @@ impl DISanitizer {
             Metadata::DISubprogram(mut di_subprogram) => {
                 // Sanitize function names
                 if let Some(name) = di_subprogram.name() {
-                    let name = sanitize_type_name(name);
+                    let new_name = sanitize_type_name(name, self.max_name_len);
+                    let name = name.to_owned();
                     di_subprogram
-                        .replace_name(self.context, name.as_str())
-                        .unwrap();
+                        .replace_name(self.context, new_name.as_str())
+                        .map_err(|_| BtfSanitizeError::InvalidName {
+                            name,
+                            location: location(
+                                &unsafe { DIFile::from_metadata_ref(di_subprogram.file()) },
+                                di_subprogram.line(),
+                            ),
+                            new_name,
+                        })?;
                 }
             }
             _ => (),
         }
+
+        Ok(())
+    }
+
+    fn replace_composite_type_name(
+        &self,
+        di_composite_type: &mut DICompositeType,
+        name: Option<&str>,
+        new_name: &str,
+    ) -> Result<(), BtfSanitizeError> {
+        di_composite_type
+            .replace_name(self.context, new_name)
+            .map_err(|_| BtfSanitizeError::InvalidName {
+                name: name.unwrap_or("(anon)").to_owned(),
+                new_name: new_name.to_owned(),
+                location: location(&di_composite_type.file(), di_composite_type.line()),
+            })
+    }
+
+    // The BTF backend emits enumerations with a base type wider than 32 bits as BTF_KIND_ENUM64,
+    // which is not supported by kernels older than 6.0. Replace the base type with a 32-bit
+    // integer, so that BTF_KIND_ENUM is emitted instead.
+    fn downgrade_enum64_base_type(&mut self, di_composite_type: &mut DICompositeType) {
+        let Some(base_type) = di_composite_type.base_type() else {
+            // Forward declaration.
+            return;
+        };
+        if base_type.size_in_bits() <= 32 {
+            return;
+        }
+
+        let mut is_signed = false;
+        let mut truncated = false;
+        for element in di_composite_type.elements() {
+            if let Metadata::DIEnumerator(di_enumerator) = element {
+                let unsigned = di_enumerator.is_unsigned();
+                is_signed |= !unsigned;
+                truncated |= match di_enumerator.value() {
+                    Some(value) if unsigned => u32::try_from(value).is_err(),
+                    Some(value) => i32::try_from(value).is_err(),
+                    None => true,
+                };
+            }
+        }
+
+        let name = di_composite_type
+            .name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "(anon)".to_owned());
+        if truncated {
+            let line = di_composite_type.line();
+            let file = di_composite_type.file();
+            let filename = file
+                .filename()
+                .map(|filename| filename.to_string_lossy().to_string())
+                .unwrap_or_else(|| "<unknown>".to_owned());
+            warn!(
+                "downgrading 64-bit enum {name} ({filename}:{line}) to 32 bits truncates the values of its enumerators"
+            );
+        } else {
+            trace!("downgrading 64-bit enum {name} to 32 bits");
+        }
+
+        let (base_name, encoding) = if is_signed {
+            ("i32", DW_ATE_signed)
+        } else {
+            ("u32", DW_ATE_unsigned)
+        };
+        let new_base_type = unsafe {
+            LLVMDIBuilderCreateBasicType(
+                self.builder,
+                base_name.as_ptr() as *const c_char,
+                base_name.len(),
+                32,
+                encoding.0 as u32,
+                LLVMDIFlagZero,
+            )
+        };
+        di_composite_type.replace_base_type(new_base_type);
     }
 
     // navigate the tree of LLVMValueRefs (DFS-pre-order)
-    fn visit_item(&mut self, mut item: Item) {
+    fn visit_item(&mut self, mut item: Item) -> Result<(), BtfSanitizeError> {
         let value_ref = item.value_ref();
-        let value_id = item.value_id();
 
-        let item_span = span!(Level::TRACE, "item", value_id);
+        let item_span = span!(Level::TRACE, "item", node_id = field::Empty);
         let _enter = item_span.enter();
         trace!(?item, value = ?value_ref, "visiting item");
 
         let value = match (value_ref, &item) {
             // An operand with no value is valid and means that the operand is
             // not set
-            (v, Item::Operand { .. }) if v.is_null() => return,
+            (v, Item::Operand { .. }) if v.is_null() => return Ok(()),
             (v, _) if !v.is_null() => Value::new(v),
             // All other items should have values
-            (_, item) => panic!("{item:?} has no value"),
+            (_, item) => return Err(BtfSanitizeError::NullMetadata(format!("{item:?}"))),
         };
 
         if let Item::Operand(operand) = &mut item {
             // When we have an operand to replace, we must do so regardless of whether we've already
             // seen its value or not, since the same value can appear as an operand in multiple
             // nodes in the tree.
-            if let Some(new_metadata) = self.replace_operands.get(&value_id) {
+            if let Some(new_metadata) = self.replace_operands.get(&value_ref) {
                 operand.replace(unsafe { LLVMMetadataAsValue(self.context, *new_metadata) })
             }
         }
 
-        let first_visit = self.visited_nodes.insert(value_id);
-        if !first_visit {
-            trace!("already visited");
-            return;
+        let next_id = self.node_ids.len() as u64;
+        match self.node_ids.entry(value_ref) {
+            Entry::Occupied(entry) => {
+                trace!(node_id = entry.get(), "already visited");
+                return Ok(());
+            }
+            Entry::Vacant(entry) => {
+                let _: &mut u64 = entry.insert(next_id);
+                let _: &tracing::Span = item_span.record("node_id", next_id);
+            }
         }
 
         self.item_stack.push(item.clone());
 
         if let Value::MDNode(mdnode) = value.clone() {
-            self.visit_mdnode(mdnode)
+            if !self.merging {
+                self.visit_mdnode(mdnode)?;
+            }
         }
 
         if let Some(operands) = value.operands() {
@@ -261,14 +520,14 @@ impl DISanitizer {
                     parent: value_ref,
                     value: operand,
                     index: index as u32,
-                }))
+                }))?;
             }
         }
 
         if let Some(entries) = value.metadata_entries() {
             for (index, (metadata, kind)) in entries.iter().enumerate() {
                 let metadata_value = unsafe { LLVMMetadataAsValue(self.context, metadata) };
-                self.visit_item(Item::MetadataEntry(metadata_value, kind, index));
+                self.visit_item(Item::MetadataEntry(metadata_value, kind, index))?;
             }
         }
 
@@ -276,33 +535,66 @@ impl DISanitizer {
         // those too.
         if let Value::Function(fun) = value {
             for param in fun.params() {
-                self.visit_item(Item::FunctionParam(param));
+                self.visit_item(Item::FunctionParam(param))?;
             }
 
             for basic_block in fun.basic_blocks() {
                 for instruction in basic_block.instructions_iter() {
-                    self.visit_item(Item::Instruction(instruction));
+                    self.visit_item(Item::Instruction(instruction))?;
                 }
             }
         }
 
         let _ = self.item_stack.pop().unwrap();
+
+        Ok(())
     }
 
-    pub fn run(mut self, exported_symbols: &HashSet<Cow<'static, str>>) {
-        let module = self.module;
+    /// Sanitizes the debug info of `module` with the default options, without running any
+    /// optimization or codegen. No symbol gets internalized, so the linkage of subprograms is left
+    /// untouched. Modules without debug info are left as they are.
+    ///
+    /// # Safety
+    ///
+    /// `context` and `module` must be valid, and `module` must belong to `context`.
+    pub unsafe fn sanitize_module(
+        context: LLVMContextRef,
+        module: LLVMModuleRef,
+    ) -> Result<(), BtfSanitizeError> {
+        let no_symbols = HashSet::new();
+        match DISanitizer::new(context, module, false, MAX_KSYM_NAME_LEN, false, false) {
+            Ok(sanitizer) => sanitizer.run(&ExportSymbols::AllExcept(&no_symbols)),
+            Err(BtfSanitizeError::NoDebugInfo) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 
-        self.replace_operands = self.fix_subprogram_linkage(exported_symbols);
+    pub fn run(mut self, exported_symbols: &ExportSymbols<'_>) -> Result<(), BtfSanitizeError> {
+        let result = self.sanitize(exported_symbols);
 
-        for value in module.globals_iter() {
-            self.visit_item(Item::GlobalVariable(value));
-        }
-        for value in module.global_aliases_iter() {
-            self.visit_item(Item::GlobalAlias(value));
-        }
+        unsafe { LLVMDisposeDIBuilder(self.builder) };
 
-        for function in module.functions_iter() {
-            self.visit_item(Item::Function(function));
+        result
+    }
+
+    fn sanitize(&mut self, exported_symbols: &ExportSymbols<'_>) -> Result<(), BtfSanitizeError> {
+        let module = self.module;
+
+        self.replace_operands = self.fix_subprogram_linkage(exported_symbols)?;
+        self.visit_module(module)?;
+
+        if self.dedup_types {
+            let duplicates = self.duplicate_composite_types();
+            if !duplicates.is_empty() {
+                debug!("merging {} duplicate BTF types", duplicates.len());
+                // Visit the module again to point the uses of the duplicates to their
+                // canonical type.
+                self.replace_operands = duplicates;
+                self.node_ids.clear();
+                self.merging = true;
+                self.visit_module(module)?;
+                self.merging = false;
+            }
         }
 
         if !self.skipped_types.is_empty() {
@@ -312,7 +604,69 @@ impl DISanitizer {
             );
         }
 
-        unsafe { LLVMDisposeDIBuilder(self.builder) };
+        Ok(())
+    }
+
+    fn visit_module(&mut self, module: LLVMModuleRef) -> Result<(), BtfSanitizeError> {
+        for value in module.globals_iter() {
+            self.visit_item(Item::GlobalVariable(value))?;
+        }
+        for value in module.global_aliases_iter() {
+            self.visit_item(Item::GlobalAlias(value))?;
+        }
+
+        for function in module.functions_iter() {
+            self.visit_item(Item::Function(function))?;
+        }
+
+        Ok(())
+    }
+
+    // Maps the structs and unions which are identical to a struct or union visited before them to
+    // that first type. Anonymous types and types with nested composite types (like the variant
+    // parts of data-carrying enums) are left alone.
+    fn duplicate_composite_types(&self) -> HashMap<LLVMValueRef, LLVMMetadataRef> {
+        let mut canonical = HashMap::new();
+        let mut duplicates = HashMap::new();
+        for value_ref in &self.composite_types {
+            let di_composite_type = unsafe { DICompositeType::from_value_ref(*value_ref) };
+            let Some(name) = di_composite_type.name().filter(|name| !name.is_empty()) else {
+                continue;
+            };
+            let members = di_composite_type
+                .members()
+                .map(|member| {
+                    let name = member.name().map(CStr::to_owned);
+                    let base_type = member.base_type_value_ref();
+                    let member = DIType::from(member);
+                    (
+                        name,
+                        member.offset_in_bits(),
+                        member.size_in_bits(),
+                        base_type,
+                    )
+                })
+                .collect::<Vec<_>>();
+            if members.len() != di_composite_type.elements().count() {
+                continue;
+            }
+            let key = CompositeTypeKey {
+                tag: di_composite_type.tag(),
+                name: name.to_owned(),
+                size_in_bits: di_composite_type.size_in_bits(),
+                members,
+            };
+            match canonical.entry(key) {
+                Entry::Occupied(entry) => {
+                    let _: Option<LLVMMetadataRef> =
+                        duplicates.insert(*value_ref, unsafe { LLVMValueAsMetadata(*entry.get()) });
+                }
+                Entry::Vacant(entry) => {
+                    let _: &mut LLVMValueRef = entry.insert(*value_ref);
+                }
+            }
+        }
+        duplicates
     }
 
     // Make it so that only exported symbols (programs marked as #[no_mangle]) get BTF
@@ -330,8 +684,8 @@ impl DISanitizer {
     // See tests/btf/assembly/exported-symbols.rs .
     fn fix_subprogram_linkage(
         &mut self,
-        export_symbols: &HashSet<Cow<'static, str>>,
-    ) -> HashMap<u64, LLVMMetadataRef> {
+        export_symbols: &ExportSymbols<'_>,
+    ) -> Result<HashMap<LLVMValueRef, LLVMMetadataRef>, BtfSanitizeError> {
         let mut replace = HashMap::new();
 
         for mut function in self
@@ -348,7 +702,12 @@ impl DISanitizer {
                 continue;
             };
 
-            let name = subprogram.name().unwrap();
+            let incomplete = |field| BtfSanitizeError::IncompleteSubprogram {
+                function: function.name().to_owned(),
+                field,
+            };
+            let name = subprogram.name().ok_or_else(|| incomplete("name"))?;
+            let scope = subprogram.scope().ok_or_else(|| incomplete("scope"))?;
             let linkage_name = subprogram.linkage_name();
             let ty = subprogram.ty();
 
@@ -357,7 +716,7 @@ impl DISanitizer {
             let mut new_program = unsafe {
                 let new_program = LLVMDIBuilderCreateFunction(
                     self.builder,
-                    subprogram.scope().unwrap(),
+                    scope,
                     name.as_ptr() as *const c_char,
                     name.len(),
                     linkage_name.map(|s| s.as_ptr()).unwrap_or(ptr::null()) as *const c_char,
@@ -402,13 +761,13 @@ impl DISanitizer {
                 unsafe { LLVMMDNodeInContext2(self.context, core::ptr::null_mut(), 0) };
             subprogram.set_retained_nodes(empty_node);
 
-            let ret = replace.insert(subprogram.value_ref as u64, unsafe {
+            let ret = replace.insert(subprogram.value_ref, unsafe {
                 LLVMValueAsMetadata(new_program.value_ref)
             });
             assert!(ret.is_none());
         }
 
-        replace
+        Ok(replace)
     }
 }
 
@@ -455,49 +814,210 @@ impl Item {
             | Item::MetadataEntry(value, _, _) => *value,
         }
     }
-
-    fn value_id(&self) -> u64 {
-        self.value_ref() as u64
-    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_new_without_debug_info() {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module =
+                LLVMModuleCreateWithNameInContext(b"no_di\0".as_ptr() as *const c_char, context);
+            assert!(matches!(
+                DISanitizer::new(context, module, false, MAX_KSYM_NAME_LEN, false, false),
+                Err(BtfSanitizeError::NoDebugInfo)
+            ));
+            assert!(matches!(
+                DISanitizer::new(
+                    context,
+                    ptr::null_mut(),
+                    false,
+                    MAX_KSYM_NAME_LEN,
+                    false,
+                    false
+                ),
+                Err(BtfSanitizeError::NullPointer("module"))
+            ));
+            assert!(DISanitizer::sanitize_module(context, module).is_ok());
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_di_basic_type() {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module = LLVMModuleCreateWithNameInContext(
+                b"basic_type\0".as_ptr() as *const c_char,
+                context,
+            );
+            let builder = LLVMCreateDIBuilder(module);
+            let name = "u32";
+            let basic_type = LLVMDIBuilderCreateBasicType(
+                builder,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                32,
+                DW_ATE_unsigned.0 as u32,
+                LLVMDIFlagZero,
+            );
+            LLVMDIBuilderFinalize(builder);
+
+            match Metadata::from_value_ref(LLVMMetadataAsValue(context, basic_type)) {
+                Metadata::DIBasicType(di_basic_type) => {
+                    assert_eq!(di_basic_type.name().unwrap().to_str().unwrap(), "u32");
+                    assert_eq!(di_basic_type.size_in_bits(), 32);
+                    assert_eq!(di_basic_type.encoding(), Some(DW_ATE_unsigned));
+                }
+                _ => panic!("expected a DIBasicType"),
+            }
+
+            LLVMDisposeDIBuilder(builder);
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
+    #[test]
+    fn test_di_location() {
+        unsafe {
+            let context = LLVMContextCreate();
+            let module =
+                LLVMModuleCreateWithNameInContext(b"location\0".as_ptr() as *const c_char, context);
+            let builder = LLVMCreateDIBuilder(module);
+            let (filename, directory) = ("main.rs", "/src");
+            let file = LLVMDIBuilderCreateFile(
+                builder,
+                filename.as_ptr() as *const c_char,
+                filename.len(),
+                directory.as_ptr() as *const c_char,
+                directory.len(),
+            );
+            let subroutine_type = LLVMDIBuilderCreateSubroutineType(
+                builder,
+                file,
+                ptr::null_mut(),
+                0,
+                LLVMDIFlagZero,
+            );
+            let name = "prog";
+            let subprogram = LLVMDIBuilderCreateFunction(
+                builder,
+                file,
+                name.as_ptr() as *const c_char,
+                name.len(),
+                name.as_ptr() as *const c_char,
+                name.len(),
+                file,
+                10,
+                subroutine_type,
+                0,
+                1,
+                10,
+                LLVMDIFlagZero,
+                0,
+            );
+            let location =
+                LLVMDIBuilderCreateDebugLocation(context, 12, 5, subprogram, ptr::null_mut());
+            let inlined = LLVMDIBuilderCreateDebugLocation(context, 3, 9, subprogram, location);
+            LLVMDIBuilderFinalize(builder);
+
+            match Metadata::from_value_ref(LLVMMetadataAsValue(context, location)) {
+                Metadata::DILocation(di_location) => {
+                    assert_eq!(di_location.line(), 12);
+                    assert_eq!(di_location.column(), 5);
+                    assert_eq!(di_location.scope(), subprogram);
+                    assert_eq!(di_location.inlined_at(), None);
+                }
+                _ => panic!("expected a DILocation"),
+            }
+            match Metadata::from_value_ref(LLVMMetadataAsValue(context, inlined)) {
+                Metadata::DILocation(di_location) => {
+                    assert_eq!(di_location.inlined_at(), Some(location));
+                }
+                _ => panic!("expected a DILocation"),
+            }
+
+            LLVMDisposeDIBuilder(builder);
+            LLVMDisposeModule(module);
+            LLVMContextDispose(context);
+        }
+    }
+
     #[test]
     fn test_strip_generics() {
-        let name = "MyStruct<u64>";
-        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_3E_");
+        for max_len in [MAX_KSYM_NAME_LEN, 512] {
+            let name = "MyStruct<u64>";
+            assert_eq!(sanitize_type_name(name, max_len), "MyStruct_3C_u64_3E_");
+
+            let name = "MyStruct<u64, u64>";
+            assert_eq!(
+                sanitize_type_name(name, max_len),
+                "MyStruct_3C_u64_2C__20_u64_3E_"
+            );
 
-        let name = "MyStruct<u64, u64>";
-        assert_eq!(sanitize_type_name(name), "MyStruct_3C_u64_2C__20_u64_3E_");
+            let name = "my_function<aya_bpf::BpfContext>";
+            assert_eq!(
+                sanitize_type_name(name, max_len),
+                "my_function_3C_aya_bpf_3A__3A_BpfContext_3E_"
+            );
+
+            let name = "my_function<aya_bpf::BpfContext, aya_log_ebpf::WriteToBuf>";
+            assert_eq!(
+                sanitize_type_name(name, max_len),
+                "my_function_3C_aya_bpf_3A__3A_BpfContext_2C__20_aya_log_ebpf_3A__3A_WriteToBuf_3E_"
+            );
 
-        let name = "my_function<aya_bpf::BpfContext>";
+            let name = "PerfEventArray<[u8; 32]>";
+            assert_eq!(
+                sanitize_type_name(name, max_len),
+                "PerfEventArray_3C__5B_u8_3B__20_32_5D__3E_"
+            );
+        }
+
+        let name = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf>";
+        let san = sanitize_type_name(name, MAX_KSYM_NAME_LEN);
+
+        assert_eq!(san.len(), 128);
         assert_eq!(
-            sanitize_type_name(name),
-            "my_function_3C_aya_bpf_3A__3A_BpfContext_3E_"
+            san,
+            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace__bf4e7cfb6654466723426c5dceb7fecb"
         );
+        assert_eq!(sanitize_btf_type_name(name), san);
+
+        // Names sharing a prefix longer than the limit still get distinct hashes.
+        let first = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf, First>";
+        let second = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf, Second>";
+        let first = sanitize_type_name(first, MAX_KSYM_NAME_LEN);
+        let second = sanitize_type_name(second, MAX_KSYM_NAME_LEN);
 
-        let name = "my_function<aya_bpf::BpfContext, aya_log_ebpf::WriteToBuf>";
+        assert_eq!(first.len(), 128);
+        assert_eq!(second.len(), 128);
         assert_eq!(
-            sanitize_type_name(name),
-            "my_function_3C_aya_bpf_3A__3A_BpfContext_2C__20_aya_log_ebpf_3A__3A_WriteToBuf_3E_"
+            first,
+            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace__29f346cbaa35c97e3df4d46362206d6e"
         );
-
-        let name = "PerfEventArray<[u8; 32]>";
         assert_eq!(
-            sanitize_type_name(name),
-            "PerfEventArray_3C__5B_u8_3B__20_32_5D__3E_"
+            second,
+            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace__38295d88a0969cefa430fa361f3eb860"
         );
 
-        let name = "my_function<aya_bpf::this::is::a::very::long::namespace::BpfContext, aya_log_ebpf::this::is::a::very::long::namespace::WriteToBuf>";
-        let san = sanitize_type_name(name);
+        // The shortest limit keeps the first character of the name.
+        let san = sanitize_type_name(name, MIN_BTF_TYPE_NAME_LEN);
+        assert_eq!(san.len(), MIN_BTF_TYPE_NAME_LEN);
+        assert_eq!(san, "m_bf4e7cfb6654466723426c5dceb7fecb");
 
-        assert_eq!(san.len(), 128);
+        // With a larger limit, the name fits and is not hashed.
+        let san = sanitize_type_name(name, 512);
+
+        assert_eq!(san.len(), 226);
         assert_eq!(
             san,
-            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace_3A__3A_BpfContex_94e4085604b3142f"
+            "my_function_3C_aya_bpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace_3A__3A_BpfContext_2C__20_aya_log_ebpf_3A__3A_this_3A__3A_is_3A__3A_a_3A__3A_very_3A__3A_long_3A__3A_namespace_3A__3A_WriteToBuf_3E_"
         );
     }
 }
@@ -1,58 +1,105 @@
+mod bitcode;
 mod di;
 mod iter;
 mod types;
 
 use std::{
     borrow::Cow,
-    collections::HashSet,
-    ffi::{c_uchar, c_void, CStr, CString},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{c_uchar, c_void, CStr, CString, NulError},
     os::raw::c_char,
     ptr, slice, str,
+    sync::{Mutex, PoisonError},
 };
 
-pub use di::DISanitizer;
-use iter::{IterModuleFunctions, IterModuleGlobalAliases, IterModuleGlobals};
+pub use bitcode::{
+    bitcode_blocks, bitcode_producer_string, bitcode_target_triple, format_blocks, BitcodeBlock,
+    BitcodeError,
+};
+pub(crate) use di::fnv1a_128;
+pub use di::{
+    sanitize_btf_type_name, BtfSanitizeError, DISanitizer, MAX_KSYM_NAME_LEN, MIN_BTF_TYPE_NAME_LEN,
+};
+use iter::{
+    IterBasicBlocks as _, IterInstructions as _, IterModuleFunctions, IterModuleGlobalAliases,
+    IterModuleGlobals,
+};
 use libc::c_char as libc_char;
 use llvm_sys::{
+    analysis::{LLVMVerifierFailureAction, LLVMVerifyModule},
     bit_reader::LLVMParseBitcodeInContext2,
+    bit_writer::LLVMWriteBitcodeToMemoryBuffer,
     core::{
-        LLVMCreateMemoryBufferWithMemoryRange, LLVMDisposeMemoryBuffer, LLVMDisposeMessage,
-        LLVMGetDiagInfoDescription, LLVMGetDiagInfoSeverity, LLVMGetEnumAttributeKindForName,
-        LLVMGetMDString, LLVMGetModuleInlineAsm, LLVMGetTarget, LLVMGetValueName2,
-        LLVMModuleCreateWithNameInContext, LLVMPrintModuleToFile, LLVMRemoveEnumAttributeAtIndex,
-        LLVMSetLinkage, LLVMSetModuleInlineAsm2, LLVMSetVisibility,
+        LLVMCreateMemoryBufferWithMemoryRange, LLVMCreateMemoryBufferWithMemoryRangeCopy,
+        LLVMDeleteFunction, LLVMDisposeMemoryBuffer, LLVMDisposeMessage, LLVMGetBufferSize,
+        LLVMGetBufferStart, LLVMGetDataLayoutStr, LLVMGetDiagInfoDescription,
+        LLVMGetDiagInfoSeverity, LLVMGetEnumAttributeKindForName, LLVMGetLinkage, LLVMGetMDString,
+        LLVMGetModuleInlineAsm, LLVMGetNumOperands, LLVMGetOperand, LLVMGetPoison, LLVMGetSection,
+        LLVMGetStringAttributeAtIndex, LLVMGetStringAttributeValue, LLVMGetTarget,
+        LLVMGetValueName2, LLVMGlobalGetValueType, LLVMIsAConstant, LLVMIsAFunction,
+        LLVMIsAGlobalValue, LLVMIsDeclaration, LLVMModuleCreateWithNameInContext,
+        LLVMPrintModuleToFile, LLVMPrintModuleToString, LLVMRemoveEnumAttributeAtIndex,
+        LLVMReplaceAllUsesWith, LLVMSetLinkage, LLVMSetModuleInlineAsm2, LLVMSetVisibility,
+        LLVMTypeOf,
     },
     debuginfo::LLVMStripModuleDebugInfo,
     error::{
         LLVMDisposeErrorMessage, LLVMGetErrorMessage, LLVMGetErrorTypeId, LLVMGetStringErrorTypeId,
     },
+    ir_reader::LLVMParseIRInContext,
     linker::LLVMLinkModules2,
     object::{
-        LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSectionIterator, LLVMGetSectionContents,
-        LLVMGetSectionName, LLVMGetSectionSize, LLVMMoveToNextSection,
-        LLVMObjectFileCopySectionIterator, LLVMObjectFileIsSectionIteratorAtEnd,
+        LLVMCreateBinary, LLVMDisposeBinary, LLVMDisposeSectionIterator, LLVMDisposeSymbolIterator,
+        LLVMGetSectionContents, LLVMGetSectionName, LLVMGetSectionSize,
+        LLVMMoveToContainingSection, LLVMMoveToNextSection, LLVMMoveToNextSymbol,
+        LLVMObjectFileCopySectionIterator, LLVMObjectFileCopySymbolIterator,
+        LLVMObjectFileIsSectionIteratorAtEnd, LLVMObjectFileIsSymbolIteratorAtEnd,
+    },
+    prelude::{
+        LLVMContextRef, LLVMDiagnosticInfoRef, LLVMMemoryBufferRef, LLVMModuleRef, LLVMValueRef,
     },
-    prelude::{LLVMContextRef, LLVMDiagnosticInfoRef, LLVMModuleRef, LLVMValueRef},
     support::LLVMParseCommandLineOptions,
     target::{
-        LLVMInitializeBPFAsmParser, LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler,
-        LLVMInitializeBPFTarget, LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC,
+        LLVMABISizeOfType, LLVMDisposeTargetData, LLVMInitializeBPFAsmParser,
+        LLVMInitializeBPFAsmPrinter, LLVMInitializeBPFDisassembler, LLVMInitializeBPFTarget,
+        LLVMInitializeBPFTargetInfo, LLVMInitializeBPFTargetMC,
     },
     target_machine::{
-        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetMachine,
-        LLVMGetTargetFromTriple, LLVMRelocMode, LLVMTargetMachineEmitToFile, LLVMTargetMachineRef,
-        LLVMTargetRef,
+        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetDataLayout,
+        LLVMCreateTargetMachine, LLVMGetTargetFromTriple, LLVMRelocMode,
+        LLVMTargetMachineEmitToMemoryBuffer, LLVMTargetMachineRef, LLVMTargetRef,
     },
     transforms::pass_builder::{
         LLVMCreatePassBuilderOptions, LLVMDisposePassBuilderOptions, LLVMRunPasses,
     },
     LLVMAttributeFunctionIndex, LLVMLinkage, LLVMVisibility,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
+use types::ir::Function;
+
+use crate::{CodeModel, OptLevel, RelocMode};
 
-use crate::OptLevel;
+// The command line LLVM was initialized with. LLVM's options are global to the process and can
+// only be parsed once.
+static COMMAND_LINE: Mutex<Option<Vec<String>>> = Mutex::new(None);
 
+/// Initializes the BPF target and parses `args` as LLVM's command line.
+///
+/// Only the first call parses `args`, later calls with different arguments log a warning and
+/// keep the options LLVM was first initialized with.
 pub unsafe fn init<T: AsRef<str>>(args: &[T], overview: &str) {
+    let args = args
+        .iter()
+        .map(|arg| arg.as_ref().to_owned())
+        .collect::<Vec<_>>();
+    let mut command_line = COMMAND_LINE.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(parsed) = &*command_line {
+        if *parsed != args {
+            warn!("LLVM is already initialized with {parsed:?}, ignoring {args:?}");
+        }
+        return;
+    }
+
     LLVMInitializeBPFTarget();
     LLVMInitializeBPFTargetMC();
     LLVMInitializeBPFTargetInfo();
@@ -60,7 +107,8 @@ pub unsafe fn init<T: AsRef<str>>(args: &[T], overview: &str) {
     LLVMInitializeBPFAsmParser();
     LLVMInitializeBPFDisassembler();
 
-    parse_command_line_options(args, overview);
+    parse_command_line_options(&args, overview);
+    *command_line = Some(args);
 }
 
 unsafe fn parse_command_line_options<T: AsRef<str>>(args: &[T], overview: &str) {
@@ -73,20 +121,19 @@ unsafe fn parse_command_line_options<T: AsRef<str>>(args: &[T], overview: &str)
     LLVMParseCommandLineOptions(c_ptrs.len() as i32, c_ptrs.as_ptr(), overview.as_ptr());
 }
 
-pub unsafe fn create_module(name: &str, context: LLVMContextRef) -> Option<LLVMModuleRef> {
-    let c_name = CString::new(name).unwrap();
-    let module = LLVMModuleCreateWithNameInContext(c_name.as_ptr(), context);
-
-    if module.is_null() {
-        return None;
-    }
-
-    Some(module)
+pub unsafe fn create_module(
+    name: &str,
+    context: LLVMContextRef,
+) -> Result<LLVMModuleRef, NulError> {
+    let c_name = CString::new(name)?;
+    Ok(LLVMModuleCreateWithNameInContext(c_name.as_ptr(), context))
 }
 
-pub unsafe fn find_embedded_bitcode(
+/// Returns the contents of the section named `section` of the object file in `data`.
+pub unsafe fn find_section(
     context: LLVMContextRef,
     data: &[u8],
+    section: &str,
 ) -> Result<Option<Vec<u8>>, String> {
     let buffer_name = CString::new("mem_buffer").unwrap();
     let buffer = LLVMCreateMemoryBufferWithMemoryRange(
@@ -98,6 +145,7 @@ pub unsafe fn find_embedded_bitcode(
 
     let (bin, message) = Message::with(|message| LLVMCreateBinary(buffer, context, message));
     if bin.is_null() {
+        LLVMDisposeMemoryBuffer(buffer);
         return Err(message.as_c_str().unwrap().to_str().unwrap().to_string());
     }
 
@@ -106,8 +154,7 @@ pub unsafe fn find_embedded_bitcode(
     while LLVMObjectFileIsSectionIteratorAtEnd(bin, iter) == 0 {
         let name = LLVMGetSectionName(iter);
         if !name.is_null() {
-            let name = CStr::from_ptr(name);
-            if name.to_str().unwrap() == ".llvmbc" {
+            if CStr::from_ptr(name).to_bytes() == section.as_bytes() {
                 let buf = LLVMGetSectionContents(iter);
                 let size = LLVMGetSectionSize(iter) as usize;
                 ret = Some(slice::from_raw_parts(buf as *const c_uchar, size).to_vec());
@@ -123,13 +170,50 @@ pub unsafe fn find_embedded_bitcode(
     Ok(ret)
 }
 
+/// Returns whether the object file in `data` defines any symbol in a non-empty section, i.e.
+/// whether it contains code or data which would have to be linked.
+pub unsafe fn object_defines_symbols(context: LLVMContextRef, data: &[u8]) -> Result<bool, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    let buffer = LLVMCreateMemoryBufferWithMemoryRange(
+        data.as_ptr() as *const libc_char,
+        data.len(),
+        buffer_name.as_ptr(),
+        0,
+    );
+
+    let (bin, message) = Message::with(|message| LLVMCreateBinary(buffer, context, message));
+    if bin.is_null() {
+        LLVMDisposeMemoryBuffer(buffer);
+        return Err(message.as_c_str().unwrap().to_str().unwrap().to_string());
+    }
+
+    let mut defines = false;
+    let sections = LLVMObjectFileCopySectionIterator(bin);
+    let symbols = LLVMObjectFileCopySymbolIterator(bin);
+    while !defines && LLVMObjectFileIsSymbolIteratorAtEnd(bin, symbols) == 0 {
+        // Undefined and absolute symbols move the section iterator to the end.
+        LLVMMoveToContainingSection(sections, symbols);
+        defines = LLVMObjectFileIsSectionIteratorAtEnd(bin, sections) == 0
+            && LLVMGetSectionSize(sections) > 0;
+        LLVMMoveToNextSymbol(symbols);
+    }
+    LLVMDisposeSymbolIterator(symbols);
+    LLVMDisposeSectionIterator(sections);
+    LLVMDisposeBinary(bin);
+    LLVMDisposeMemoryBuffer(buffer);
+
+    Ok(defines)
+}
+
+/// Links the bitcode module in `buffer` into `module`. Returns the target triple and the data
+/// layout of the linked module, or `None` if the bitcode couldn't be parsed or linked.
 #[must_use]
 pub unsafe fn link_bitcode_buffer(
     context: LLVMContextRef,
     module: LLVMModuleRef,
     buffer: &[u8],
-) -> bool {
-    let mut linked = false;
+) -> Option<(String, String)> {
+    let mut linked = None;
     let buffer_name = CString::new("mem_buffer").unwrap();
     let buffer = LLVMCreateMemoryBufferWithMemoryRange(
         buffer.as_ptr() as *const libc_char,
@@ -141,7 +225,8 @@ pub unsafe fn link_bitcode_buffer(
     let mut temp_module = ptr::null_mut();
 
     if LLVMParseBitcodeInContext2(context, buffer, &mut temp_module) == 0 {
-        linked = LLVMLinkModules2(module, temp_module) == 0;
+        let target = (module_triple(temp_module), module_data_layout(temp_module));
+        linked = (LLVMLinkModules2(module, temp_module) == 0).then_some(target);
     }
 
     LLVMDisposeMemoryBuffer(buffer);
@@ -149,6 +234,47 @@ pub unsafe fn link_bitcode_buffer(
     linked
 }
 
+/// Parses the textual IR in `buffer` and links it into `module`. Like [`link_bitcode_buffer`],
+/// returns the target triple and the data layout of the linked module, or `None` if linking
+/// failed.
+pub unsafe fn link_ir_buffer(
+    context: LLVMContextRef,
+    module: LLVMModuleRef,
+    buffer: &[u8],
+) -> Result<Option<(String, String)>, String> {
+    let buffer_name = CString::new("mem_buffer").unwrap();
+    // The IR parser needs a NUL terminated buffer, which the copy provides.
+    let buffer = LLVMCreateMemoryBufferWithMemoryRangeCopy(
+        buffer.as_ptr() as *const libc_char,
+        buffer.len(),
+        buffer_name.as_ptr(),
+    );
+
+    let mut temp_module = ptr::null_mut();
+
+    // LLVMParseIRInContext takes ownership of the buffer.
+    let (ret, message) =
+        Message::with(|message| LLVMParseIRInContext(context, buffer, &mut temp_module, message));
+    if ret != 0 {
+        return Err(message.as_c_str().unwrap().to_str().unwrap().to_string());
+    }
+
+    let target = (module_triple(temp_module), module_data_layout(temp_module));
+    Ok((LLVMLinkModules2(module, temp_module) == 0).then_some(target))
+}
+
+unsafe fn module_triple(module: LLVMModuleRef) -> String {
+    CStr::from_ptr(LLVMGetTarget(module))
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe fn module_data_layout(module: LLVMModuleRef) -> String {
+    CStr::from_ptr(LLVMGetDataLayoutStr(module))
+        .to_string_lossy()
+        .into_owned()
+}
+
 pub unsafe fn target_from_triple(triple: &CStr) -> Result<LLVMTargetRef, String> {
     let mut target = ptr::null_mut();
     let (ret, message) =
@@ -170,6 +296,9 @@ pub unsafe fn create_target_machine(
     triple: &str,
     cpu: &str,
     features: &str,
+    opt_level: OptLevel,
+    reloc_mode: RelocMode,
+    code_model: CodeModel,
 ) -> Option<LLVMTargetMachineRef> {
     let triple = CString::new(triple).unwrap();
     let cpu = CString::new(cpu).unwrap();
@@ -179,9 +308,21 @@ pub unsafe fn create_target_machine(
         triple.as_ptr(),
         cpu.as_ptr(),
         features.as_ptr(),
-        LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
-        LLVMRelocMode::LLVMRelocDefault,
-        LLVMCodeModel::LLVMCodeModelDefault,
+        codegen_opt_level(opt_level),
+        match reloc_mode {
+            RelocMode::Default => LLVMRelocMode::LLVMRelocDefault,
+            RelocMode::Static => LLVMRelocMode::LLVMRelocStatic,
+            RelocMode::Pic => LLVMRelocMode::LLVMRelocPIC,
+            RelocMode::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+        },
+        match code_model {
+            CodeModel::Default => LLVMCodeModel::LLVMCodeModelDefault,
+            CodeModel::Tiny => LLVMCodeModel::LLVMCodeModelTiny,
+            CodeModel::Small => LLVMCodeModel::LLVMCodeModelSmall,
+            CodeModel::Kernel => LLVMCodeModel::LLVMCodeModelKernel,
+            CodeModel::Medium => LLVMCodeModel::LLVMCodeModelMedium,
+            CodeModel::Large => LLVMCodeModel::LLVMCodeModelLarge,
+        },
     );
     if tm.is_null() {
         None
@@ -190,12 +331,24 @@ pub unsafe fn create_target_machine(
     }
 }
 
+pub fn codegen_opt_level(opt_level: OptLevel) -> LLVMCodeGenOptLevel {
+    match opt_level {
+        OptLevel::No => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+        OptLevel::Less => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+        OptLevel::Default => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+        OptLevel::Aggressive => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        // Same as clang, which only optimizes for size at the IR level.
+        OptLevel::Size | OptLevel::SizeMin => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+    }
+}
+
 pub unsafe fn optimize(
     tm: LLVMTargetMachineRef,
     module: LLVMModuleRef,
     opt_level: OptLevel,
+    passes: Option<&str>,
     ignore_inline_never: bool,
-    export_symbols: &HashSet<Cow<'static, str>>,
+    export_symbols: &ExportSymbols<'_>,
 ) -> Result<(), String> {
     if module_asm_is_probestack(module) {
         LLVMSetModuleInlineAsm2(module, ptr::null_mut(), 0);
@@ -209,16 +362,26 @@ pub unsafe fn optimize(
     }
 
     for function in module.functions_iter() {
-        let name = symbol_name(function);
-        if !name.starts_with("llvm.") {
-            if ignore_inline_never {
-                remove_attribute(function, "noinline");
-            }
-            internalize(function, name, export_symbols);
+        let mut function = Function::from_value_ref(function);
+        let name = function.name();
+        if name.starts_with("llvm.") {
+            continue;
+        }
+        if ignore_inline_never {
+            remove_attribute(function.value_ref, "noinline");
+        }
+        if !export_symbols.contains(name) {
+            debug!(
+                "internalizing {name}, linkage: {:?}, visibility: {:?}",
+                function.linkage(),
+                function.visibility()
+            );
+            function.set_linkage(LLVMLinkage::LLVMInternalLinkage);
+            function.set_visibility(LLVMVisibility::LLVMDefaultVisibility);
         }
     }
 
-    let passes = [
+    let default_passes = [
         // NB: "default<_>" must be the first pass in the list, otherwise it will be ignored.
         match opt_level {
             // Pretty much nothing compiles with -O0 so make it an alias for -O1.
@@ -235,9 +398,12 @@ pub unsafe fn optimize(
         "dce",
     ];
 
-    let passes = passes.join(",");
+    let passes = match passes {
+        Some(passes) => passes.to_owned(),
+        None => default_passes.join(","),
+    };
     debug!("running passes: {passes}");
-    let passes = CString::new(passes).unwrap();
+    let passes = CString::new(passes).map_err(|e| format!("invalid pass pipeline: {e}"))?;
     let options = LLVMCreatePassBuilderOptions();
     let error = LLVMRunPasses(module, passes.as_ptr(), tm, options);
     LLVMDisposePassBuilderOptions(options);
@@ -271,6 +437,261 @@ unsafe fn module_asm_is_probestack(module: LLVMModuleRef) -> bool {
     asm.contains("__rust_probestack")
 }
 
+/// Returns the symbols defined or declared in the module, mapped to whether they have internal
+/// linkage.
+pub unsafe fn symbol_linkages(module: LLVMModuleRef) -> BTreeMap<String, bool> {
+    module
+        .globals_iter()
+        .chain(module.global_aliases_iter())
+        .chain(module.functions_iter())
+        .filter_map(|value| {
+            let name = symbol_name(value);
+            (!name.starts_with("llvm.")).then(|| {
+                let internal = matches!(
+                    LLVMGetLinkage(value),
+                    LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+                );
+                (name.to_owned(), internal)
+            })
+        })
+        .collect()
+}
+
+/// Returns the names of the symbols defined in the module.
+pub unsafe fn defined_symbols(module: LLVMModuleRef) -> HashSet<String> {
+    module
+        .globals_iter()
+        .chain(module.global_aliases_iter())
+        .chain(module.functions_iter())
+        .filter(|value| LLVMIsDeclaration(*value) == 0)
+        .map(|value| symbol_name(value).to_owned())
+        .collect()
+}
+
+/// Deletes the functions which can't be reached from the exported symbols, the functions placed
+/// in a section (the programs) and the global variables. Returns the number of deleted functions.
+pub unsafe fn gc_functions(module: LLVMModuleRef, export_symbols: &ExportSymbols<'_>) -> usize {
+    let mut worklist = Vec::new();
+    // Functions referenced by globals can be reached through pointers, keep them.
+    for global in module.globals_iter().chain(module.global_aliases_iter()) {
+        referenced_functions(global, &mut worklist);
+    }
+    worklist.extend(module.functions_iter().filter(|function| {
+        export_symbols.contains(symbol_name(*function)) || section(*function).is_some()
+    }));
+
+    let mut live = HashSet::new();
+    while let Some(function) = worklist.pop() {
+        if !live.insert(function) {
+            continue;
+        }
+        referenced_functions(function, &mut worklist);
+        for basic_block in function.basic_blocks_iter() {
+            for instruction in basic_block.instructions_iter() {
+                referenced_functions(instruction, &mut worklist);
+            }
+        }
+    }
+
+    let dead = module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0 && !live.contains(function))
+        .collect::<Vec<_>>();
+    // Dead functions can only be used by other dead functions, so their uses can be dropped
+    // before deleting them.
+    for function in &dead {
+        LLVMReplaceAllUsesWith(*function, LLVMGetPoison(LLVMTypeOf(*function)));
+    }
+    for function in &dead {
+        LLVMDeleteFunction(*function);
+    }
+
+    dead.len()
+}
+
+/// Returns the sorted and deduplicated sections of the functions defined in the module.
+pub unsafe fn program_sections(module: LLVMModuleRef) -> Vec<String> {
+    module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0)
+        .filter_map(|function| section(function).map(Cow::into_owned))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Renames the sections of the functions defined in the module. A section is renamed by the first
+/// `(old, new)` pair where it's either `old` or starts with `old/`, keeping the rest of its name.
+/// Returns the number of renamed functions, or the new section name which contains a NUL byte.
+pub unsafe fn rename_sections(
+    module: LLVMModuleRef,
+    renames: &[(String, String)],
+) -> Result<usize, String> {
+    let mut renamed = 0;
+    for function in module.functions_iter() {
+        let mut function = Function::from_value_ref(function);
+        if function.is_declaration() {
+            continue;
+        }
+        let Some(section) = function.section().map(Cow::into_owned) else {
+            continue;
+        };
+        let new_section = renames.iter().find_map(|(old, new)| {
+            let rest = section.strip_prefix(old.as_str())?;
+            (rest.is_empty() || rest.starts_with('/')).then(|| format!("{new}{rest}"))
+        });
+        if let Some(new_section) = new_section {
+            debug!(
+                "renaming section {section} of {} to {new_section}",
+                function.name()
+            );
+            function
+                .set_section(&new_section)
+                .map_err(|_| new_section.clone())?;
+            renamed += 1;
+        }
+    }
+    Ok(renamed)
+}
+
+/// Returns the name, the number of basic blocks and the number of instructions of each function
+/// defined in the module, in the module order.
+pub unsafe fn function_stats(module: LLVMModuleRef) -> Vec<(String, usize, usize)> {
+    module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0)
+        .map(|function| {
+            let (blocks, instructions) =
+                function
+                    .basic_blocks_iter()
+                    .fold((0, 0), |(blocks, instructions), block| {
+                        (blocks + 1, instructions + block.instructions_iter().count())
+                    });
+            (symbol_name(function).to_owned(), blocks, instructions)
+        })
+        .collect()
+}
+
+/// Returns the `target-features` attribute shared by most of the functions defined in the module.
+pub unsafe fn module_target_features(module: LLVMModuleRef) -> Option<String> {
+    const KEY: &str = "target-features";
+
+    let mut counts = HashMap::<_, usize>::new();
+    for function in module
+        .functions_iter()
+        .filter(|function| LLVMIsDeclaration(*function) == 0)
+    {
+        let attr = LLVMGetStringAttributeAtIndex(
+            function,
+            LLVMAttributeFunctionIndex,
+            KEY.as_ptr() as *const c_char,
+            KEY.len() as u32,
+        );
+        if attr.is_null() {
+            continue;
+        }
+        let mut len = 0;
+        let value = LLVMGetStringAttributeValue(attr, &mut len);
+        let value =
+            String::from_utf8_lossy(slice::from_raw_parts(value as *const c_uchar, len as usize));
+        *counts.entry(value).or_default() += 1;
+    }
+    // Break ties on the features themselves so that the result doesn't depend on hashing.
+    counts
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then_with(|| b.cmp(a)))
+        .map(|(features, _)| features.into_owned())
+}
+
+/// A symbol defined in the linked module.
+#[derive(Debug)]
+pub struct LinkMapSymbol {
+    pub name: String,
+    pub function: bool,
+    pub section: Option<String>,
+    pub linkage: &'static str,
+    /// Size in bytes. The size of functions is estimated as one BPF instruction per IR
+    /// instruction.
+    pub size: u64,
+}
+
+/// Returns the symbols defined in the module with their section, linkage and approximate size.
+pub unsafe fn link_map(
+    module: LLVMModuleRef,
+    target_machine: LLVMTargetMachineRef,
+) -> Vec<LinkMapSymbol> {
+    // Size of a BPF instruction, not counting the second half of wide instructions.
+    const BPF_INSN_SIZE: u64 = 8;
+
+    let target_data = LLVMCreateTargetDataLayout(target_machine);
+    let globals = module.globals_iter().map(|global| {
+        let size = LLVMABISizeOfType(target_data, LLVMGlobalGetValueType(global));
+        (global, false, size)
+    });
+    let functions = module.functions_iter().map(|function| {
+        let instructions = function
+            .basic_blocks_iter()
+            .map(|basic_block| basic_block.instructions_iter().count() as u64)
+            .sum::<u64>();
+        (function, true, instructions * BPF_INSN_SIZE)
+    });
+    let symbols = globals
+        .chain(functions)
+        .filter(|(value, _, _)| {
+            LLVMIsDeclaration(*value) == 0 && !symbol_name(*value).starts_with("llvm.")
+        })
+        .map(|(value, function, size)| LinkMapSymbol {
+            name: symbol_name(value).to_owned(),
+            function,
+            section: section(value).map(Cow::into_owned),
+            linkage: linkage_name(LLVMGetLinkage(value)),
+            size,
+        })
+        .collect();
+    LLVMDisposeTargetData(target_data);
+    symbols
+}
+
+fn linkage_name(linkage: LLVMLinkage) -> &'static str {
+    use LLVMLinkage::*;
+    match linkage {
+        LLVMExternalLinkage => "external",
+        LLVMAvailableExternallyLinkage => "available_externally",
+        LLVMLinkOnceAnyLinkage => "linkonce",
+        LLVMLinkOnceODRLinkage => "linkonce_odr",
+        LLVMWeakAnyLinkage => "weak",
+        LLVMWeakODRLinkage => "weak_odr",
+        LLVMAppendingLinkage => "appending",
+        LLVMInternalLinkage => "internal",
+        LLVMPrivateLinkage => "private",
+        LLVMExternalWeakLinkage => "extern_weak",
+        LLVMCommonLinkage => "common",
+        _ => "other",
+    }
+}
+
+// Returns the section a global value is placed in, if any.
+unsafe fn section<'a>(value: LLVMValueRef) -> Option<Cow<'a, str>> {
+    let section = LLVMGetSection(value);
+    (!section.is_null() && *section != 0).then(|| CStr::from_ptr(section).to_string_lossy())
+}
+
+// Pushes the functions used by the operands of `value` to `functions`, looking through constant
+// expressions and aggregates.
+unsafe fn referenced_functions(value: LLVMValueRef, functions: &mut Vec<LLVMValueRef>) {
+    for index in 0..LLVMGetNumOperands(value) {
+        let operand = LLVMGetOperand(value, index as u32);
+        if operand.is_null() {
+            continue;
+        }
+        if !LLVMIsAFunction(operand).is_null() {
+            functions.push(operand);
+        } else if !LLVMIsAConstant(operand).is_null() && LLVMIsAGlobalValue(operand).is_null() {
+            referenced_functions(operand, functions);
+        }
+    }
+}
+
 fn symbol_name<'a>(value: *mut llvm_sys::LLVMValue) -> &'a str {
     let mut name_len = 0;
     let ptr = unsafe { LLVMGetValueName2(value, &mut name_len) };
@@ -292,27 +713,88 @@ pub unsafe fn write_ir(module: LLVMModuleRef, output: &CStr) -> Result<(), Strin
     }
 }
 
-pub unsafe fn codegen(
+/// Runs the LLVM verifier on the module, returning its report when the module is invalid.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+pub unsafe fn verify_module(module: LLVMModuleRef) -> Result<(), String> {
+    let (ret, message) = Message::with(|message| {
+        LLVMVerifyModule(
+            module,
+            LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            message,
+        )
+    });
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(message
+            .as_c_str()
+            .map(|message| message.to_string_lossy().trim_end().to_owned())
+            .unwrap_or_default())
+    }
+}
+
+pub unsafe fn write_ir_to_vec(module: LLVMModuleRef) -> Vec<u8> {
+    let message = Message {
+        ptr: LLVMPrintModuleToString(module),
+    };
+    message
+        .as_c_str()
+        .map(|ir| ir.to_bytes().to_vec())
+        .unwrap_or_default()
+}
+
+pub unsafe fn write_bitcode_to_vec(module: LLVMModuleRef) -> Vec<u8> {
+    memory_buffer_to_vec(LLVMWriteBitcodeToMemoryBuffer(module))
+}
+
+pub unsafe fn codegen_to_vec(
     tm: LLVMTargetMachineRef,
     module: LLVMModuleRef,
-    output: &CStr,
     output_type: LLVMCodeGenFileType,
-) -> Result<(), String> {
+) -> Result<Vec<u8>, String> {
+    let mut buffer = ptr::null_mut();
     let (ret, message) = Message::with(|message| {
-        LLVMTargetMachineEmitToFile(tm, module, output.as_ptr() as *mut _, output_type, message)
+        LLVMTargetMachineEmitToMemoryBuffer(tm, module, output_type, message, &mut buffer)
     });
     if ret == 0 {
-        Ok(())
+        Ok(memory_buffer_to_vec(buffer))
     } else {
         Err(message.as_c_str().unwrap().to_str().unwrap().to_string())
     }
 }
 
-pub unsafe fn internalize(
-    value: LLVMValueRef,
-    name: &str,
-    export_symbols: &HashSet<Cow<'static, str>>,
-) {
+/// Copies the contents of the given memory buffer into a `Vec` and disposes
+/// the buffer.
+unsafe fn memory_buffer_to_vec(buffer: LLVMMemoryBufferRef) -> Vec<u8> {
+    let start = LLVMGetBufferStart(buffer);
+    let size = LLVMGetBufferSize(buffer);
+    let data = slice::from_raw_parts(start as *const c_uchar, size).to_vec();
+    LLVMDisposeMemoryBuffer(buffer);
+    data
+}
+
+/// Symbols which are kept external when optimizing. All the other symbols are internalized.
+#[derive(Clone, Copy, Debug)]
+pub enum ExportSymbols<'a> {
+    /// Export only the given symbols.
+    Only(&'a HashSet<Cow<'static, str>>),
+    /// Export all the symbols except the given ones.
+    AllExcept(&'a HashSet<Cow<'static, str>>),
+}
+
+impl ExportSymbols<'_> {
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            Self::Only(symbols) => symbols.contains(name),
+            Self::AllExcept(symbols) => !symbols.contains(name),
+        }
+    }
+}
+
+pub unsafe fn internalize(value: LLVMValueRef, name: &str, export_symbols: &ExportSymbols<'_>) {
     if !name.starts_with("llvm.") && !export_symbols.contains(name) {
         LLVMSetLinkage(value, LLVMLinkage::LLVMInternalLinkage);
         LLVMSetVisibility(value, LLVMVisibility::LLVMDefaultVisibility);
@@ -336,6 +818,8 @@ pub extern "C" fn diagnostic_handler<T: LLVMDiagnosticHandler>(
         .handle_diagnostic(severity, message.as_c_str().unwrap().to_str().unwrap());
 }
 
+// LLVM exits the process as soon as this handler returns. Unwinding out of it is undefined
+// behavior and there's no way to resume linking, so a fatal error can only be logged.
 pub extern "C" fn fatal_error(reason: *const c_char) {
     error!("fatal error: {:?}", unsafe { CStr::from_ptr(reason) })
 }
@@ -5,13 +5,17 @@ use std::{
     str,
 };
 
-use gimli::DwTag;
+use gimli::{constants::DW_TAG_member, DwAte, DwTag};
 use llvm_sys::{
-    core::{LLVMGetNumOperands, LLVMGetOperand, LLVMReplaceMDNodeOperandWith, LLVMValueAsMetadata},
+    core::{
+        LLVMGetNumOperands, LLVMGetOperand, LLVMPrintValueToString, LLVMReplaceMDNodeOperandWith,
+        LLVMValueAsMetadata,
+    },
     debuginfo::{
-        LLVMDIFileGetFilename, LLVMDIFlags, LLVMDIScopeGetFile, LLVMDISubprogramGetLine,
+        LLVMDIFileGetFilename, LLVMDIFlags, LLVMDILocationGetColumn, LLVMDILocationGetInlinedAt,
+        LLVMDILocationGetLine, LLVMDILocationGetScope, LLVMDIScopeGetFile, LLVMDISubprogramGetLine,
         LLVMDITypeGetFlags, LLVMDITypeGetLine, LLVMDITypeGetName, LLVMDITypeGetOffsetInBits,
-        LLVMGetDINodeTag,
+        LLVMDITypeGetSizeInBits, LLVMGetDINodeTag,
     },
     prelude::{LLVMContextRef, LLVMMetadataRef, LLVMValueRef},
 };
@@ -19,6 +23,7 @@ use llvm_sys::{
 use crate::llvm::{
     mdstring_to_str,
     types::ir::{MDNode, Metadata},
+    Message,
 };
 
 /// Returns a DWARF tag for the given debug info node.
@@ -137,6 +142,68 @@ impl<'ctx> DIType<'ctx> {
     pub fn offset_in_bits(&self) -> usize {
         unsafe { LLVMDITypeGetOffsetInBits(self.metadata_ref) as usize }
     }
+
+    /// Returns the size of the type in bits.
+    pub fn size_in_bits(&self) -> u64 {
+        unsafe { LLVMDITypeGetSizeInBits(self.metadata_ref) }
+    }
+}
+
+/// Represents the debug information for a basic type, like an integer, a
+/// float or a boolean, in LLVM IR.
+pub struct DIBasicType<'ctx> {
+    metadata_ref: LLVMMetadataRef,
+    value_ref: LLVMValueRef,
+    _marker: PhantomData<&'ctx ()>,
+}
+
+impl<'ctx> DIBasicType<'ctx> {
+    /// Constructs a new [`DIBasicType`] from the given `value`.
+    ///
+    /// # Safety
+    ///
+    /// This method assumes that the provided `value` corresponds to a valid
+    /// instance of [LLVM `DIBasicType`](https://llvm.org/doxygen/classllvm_1_1DIBasicType.html).
+    /// It's the caller's responsibility to ensure this invariant, as this
+    /// method doesn't perform any validation checks.
+    pub unsafe fn from_value_ref(value_ref: LLVMValueRef) -> Self {
+        let metadata_ref = LLVMValueAsMetadata(value_ref);
+        Self {
+            metadata_ref,
+            value_ref,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the name of the basic type.
+    pub fn name(&self) -> Option<&CStr> {
+        unsafe { di_type_name(self.metadata_ref) }
+    }
+
+    /// Returns the size of the type in bits.
+    pub fn size_in_bits(&self) -> u64 {
+        unsafe { LLVMDITypeGetSizeInBits(self.metadata_ref) }
+    }
+
+    /// Returns the DWARF encoding of the type, e.g. `DW_ATE_unsigned`.
+    ///
+    /// The LLVM C API doesn't provide a getter for the encoding, which is not
+    /// stored as an operand, so it's parsed from the textual representation
+    /// of the node, e.g. `!DIBasicType(name: "u32", size: 32, encoding: DW_ATE_unsigned)`.
+    pub fn encoding(&self) -> Option<DwAte> {
+        let message = Message {
+            ptr: unsafe { LLVMPrintValueToString(self.value_ref) },
+        };
+        let repr = message.as_c_str()?.to_str().ok()?;
+        let (_, encoding) = repr.split_once("encoding: ")?;
+        let end = encoding
+            .find(|ch: char| ch != '_' && !ch.is_ascii_alphanumeric())
+            .unwrap_or(encoding.len());
+        let encoding = &encoding[..end];
+        (0..=u8::MAX)
+            .map(DwAte)
+            .find(|ate| ate.static_string() == Some(encoding))
+    }
 }
 
 impl<'ctx> From<DIDerivedType<'ctx>> for DIType<'ctx> {
@@ -191,6 +258,17 @@ impl<'ctx> DIDerivedType<'ctx> {
         }
     }
 
+    /// Returns the value of the base type of this derived type, which identifies
+    /// the base type without inspecting it.
+    pub fn base_type_value_ref(&self) -> LLVMValueRef {
+        unsafe { LLVMGetOperand(self.value_ref, DIDerivedTypeOperand::BaseType as u32) }
+    }
+
+    /// Returns the name of the derived type, e.g. the name of a struct member.
+    pub fn name(&self) -> Option<&CStr> {
+        unsafe { di_type_name(self.metadata_ref) }
+    }
+
     /// Replaces the name of the type with a new name.
     ///
     /// # Errors
@@ -211,6 +289,9 @@ impl<'ctx> DIDerivedType<'ctx> {
 /// correspond to the operand indices within metadata nodes.
 #[repr(u32)]
 enum DICompositeTypeOperand {
+    /// [`DIType`] representing the underlying type of an enumeration.
+    /// [Reference in LLVM code](https://github.com/llvm/llvm-project/blob/llvmorg-17.0.3/llvm/include/llvm/IR/DebugInfoMetadata.h#L1229).
+    BaseType = 3,
     /// Elements of the composite type.
     /// [Reference in LLVM code](https://github.com/llvm/llvm-project/blob/llvmorg-17.0.3/llvm/include/llvm/IR/DebugInfoMetadata.h#L1230).
     Elements = 4,
@@ -222,7 +303,7 @@ enum DICompositeTypeOperand {
 /// structures, enums, unions, etc.
 pub struct DICompositeType<'ctx> {
     metadata_ref: LLVMMetadataRef,
-    value_ref: LLVMValueRef,
+    pub value_ref: LLVMValueRef,
     _marker: PhantomData<&'ctx ()>,
 }
 
@@ -257,6 +338,37 @@ impl<'ctx> DICompositeType<'ctx> {
             .map(move |i| unsafe { Metadata::from_value_ref(LLVMGetOperand(elements, i as u32)) })
     }
 
+    /// Returns an iterator over the members (struct and union fields) of the
+    /// composite type. The other elements, like variant parts and subranges,
+    /// are skipped.
+    pub fn members(&self) -> impl Iterator<Item = DIDerivedType> {
+        self.elements().filter_map(|element| match element {
+            Metadata::DIDerivedType(di_derived_type) if di_derived_type.tag() == DW_TAG_member => {
+                Some(di_derived_type)
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns the base type of the composite type. Only enumerations have a
+    /// base type, which is the underlying type of their enumerators.
+    pub fn base_type(&self) -> Option<DIType> {
+        let base_type =
+            unsafe { LLVMGetOperand(self.value_ref, DICompositeTypeOperand::BaseType as u32) };
+        NonNull::new(base_type).map(|_| unsafe { DIType::from_value_ref(base_type) })
+    }
+
+    /// Replaces the base type of the composite type.
+    pub fn replace_base_type(&mut self, base_type: LLVMMetadataRef) {
+        unsafe {
+            LLVMReplaceMDNodeOperandWith(
+                self.value_ref,
+                DICompositeTypeOperand::BaseType as u32,
+                base_type,
+            )
+        }
+    }
+
     /// Returns the name of the composite type.
     pub fn name(&self) -> Option<&CStr> {
         unsafe { di_type_name(self.metadata_ref) }
@@ -280,6 +392,11 @@ impl<'ctx> DICompositeType<'ctx> {
         unsafe { LLVMDITypeGetLine(self.metadata_ref) }
     }
 
+    /// Returns the size of the composite type in bits.
+    pub fn size_in_bits(&self) -> u64 {
+        unsafe { LLVMDITypeGetSizeInBits(self.metadata_ref) }
+    }
+
     /// Replaces the elements of the composite type with a new metadata node.
     /// The provided metadata node should contain new composite type elements
     /// as operants. The metadata node can be empty if the intention is to
@@ -310,6 +427,104 @@ impl<'ctx> DICompositeType<'ctx> {
     }
 }
 
+/// Represents the debug information for an enumerator (a named value of an
+/// enumeration) in LLVM IR.
+pub struct DIEnumerator<'ctx> {
+    value_ref: LLVMValueRef,
+    _marker: PhantomData<&'ctx ()>,
+}
+
+impl<'ctx> DIEnumerator<'ctx> {
+    /// Constructs a new [`DIEnumerator`] from the given `value`.
+    ///
+    /// # Safety
+    ///
+    /// This method assumes that the provided `value` corresponds to a valid
+    /// instance of [LLVM `DIEnumerator`](https://llvm.org/doxygen/classllvm_1_1DIEnumerator.html).
+    /// It's the caller's responsibility to ensure this invariant, as this
+    /// method doesn't perform any validation checks.
+    pub unsafe fn from_value_ref(value_ref: LLVMValueRef) -> Self {
+        Self {
+            value_ref,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the value of the enumerator.
+    ///
+    /// The LLVM C API doesn't provide a getter for the value, which is not
+    /// stored as an operand, so it's parsed from the textual representation
+    /// of the node, e.g. `!DIEnumerator(name: "A", value: 1, isUnsigned: true)`.
+    pub fn value(&self) -> Option<i128> {
+        let repr = self.repr();
+        let (_, value) = repr.rsplit_once("value: ")?;
+        let end = value
+            .find(|ch: char| ch != '-' && !ch.is_ascii_digit())
+            .unwrap_or(value.len());
+        value[..end].parse().ok()
+    }
+
+    /// Returns `true` if the value of the enumerator is unsigned.
+    pub fn is_unsigned(&self) -> bool {
+        self.repr().contains("isUnsigned: true")
+    }
+
+    fn repr(&self) -> String {
+        let message = Message {
+            ptr: unsafe { LLVMPrintValueToString(self.value_ref) },
+        };
+        message
+            .as_c_str()
+            .map(|repr| repr.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// Represents a source location in debug information, attached to instructions.
+pub struct DILocation<'ctx> {
+    metadata_ref: LLVMMetadataRef,
+    _marker: PhantomData<&'ctx ()>,
+}
+
+impl<'ctx> DILocation<'ctx> {
+    /// Constructs a new [`DILocation`] from the given `value`.
+    ///
+    /// # Safety
+    ///
+    /// This method assumes that the provided `value` corresponds to a valid
+    /// instance of [LLVM `DILocation`](https://llvm.org/doxygen/classllvm_1_1DILocation.html).
+    /// It's the caller's responsibility to ensure this invariant, as this
+    /// method doesn't perform any validation checks.
+    pub unsafe fn from_value_ref(value_ref: LLVMValueRef) -> Self {
+        Self {
+            metadata_ref: LLVMValueAsMetadata(value_ref),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the line number of the location.
+    pub fn line(&self) -> u32 {
+        unsafe { LLVMDILocationGetLine(self.metadata_ref) }
+    }
+
+    /// Returns the column number of the location.
+    pub fn column(&self) -> u32 {
+        unsafe { LLVMDILocationGetColumn(self.metadata_ref) }
+    }
+
+    /// Returns the local scope, e.g. the subprogram or the lexical block, of
+    /// the location.
+    pub fn scope(&self) -> LLVMMetadataRef {
+        unsafe { LLVMDILocationGetScope(self.metadata_ref) }
+    }
+
+    /// Returns the location the code was inlined at, if it was inlined.
+    pub fn inlined_at(&self) -> Option<LLVMMetadataRef> {
+        let inlined_at = unsafe { LLVMDILocationGetInlinedAt(self.metadata_ref) };
+        NonNull::new(inlined_at).map(NonNull::as_ptr)
+    }
+}
+
 /// Represents the operands for a [`DISubprogram`]. The enum values correspond
 /// to the operand indices within metadata nodes.
 #[repr(u32)]
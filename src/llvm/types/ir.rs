@@ -1,28 +1,33 @@
 use std::{
-    ffi::{CString, NulError},
+    borrow::Cow,
+    ffi::{CStr, CString, NulError},
     marker::PhantomData,
     ptr::NonNull,
 };
 
 use llvm_sys::{
     core::{
-        LLVMCountParams, LLVMDisposeValueMetadataEntries, LLVMGetNumOperands, LLVMGetOperand,
-        LLVMGetParam, LLVMGlobalCopyAllMetadata, LLVMIsAFunction, LLVMIsAGlobalObject,
-        LLVMIsAInstruction, LLVMIsAMDNode, LLVMIsAUser, LLVMMDNodeInContext2,
-        LLVMMDStringInContext2, LLVMMetadataAsValue, LLVMPrintValueToString,
-        LLVMReplaceMDNodeOperandWith, LLVMValueAsMetadata, LLVMValueMetadataEntriesGetKind,
+        LLVMCountParams, LLVMDisposeValueMetadataEntries, LLVMGetLinkage, LLVMGetNumOperands,
+        LLVMGetOperand, LLVMGetParam, LLVMGetSection, LLVMGetVisibility, LLVMGlobalCopyAllMetadata,
+        LLVMIsAFunction, LLVMIsAGlobalObject, LLVMIsAInstruction, LLVMIsAMDNode, LLVMIsAUser,
+        LLVMIsDeclaration, LLVMMDNodeInContext2, LLVMMDStringInContext2, LLVMMetadataAsValue,
+        LLVMPrintValueToString, LLVMReplaceMDNodeOperandWith, LLVMSetLinkage, LLVMSetSection,
+        LLVMSetVisibility, LLVMValueAsMetadata, LLVMValueMetadataEntriesGetKind,
         LLVMValueMetadataEntriesGetMetadata,
     },
     debuginfo::{LLVMGetMetadataKind, LLVMGetSubprogram, LLVMMetadataKind, LLVMSetSubprogram},
     prelude::{
         LLVMBasicBlockRef, LLVMContextRef, LLVMMetadataRef, LLVMValueMetadataEntry, LLVMValueRef,
     },
+    LLVMLinkage, LLVMVisibility,
 };
 
 use crate::llvm::{
     iter::IterBasicBlocks as _,
     symbol_name,
-    types::di::{DICompositeType, DIDerivedType, DISubprogram, DIType},
+    types::di::{
+        DIBasicType, DICompositeType, DIDerivedType, DIEnumerator, DILocation, DISubprogram, DIType,
+    },
     Message,
 };
 
@@ -109,8 +114,11 @@ impl<'ctx> Value<'ctx> {
 }
 
 pub enum Metadata<'ctx> {
+    DIBasicType(DIBasicType<'ctx>),
     DICompositeType(DICompositeType<'ctx>),
     DIDerivedType(DIDerivedType<'ctx>),
+    DIEnumerator(DIEnumerator<'ctx>),
+    DILocation(DILocation<'ctx>),
     DISubprogram(DISubprogram<'ctx>),
     Other(#[allow(dead_code)] LLVMValueRef),
 }
@@ -128,6 +136,10 @@ impl<'ctx> Metadata<'ctx> {
         let metadata = LLVMValueAsMetadata(value);
 
         match unsafe { LLVMGetMetadataKind(metadata) } {
+            LLVMMetadataKind::LLVMDIBasicTypeMetadataKind => {
+                let di_basic_type = unsafe { DIBasicType::from_value_ref(value) };
+                Metadata::DIBasicType(di_basic_type)
+            }
             LLVMMetadataKind::LLVMDICompositeTypeMetadataKind => {
                 let di_composite_type = unsafe { DICompositeType::from_value_ref(value) };
                 Metadata::DICompositeType(di_composite_type)
@@ -136,6 +148,14 @@ impl<'ctx> Metadata<'ctx> {
                 let di_derived_type = unsafe { DIDerivedType::from_value_ref(value) };
                 Metadata::DIDerivedType(di_derived_type)
             }
+            LLVMMetadataKind::LLVMDIEnumeratorMetadataKind => {
+                let di_enumerator = unsafe { DIEnumerator::from_value_ref(value) };
+                Metadata::DIEnumerator(di_enumerator)
+            }
+            LLVMMetadataKind::LLVMDILocationMetadataKind => {
+                let di_location = unsafe { DILocation::from_value_ref(value) };
+                Metadata::DILocation(di_location)
+            }
             LLVMMetadataKind::LLVMDISubprogramMetadataKind => {
                 let di_subprogram = unsafe { DISubprogram::from_value_ref(value) };
                 Metadata::DISubprogram(di_subprogram)
@@ -147,13 +167,10 @@ impl<'ctx> Metadata<'ctx> {
             | LLVMMetadataKind::LLVMLocalAsMetadataMetadataKind
             | LLVMMetadataKind::LLVMDistinctMDOperandPlaceholderMetadataKind
             | LLVMMetadataKind::LLVMMDTupleMetadataKind
-            | LLVMMetadataKind::LLVMDILocationMetadataKind
             | LLVMMetadataKind::LLVMDIExpressionMetadataKind
             | LLVMMetadataKind::LLVMDIGlobalVariableExpressionMetadataKind
             | LLVMMetadataKind::LLVMGenericDINodeMetadataKind
             | LLVMMetadataKind::LLVMDISubrangeMetadataKind
-            | LLVMMetadataKind::LLVMDIEnumeratorMetadataKind
-            | LLVMMetadataKind::LLVMDIBasicTypeMetadataKind
             | LLVMMetadataKind::LLVMDISubroutineTypeMetadataKind
             | LLVMMetadataKind::LLVMDIFileMetadataKind
             | LLVMMetadataKind::LLVMDICompileUnitMetadataKind
@@ -336,4 +353,44 @@ impl<'ctx> Function<'ctx> {
     pub(crate) fn set_subprogram(&mut self, subprogram: &DISubprogram) {
         unsafe { LLVMSetSubprogram(self.value_ref, LLVMValueAsMetadata(subprogram.value_ref)) };
     }
+
+    pub(crate) fn linkage(&self) -> LLVMLinkage {
+        unsafe { LLVMGetLinkage(self.value_ref) }
+    }
+
+    pub(crate) fn set_linkage(&mut self, linkage: LLVMLinkage) {
+        unsafe { LLVMSetLinkage(self.value_ref, linkage) };
+    }
+
+    pub(crate) fn visibility(&self) -> LLVMVisibility {
+        unsafe { LLVMGetVisibility(self.value_ref) }
+    }
+
+    pub(crate) fn set_visibility(&mut self, visibility: LLVMVisibility) {
+        unsafe { LLVMSetVisibility(self.value_ref, visibility) };
+    }
+
+    /// Returns the section of the function, if it has one.
+    pub(crate) fn section(&self) -> Option<Cow<'_, str>> {
+        let section = unsafe { LLVMGetSection(self.value_ref) };
+        (!section.is_null() && unsafe { *section } != 0)
+            .then(|| unsafe { CStr::from_ptr(section) }.to_string_lossy())
+    }
+
+    /// Moves the function to the given section.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NulError` if the section name contains a NUL byte, as it
+    /// cannot be converted into a `CString`.
+    pub(crate) fn set_section(&mut self, section: &str) -> Result<(), NulError> {
+        let section = CString::new(section)?;
+        unsafe { LLVMSetSection(self.value_ref, section.as_ptr()) };
+        Ok(())
+    }
+
+    /// Returns `true` if the function is only declared, i.e. it has no body.
+    pub(crate) fn is_declaration(&self) -> bool {
+        unsafe { LLVMIsDeclaration(self.value_ref) != 0 }
+    }
 }
@@ -0,0 +1,16 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-args=--cpu=v4
+#![no_std]
+
+// The v4 instruction set adds sign-extending loads, which are only selected when targeting it.
+
+// aux-build: loop-panic-handler.rs
+extern crate loop_panic_handler;
+
+#[no_mangle]
+#[link_section = "uprobe/fun"]
+pub unsafe extern "C" fn fun(a: *const i8) -> i64 {
+    // CHECK-LABEL: fun:
+    *a as i64
+    // CHECK: r{{[0-9]}} = *(s8 *)(r{{[0-9]}} + 0)
+}
@@ -0,0 +1,18 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib
+#![no_std]
+
+// LLVM can't always expand memory intrinsics to a sequence of loads and stores, e.g. when the
+// length isn't known at compile time. The linker exports the memory builtins, so that the
+// out-of-line implementations survive optimization and the calls can be resolved.
+
+// aux-build: loop-panic-handler.rs
+extern crate loop_panic_handler;
+
+#[no_mangle]
+#[link_section = "uprobe/copy"]
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) {
+    core::ptr::copy_nonoverlapping(src, dst, len)
+}
+
+// CHECK: .globl memcpy
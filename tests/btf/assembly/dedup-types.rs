@@ -0,0 +1,32 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C link-arg=--dedup-btf-types -C debuginfo=2
+
+#![no_std]
+
+// Identical types from different modules which share a name are merged into a single BTF type.
+pub mod a {
+    pub struct Pair<T> {
+        pub x: T,
+        pub y: T,
+    }
+}
+
+pub mod b {
+    pub struct Pair<T> {
+        pub x: T,
+        pub y: T,
+    }
+}
+
+#[no_mangle]
+static PA: a::Pair<u32> = a::Pair { x: 1, y: 2 };
+#[no_mangle]
+static PB: b::Pair<u32> = b::Pair { x: 3, y: 4 };
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK: STRUCT 'Pair_3C_u32_3E_' size=8 vlen=2
+// CHECK-NOT: STRUCT 'Pair_3C_u32_3E_'
@@ -0,0 +1,34 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C debuginfo=2
+
+#![no_std]
+
+// Structurally identical types get distinct debug info nodes, each of them must be sanitized.
+pub struct Wrapper<T> {
+    pub inner: T,
+}
+
+pub struct A(pub u32);
+pub struct B(pub u32);
+pub struct C(pub u32);
+pub struct D(pub u32);
+
+#[no_mangle]
+static WA: Wrapper<A> = Wrapper { inner: A(1) };
+#[no_mangle]
+static WB: Wrapper<B> = Wrapper { inner: B(2) };
+#[no_mangle]
+static WC: Wrapper<C> = Wrapper { inner: C(3) };
+#[no_mangle]
+static WD: Wrapper<D> = Wrapper { inner: D(4) };
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK-DAG: STRUCT 'Wrapper_3C_{{.*}}_3A__3A_A_3E_' size=4 vlen=1
+// CHECK-DAG: STRUCT 'Wrapper_3C_{{.*}}_3A__3A_B_3E_' size=4 vlen=1
+// CHECK-DAG: STRUCT 'Wrapper_3C_{{.*}}_3A__3A_C_3E_' size=4 vlen=1
+// CHECK-DAG: STRUCT 'Wrapper_3C_{{.*}}_3A__3A_D_3E_' size=4 vlen=1
+// CHECK-NOT: STRUCT 'Wrapper<
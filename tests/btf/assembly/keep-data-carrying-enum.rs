@@ -0,0 +1,26 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C link-arg=--keep-btf-enums -C debuginfo=2
+
+#![no_std]
+
+pub enum DataCarryingEnum {
+    First { a: u32, b: i32 },
+    Second(u32, i32),
+    Third(u32),
+}
+
+#[no_mangle]
+pub static X: DataCarryingEnum = DataCarryingEnum::First { a: 54, b: -23 };
+#[no_mangle]
+pub static Y: DataCarryingEnum = DataCarryingEnum::Second(54, -23);
+#[no_mangle]
+pub static Z: DataCarryingEnum = DataCarryingEnum::Third(36);
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// With --keep-btf-enums, the members of the data-carrying enum are kept.
+
+// CHECK: STRUCT 'DataCarryingEnum' size={{[0-9]+}} vlen={{[1-9][0-9]*}}
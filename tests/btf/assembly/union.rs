@@ -0,0 +1,25 @@
+// assembly-output: bpf-linker
+// compile-flags: --crate-type cdylib -C link-arg=--emit=obj -C link-arg=--btf -C debuginfo=2
+
+#![no_std]
+
+use core::marker::PhantomData;
+
+// Union names are sanitized like struct names, so that generics don't end up in BTF.
+#[repr(C)]
+pub union GenericUnion<T: Copy> {
+    pub a: u32,
+    pub b: u64,
+    _marker: PhantomData<T>,
+}
+
+#[no_mangle]
+static FOO: GenericUnion<u8> = GenericUnion { a: 0 };
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// CHECK: UNION 'GenericUnion_3C_u8_3E_' size=8 vlen={{[0-9]+}}
+// CHECK-NOT: UNION 'GenericUnion<u8>'
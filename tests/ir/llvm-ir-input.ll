@@ -0,0 +1,8 @@
+; ModuleID = 'llvm-ir-input.ll'
+source_filename = "llvm-ir-input.ll"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel"
+
+define i32 @llvm_ir_input(ptr %ctx) section "uprobe/llvm_ir_input" {
+  ret i32 0
+}
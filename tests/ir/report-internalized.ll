@@ -0,0 +1,17 @@
+; ModuleID = 'report-internalized.ll'
+source_filename = "report-internalized.ll"
+target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"
+target triple = "bpfel"
+
+@counter = global i32 0
+@unused = global i32 0
+
+define void @helper(i32 %a) noinline {
+  store volatile i32 %a, ptr @counter
+  ret void
+}
+
+define i32 @prog(ptr %ctx) section "uprobe/prog" {
+  call void @helper(i32 42)
+  ret i32 0
+}
@@ -0,0 +1,3 @@
+// Compiled to a Mach-O object file with embedded bitcode by tests/tests.rs.
+
+int macho_embedded_bitcode(int x) { return x + 1; }
@@ -2,8 +2,9 @@ use std::{
     env,
     ffi::{OsStr, OsString},
     fs,
+    io::Write as _,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
 fn find_binary(binary_re_str: &str) -> PathBuf {
@@ -151,3 +152,579 @@ fn compile_test() {
         }),
     );
 }
+
+#[test]
+fn link_macho_embedded_bitcode() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/macho");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for Mach-O objects");
+    let object = out_dir.join("embedded-bitcode.o");
+    let output = out_dir.join("embedded-bitcode.bpf.o");
+
+    // Clang embeds the bitcode in the `__LLVM,__bitcode` section of Mach-O objects.
+    let clang = find_binary(r"^clang(-\d+)?$");
+    let status = Command::new(clang)
+        .arg("-target")
+        .arg("x86_64-apple-macos")
+        .arg("-fembed-bitcode")
+        .arg("-c")
+        .arg("-o")
+        .arg(&object)
+        .arg(root_dir.join("tests/macho/embedded-bitcode.c"))
+        .status()
+        .expect("failed to execute clang");
+    assert!(status.success(), "clang failed with {status}");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("macho_embedded_bitcode")
+        .arg("-o")
+        .arg(&output)
+        .arg(&object)
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    assert!(fs::metadata(&output).unwrap().len() > 0);
+
+    // The object is skipped when looking for the bitcode in another section.
+    let result = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--bitcode-section")
+        .arg(".custombc")
+        .arg("--dry-run")
+        .arg("-o")
+        .arg(&output)
+        .arg(&object)
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(
+        result.status.success(),
+        "bpf-linker failed with {}",
+        result.status
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        format!(
+            "linked 0 inputs, skipped 1\n  skipped {}: no embedded bitcode\n",
+            object.display()
+        )
+    );
+}
+
+#[test]
+fn link_llvm_ir() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let output = out_dir.join("llvm-ir-input.o");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("-o")
+        .arg(&output)
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    assert!(fs::metadata(&output).unwrap().len() > 0);
+
+    // Invalid IR is reported with the message of the parser.
+    let invalid = out_dir.join("invalid.ll");
+    fs::write(&invalid, "define i32 @invalid() {\n  ret i64 0\n}\n").unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("-o")
+        .arg(out_dir.join("invalid.o"))
+        .arg(&invalid)
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("failure parsing LLVM IR"),
+        "unexpected stderr: {stderr}"
+    );
+}
+
+#[test]
+fn report_internalized() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let report = out_dir.join("report-internalized.txt");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("prog")
+        .arg("--disable-memory-builtins")
+        .arg("--report-internalized")
+        .arg(&report)
+        .arg("-o")
+        .arg(out_dir.join("report-internalized.o"))
+        .arg(root_dir.join("tests/ir/report-internalized.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    assert_eq!(
+        fs::read_to_string(&report).unwrap(),
+        "internalized:\n  counter\n  helper\nremoved:\n  unused\n"
+    );
+}
+
+#[test]
+fn emit_multiple_outputs() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/emit");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for outputs");
+    let object = out_dir.join("multiple.o");
+    let ir = out_dir.join("multiple.ll");
+    let assembly = out_dir.join("multiple.s");
+    for path in [&object, &ir, &assembly] {
+        let _ = fs::remove_file(path);
+    }
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--emit=obj,llvm-ir,asm")
+        .arg("-o")
+        .arg(&object)
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+
+    // All the outputs are generated from the same linked module.
+    let object = fs::read(&object).unwrap();
+    assert!(object.starts_with(b"\x7FELF"));
+    assert!(object
+        .windows(b"llvm_ir_input".len())
+        .any(|w| w == b"llvm_ir_input"));
+    let ir = fs::read_to_string(&ir).unwrap();
+    assert!(ir.contains("@llvm_ir_input("), "unexpected IR: {ir}");
+    let assembly = fs::read_to_string(&assembly).unwrap();
+    assert!(
+        assembly.contains("llvm_ir_input:"),
+        "unexpected assembly: {assembly}"
+    );
+}
+
+#[test]
+fn time_passes() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let report = out_dir.join("time-passes.txt");
+    let _ = fs::remove_file(&report);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--time-passes")
+        .arg("--time-passes-file")
+        .arg(&report)
+        .arg("-o")
+        .arg(out_dir.join("time-passes.o"))
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    let report = fs::read_to_string(&report).unwrap();
+    assert!(
+        report.contains("Pass execution timing report"),
+        "unexpected report: {report}"
+    );
+}
+
+#[test]
+fn time_passes_with_log_file() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let log = out_dir.join("time-passes.log");
+    let _ = fs::remove_file(&log);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--time-passes")
+        .arg("--print-stats")
+        .arg("--log-file")
+        .arg(&log)
+        .arg("--log-level=info")
+        .arg("-o")
+        .arg(out_dir.join("time-passes-log.o"))
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(
+        output.status.success(),
+        "bpf-linker failed with {}",
+        output.status
+    );
+    // The reports don't get interleaved with the logs.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Pass execution timing report"),
+        "unexpected stderr: {stderr}"
+    );
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(
+        !log.contains("Pass execution timing report"),
+        "unexpected log: {log}"
+    );
+}
+
+#[test]
+fn custom_passes() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+
+    let link = |passes: &str| {
+        Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+            .arg("--export")
+            .arg("llvm_ir_input")
+            .arg("--passes")
+            .arg(passes)
+            .arg("-o")
+            .arg(out_dir.join("custom-passes.o"))
+            .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+            .output()
+            .expect("failed to execute bpf-linker")
+    };
+
+    let result = link("default<Os>,dce");
+    assert!(
+        result.status.success(),
+        "bpf-linker failed with {}",
+        result.status
+    );
+
+    let result = link("not-a-pass");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("LLVMRunPasses failed") && stderr.contains("not-a-pass"),
+        "unexpected stderr: {stderr}"
+    );
+}
+
+#[test]
+fn data_layout() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let output = out_dir.join("data-layout.ll");
+    let data_layout = "e-m:e-p:64:64-i64:64-n32:64-S128";
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--data-layout")
+        .arg(data_layout)
+        .arg("--emit=llvm-ir")
+        .arg("-o")
+        .arg(&output)
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    let ir = fs::read_to_string(&output).unwrap();
+    assert!(
+        ir.contains(&format!("target datalayout = \"{data_layout}\"")),
+        "unexpected output: {ir}"
+    );
+}
+
+#[test]
+fn version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--version")
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(
+        output.status.success(),
+        "bpf-linker failed with {}",
+        output.status
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with(&format!("bpf-linker {} (LLVM ", env!("CARGO_PKG_VERSION"))),
+        "unexpected output: {stdout}"
+    );
+}
+
+#[test]
+fn dry_run() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let output = out_dir.join("dry-run.o");
+    let _ = fs::remove_file(&output);
+
+    let result = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--dry-run")
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("-o")
+        .arg(&output)
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(
+        result.status.success(),
+        "bpf-linker failed with {}",
+        result.status
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&result.stdout),
+        "linked 1 inputs, skipped 0\n"
+    );
+    assert!(!output.exists());
+}
+
+#[test]
+fn emit_btf() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+
+    for emit in ["obj", "llvm-ir"] {
+        let btf = out_dir.join(format!("emit-btf-{emit}.btf"));
+        let _ = fs::remove_file(&btf);
+
+        let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+            .arg("--export")
+            .arg("counter")
+            .arg("--btf")
+            .arg("--emit-btf")
+            .arg(&btf)
+            .arg("--emit")
+            .arg(emit)
+            .arg("-o")
+            .arg(out_dir.join(format!("emit-btf.{emit}")))
+            .arg(root_dir.join("tests/ir/emit-btf.ll"))
+            .status()
+            .expect("failed to execute bpf-linker");
+        assert!(status.success(), "bpf-linker failed with {status}");
+        // The BTF header starts with the 0xeB9F magic.
+        let btf = fs::read(&btf).unwrap();
+        assert_eq!(btf.get(..2), Some([0x9f, 0xeb].as_slice()));
+    }
+}
+
+#[test]
+fn missing_export() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input,llvm_ir_inptu")
+        .arg("--error-on-missing-export")
+        .arg("-o")
+        .arg(out_dir.join("missing-export.o"))
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("exported symbols not found: llvm_ir_inptu"),
+        "unexpected stderr: {stderr}"
+    );
+}
+
+#[test]
+fn emit_link_map() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let link_map = out_dir.join("link-map.json");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("prog")
+        .arg("--disable-memory-builtins")
+        .arg("--emit-link-map")
+        .arg(&link_map)
+        .arg("--emit-link-map-format=json")
+        .arg("-o")
+        .arg(out_dir.join("link-map.o"))
+        .arg(root_dir.join("tests/ir/report-internalized.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    let link_map = fs::read_to_string(&link_map).unwrap();
+    assert!(
+        link_map.contains(
+            r#""name":"prog","kind":"function","section":"uprobe/prog","linkage":"external""#
+        ),
+        "unexpected link map: {link_map}"
+    );
+    assert!(
+        link_map.contains(
+            r#""name":"counter","kind":"global","section":null,"linkage":"internal","size":4}"#
+        ),
+        "unexpected link map: {link_map}"
+    );
+    assert!(
+        !link_map.contains("unused"),
+        "unexpected link map: {link_map}"
+    );
+}
+
+#[test]
+fn save_temps() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    let temps_dir = out_dir.join("save-temps");
+    let _ = fs::remove_dir_all(&temps_dir);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--save-temps")
+        .arg(&temps_dir)
+        .arg("-o")
+        .arg(out_dir.join("save-temps.o"))
+        .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+        .status()
+        .expect("failed to execute bpf-linker");
+    assert!(status.success(), "bpf-linker failed with {status}");
+    for stage in ["linked", "optimized", "codegen"] {
+        assert!(temps_dir.join(format!("{stage}.bc")).exists(), "{stage}.bc");
+        let ir = fs::read_to_string(temps_dir.join(format!("{stage}.ll"))).unwrap();
+        assert!(ir.contains("@llvm_ir_input"), "unexpected {stage}.ll: {ir}");
+    }
+}
+
+#[test]
+fn fingerprint() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let out_dir = root_dir.join("target/ir");
+    fs::create_dir_all(&out_dir).expect("failed to create a build directory for LLVM IR");
+    let fingerprint = out_dir.join("fingerprint.txt");
+    let output = out_dir.join("fingerprint.o");
+    let link_map = out_dir.join("fingerprint.map");
+    let _ = fs::remove_file(&fingerprint);
+    let _ = fs::remove_file(&output);
+    let _ = fs::remove_file(&link_map);
+
+    let link = |export: &str, args: &[&OsStr]| {
+        Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+            .arg("--export")
+            .arg(export)
+            .arg("--fingerprint")
+            .arg(&fingerprint)
+            .arg("-o")
+            .arg(&output)
+            .args(args)
+            .arg(root_dir.join("tests/ir/llvm-ir-input.ll"))
+            .arg("--log-level=info")
+            .output()
+            .expect("failed to execute bpf-linker")
+    };
+    let skipped = |result: &std::process::Output| {
+        assert!(
+            result.status.success(),
+            "bpf-linker failed with {}",
+            result.status
+        );
+        String::from_utf8_lossy(&result.stderr).contains("inputs and options unchanged")
+    };
+
+    assert!(!skipped(&link("llvm_ir_input", &[])));
+    assert!(fingerprint.exists());
+    assert!(skipped(&link("llvm_ir_input", &[])));
+    // Changing the options invalidates the fingerprint.
+    assert!(!skipped(&link("other", &[])));
+    // So does removing the output.
+    fs::remove_file(&output).unwrap();
+    assert!(!skipped(&link("other", &[])));
+    assert!(output.exists());
+    // Requesting a side output does too, as does removing it.
+    let args = [OsStr::new("--emit-link-map"), link_map.as_os_str()];
+    assert!(!skipped(&link("other", &args)));
+    assert!(link_map.exists());
+    assert!(skipped(&link("other", &args)));
+    fs::remove_file(&link_map).unwrap();
+    assert!(!skipped(&link("other", &args)));
+    assert!(link_map.exists());
+}
+
+#[test]
+fn link_stdin_to_stdout() {
+    let root_dir = env::var_os("CARGO_MANIFEST_DIR")
+        .expect("could not determine the root directory of the project");
+    let root_dir = Path::new(&root_dir);
+    let input = fs::read(root_dir.join("tests/ir/llvm-ir-input.ll")).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("--export")
+        .arg("llvm_ir_input")
+        .arg("--emit=llvm-ir")
+        .arg("-o")
+        .arg("-")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to execute bpf-linker");
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "bpf-linker failed with {}",
+        output.status
+    );
+    let ir = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        ir.contains("define i32 @llvm_ir_input"),
+        "unexpected output: {ir}"
+    );
+
+    let result = Command::new(env!("CARGO_BIN_EXE_bpf-linker"))
+        .arg("-o")
+        .arg("out.o")
+        .arg("-")
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to execute bpf-linker");
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("only one input can be read from stdin"),
+        "unexpected stderr: {stderr}"
+    );
+}